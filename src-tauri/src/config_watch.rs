@@ -0,0 +1,128 @@
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use serde_yaml::Value;
+use std::path::Path;
+use std::time::Duration;
+
+/// Editors save in bursts (truncate then write, or write a temp file and
+/// rename it over): one reload per burst.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Stops watching when dropped.
+pub type ConfigWatcher = Debouncer<RecommendedWatcher>;
+
+/// The top-level sections of config.yml a save touched, by who applies them.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// The bridge: rebuilt from the new section.
+    pub mqtt: bool,
+    /// The Node child: restarted.
+    pub modules: bool,
+    /// Hotkey and autoplace interval: re-applied in place.
+    pub tray: bool,
+}
+
+impl Changes {
+    pub fn between(old: &Value, new: &Value) -> Self {
+        let changed = |key: &str| old.get(key) != new.get(key);
+        Self {
+            mqtt: changed("mqtt"),
+            modules: changed("modules"),
+            tray: changed("tray"),
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.mqtt || self.modules || self.tray
+    }
+
+    /// For the log line: "mqtt, modules".
+    pub fn describe(&self) -> String {
+        [
+            (self.mqtt, "mqtt"),
+            (self.modules, "modules"),
+            (self.tray, "tray"),
+        ]
+        .iter()
+        .filter(|(changed, _)| *changed)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// Calls `on_change` once per burst of writes to `path`, and `on_error` with
+/// what the watcher reports going wrong after it started. The directory is
+/// watched rather than the file, since a save that renames a new file over
+/// the old one would leave a watch on the file pointing at nothing.
+pub fn watch(
+    path: &Path,
+    on_change: impl Fn() + Send + 'static,
+    on_error: impl Fn(String) + Send + 'static,
+) -> Result<ConfigWatcher, String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let name = path.file_name().map(|name| name.to_os_string());
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| match result {
+        Ok(events) => {
+            if events
+                .iter()
+                .any(|event| event.path.file_name() == name.as_deref())
+            {
+                on_change();
+            }
+        }
+        Err(e) => on_error(e.to_string()),
+    })
+    .map_err(|e| e.to_string())?;
+    debouncer
+        .watcher()
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("{}: {e}", dir.display()))?;
+    Ok(debouncer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn changes_are_per_section() {
+        let old = yaml("mqtt: { host: nas }\nmodules: { exec: {} }\nlogLevel: info");
+        let new =
+            yaml("mqtt: { host: nas }\nmodules: { exec: { enabled: false } }\nlogLevel: debug");
+        let changes = Changes::between(&old, &new);
+        assert_eq!(
+            changes,
+            Changes {
+                modules: true,
+                ..Changes::default()
+            }
+        );
+        assert_eq!(changes.describe(), "modules");
+    }
+
+    #[test]
+    fn a_section_that_appears_or_goes_away_is_a_change() {
+        let old = yaml("mqtt: { host: nas }");
+        let new = yaml("mqtt: { host: nas }\ntray: { hotkey: none }");
+        assert!(Changes::between(&old, &new).tray);
+        assert!(Changes::between(&new, &old).tray);
+        assert!(!Changes::between(&old, &old).any());
+    }
+
+    #[test]
+    fn everything_changed_since_an_unreadable_start() {
+        let new = yaml("mqtt: { host: nas }\nmodules: {}\ntray: {}");
+        assert_eq!(
+            Changes::between(&Value::Null, &new).describe(),
+            "mqtt, modules, tray"
+        );
+    }
+}
@@ -1,12 +1,50 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod config_watch;
+mod mqtt_acks;
+mod mqtt_acl;
 mod mqtt_bridge;
+mod mqtt_broker;
+mod mqtt_cache;
+mod mqtt_channel;
+mod mqtt_client;
+mod mqtt_discovery;
+mod mqtt_freshness;
+mod mqtt_outbox;
+mod mqtt_signing;
+mod mqtt_stats;
+mod mqtt_subscriptions;
+mod mqtt_takeover;
+mod mqtt_tls;
+mod mqtt_topics;
+mod traffic_capture;
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
-use mqtt_bridge::{MqttBridge, MqttConfig, MqttEvent};
+use config_watch::{Changes, ConfigWatcher};
+use mqtt_acl::{Acl, Check, Rules};
+use mqtt_bridge::{
+    qos_from_level, Availability, Backoff, BrokerEndpoint, EventReceiver, Failover, MqttBridge,
+    MqttConfig, MqttEvent,
+};
+use mqtt_broker::{EmbeddedBroker, EmbeddedConfig};
+use mqtt_cache::{CachePolicy, CachedValue};
+use mqtt_channel::{ChannelConfig, Overflow};
+use mqtt_client::{MessageProperties, Protocol};
+use mqtt_discovery::{AudioTopics, DeviceInfo, Exposed};
+use mqtt_freshness::FreshnessConfig;
+use mqtt_outbox::{OutboxConfig, QueuePolicy};
+use mqtt_signing::SigningConfig;
+use mqtt_stats::{BridgeStatus, ConnectionState};
+use mqtt_subscriptions::SubscriptionInfo;
+use mqtt_tls::TlsConfig;
 use rumqttc::QoS;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tauri::{
     async_runtime::Mutex,
     menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
@@ -16,41 +54,176 @@ use tauri::{
 use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutState};
 use tauri_plugin_opener::OpenerExt;
 use tauri_plugin_shell::{process::CommandEvent, ShellExt};
+use traffic_capture::{Capture, Direction};
 
 // --- IPC protocol types ---
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "camelCase")]
 enum IpcFromJs {
-    Subscribe { topics: Vec<String> },
-    Unsubscribe { topics: Vec<String> },
+    Subscribe {
+        topics: Vec<SubscribeTopic>,
+        #[serde(default = "default_owner")]
+        owner: String,
+    },
+    Unsubscribe {
+        topics: Vec<String>,
+        #[serde(default = "default_owner")]
+        owner: String,
+    },
     Publish {
         topic: String,
         payload: String,
         #[serde(default)]
+        encoding: PayloadEncoding,
+        #[serde(default)]
         options: PublishOptions,
+        /// Set when the caller wants a `publishResult` back.
+        #[serde(default)]
+        id: Option<String>,
+    },
+    /// Last cached value of a topic, answered with `topicValue`.
+    GetTopicValue { id: String, topic: String },
+    /// Cached topics, optionally under a filter, answered with `topicList`.
+    ListTopics {
+        id: String,
+        #[serde(default)]
+        filter: Option<String>,
     },
 }
 
+/// Owner of subscriptions from a child that doesn't name its module.
+fn default_owner() -> String {
+    "node".into()
+}
+
+/// A `subscribe` entry: a bare filter (QoS 0, the original protocol) or
+/// `{ "topic": ..., "qos": n }`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum SubscribeTopic {
+    Filter(String),
+    WithQos {
+        topic: String,
+        #[serde(default)]
+        qos: u8,
+    },
+}
+
+impl SubscribeTopic {
+    fn into_pair(self) -> (String, QoS) {
+        match self {
+            SubscribeTopic::Filter(topic) => (topic, QoS::AtMostOnce),
+            SubscribeTopic::WithQos { topic, qos } => (topic, qos_from_level(qos)),
+        }
+    }
+}
+
+/// How a payload travels inside the JSON lines. Text stays readable as-is;
+/// anything that isn't valid UTF-8 (images, CBOR) goes as base64 so no byte
+/// is lost to lossy decoding.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PayloadEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+fn encode_payload(bytes: Vec<u8>) -> (String, PayloadEncoding) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, PayloadEncoding::Utf8),
+        Err(e) => (BASE64.encode(e.into_bytes()), PayloadEncoding::Base64),
+    }
+}
+
+fn decode_payload(payload: String, encoding: PayloadEncoding) -> Result<Vec<u8>, String> {
+    match encoding {
+        PayloadEncoding::Utf8 => Ok(payload.into_bytes()),
+        PayloadEncoding::Base64 => BASE64
+            .decode(payload)
+            .map_err(|e| format!("invalid base64 payload: {e}")),
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 struct PublishOptions {
     #[serde(default)]
     retain: bool,
     #[serde(default)]
     qos: u8,
+    /// Свойства MQTT 5; на 3.1.1 их некуда положить, и они отбрасываются.
+    #[serde(default)]
+    properties: Option<MessageProperties>,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "type", rename_all = "camelCase")]
 enum IpcToJs {
-    Message { topic: String, payload: String },
+    Message {
+        topic: String,
+        payload: String,
+        encoding: PayloadEncoding,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        properties: Option<MessageProperties>,
+    },
     Connected,
-    Disconnected { reason: String },
+    Disconnected {
+        reason: String,
+    },
+    /// A subscribe/unsubscribe/publish the bridge couldn't carry out, or a
+    /// subscription the broker rejected.
+    Error {
+        operation: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        reason: String,
+    },
     Action {
         action: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         payload: Option<serde_json::Value>,
     },
+    /// Ответ на publish с `id`: брокер подтвердил (для QoS 0 — отправлено)
+    /// или нет, с причиной.
+    PublishResult {
+        id: String,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    TopicValue {
+        id: String,
+        value: Option<TopicValue>,
+    },
+    TopicList {
+        id: String,
+        topics: Vec<String>,
+    },
+}
+
+/// Значение из кэша моста — для `get_topic_value` и IPC-запроса `getTopicValue`.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct TopicValue {
+    topic: String,
+    payload: String,
+    encoding: PayloadEncoding,
+    retained: bool,
+    received_at: String,
+}
+
+impl TopicValue {
+    fn new(topic: &str, value: CachedValue) -> Self {
+        let (payload, encoding) = encode_payload(value.payload);
+        Self {
+            topic: topic.to_string(),
+            payload,
+            encoding,
+            retained: value.retained,
+            received_at: chrono::DateTime::<Local>::from(value.received).to_rfc3339(),
+        }
+    }
 }
 
 // --- App state ---
@@ -58,10 +231,53 @@ enum IpcToJs {
 #[derive(Default)]
 struct ServerState(Arc<Mutex<Option<tauri_plugin_shell::process::CommandChild>>>);
 
-struct BridgeState(Arc<MqttBridge>);
+/// Текущий мост. При перечитывании `mqtt` из config.yml он заменяется
+/// целиком, поэтому `Arc` берётся через `get()` на каждое действие, а не
+/// запоминается надолго.
+struct BridgeState {
+    bridge: std::sync::RwLock<Arc<MqttBridge>>,
+    /// Одна перестройка за раз.
+    reloading: Mutex<()>,
+}
+
+impl BridgeState {
+    fn new(bridge: Arc<MqttBridge>) -> Self {
+        Self {
+            bridge: std::sync::RwLock::new(bridge),
+            reloading: Mutex::new(()),
+        }
+    }
+
+    fn get(&self) -> Arc<MqttBridge> {
+        self.bridge.read().unwrap().clone()
+    }
+}
 
 struct MqttConnected(Arc<std::sync::atomic::AtomicBool>);
 
+/// Запись IPC-трафика в JSONL и воспроизведение записи в Node (см.
+/// `traffic_capture`). Пока идёт воспроизведение, живые сообщения MQTT до
+/// Node не доходят, а его публикации не уходят брокеру: Node видит только
+/// запись, брокер — ничего из неё.
+#[derive(Default)]
+struct TrafficState {
+    capture: std::sync::Mutex<Option<Capture>>,
+    replaying: std::sync::atomic::AtomicBool,
+    replay: std::sync::Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
+}
+
+impl TrafficState {
+    fn record(&self, dir: Direction, ipc: &impl Serialize) {
+        if let Some(capture) = self.capture.lock().unwrap().as_ref() {
+            capture.record(dir, ipc);
+        }
+    }
+
+    fn replaying(&self) -> bool {
+        self.replaying.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
 #[derive(Clone, Serialize)]
 struct LogPayload {
     message: String,
@@ -70,10 +286,31 @@ struct LogPayload {
 
 struct AutoplaceTimer(Mutex<Option<tauri::async_runtime::JoinHandle<()>>>);
 
+/// Что сейчас заведено `apply_app_topics`: с этим сравнивается следующий
+/// конфиг, лишнее снимается.
+#[derive(Default)]
+struct AppTopicsState(Mutex<WiredAppTopics>);
+
+#[derive(Default)]
+struct WiredAppTopics {
+    wiring: AppWiring,
+    status_task: Option<tauri::async_runtime::JoinHandle<()>>,
+}
+
 struct HotkeyMenuItems(Vec<CheckMenuItem<tauri::Wry>>);
 struct IntervalMenuItems(Vec<CheckMenuItem<tauri::Wry>>);
+struct CaptureMenuItem(CheckMenuItem<tauri::Wry>);
 struct CurrentShortcut(Mutex<Option<String>>);
 
+/// Слежение за config.yml: последний удачно разобранный конфиг (с ним
+/// сравнивается каждое сохранение) и сам наблюдатель — пока он лежит в
+/// state, слежение идёт.
+struct ConfigWatch {
+    path: PathBuf,
+    last_good: Mutex<serde_yaml::Value>,
+    _watcher: std::sync::Mutex<ConfigWatcher>,
+}
+
 // --- Send command to JS child via IPC ---
 
 async fn send_command(app: &tauri::AppHandle, action: &str) {
@@ -92,6 +329,7 @@ async fn send_command_with(
             action: action.to_string(),
             payload,
         };
+        app.state::<TrafficState>().record(Direction::ToNode, &msg);
         let line = match serde_json::to_string(&msg) {
             Ok(s) => s + "\n",
             Err(_) => return,
@@ -126,6 +364,179 @@ async fn shutdown_node(app: &tauri::AppHandle) {
     }
 }
 
+/// Запуск Node-процесса, если он ещё не запущен. Ошибку пишет в лог.
+async fn start_node(app: &tauri::AppHandle) {
+    let state = app.state::<ServerState>();
+    let mut guard = state.0.lock().await;
+    if guard.is_some() {
+        return;
+    }
+    match spawn_node_server(app, state.0.clone()) {
+        Ok(mut child) => {
+            replay_connected_if_needed(app, &mut child);
+            *guard = Some(child);
+        }
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Failed to start Node server: {e}"),
+                    level: "error".into(),
+                },
+            );
+        }
+    }
+}
+
+/// `<base>/app/node` обрабатывается в Rust: `start` или `restart` должны
+/// работать как раз тогда, когда Node упал или завис. Только при
+/// `mqtt.node_control: true`: иначе перезапускать Node мог бы любой клиент
+/// брокера.
+async fn route_node_commands(app: &tauri::AppHandle, bridge: &MqttBridge, topic: &str) {
+    let handler_app = app.clone();
+    let handler = Arc::new(move |topic: &str, payload: &[u8]| {
+        let app = handler_app.clone();
+        let command = String::from_utf8_lossy(payload).trim().to_string();
+        let topic = topic.to_string();
+        tauri::async_runtime::spawn(async move {
+            match command.as_str() {
+                "start" => start_node(&app).await,
+                "restart" => {
+                    shutdown_node(&app).await;
+                    start_node(&app).await;
+                }
+                other => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("{topic}: unknown command '{other}' (start, restart)"),
+                            level: "warn".into(),
+                        },
+                    );
+                }
+            }
+        });
+    });
+    if let Err(e) = bridge.route(topic, QoS::AtLeastOnce, handler).await {
+        let _ = app.emit(
+            "server-log",
+            LogPayload {
+                message: format!("MQTT route {topic}: {e}"),
+                level: "error".into(),
+            },
+        );
+    }
+}
+
+/// Раз в `interval` — retained JSON со статусом моста в `<base>/app/bridge`.
+/// Только пока есть соединение: офлайн-очередь снимками не забиваем.
+fn spawn_status_publisher(
+    app: tauri::AppHandle,
+    topic: String,
+    interval: Duration,
+) -> tauri::async_runtime::JoinHandle<()> {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let bridge = app.state::<BridgeState>().get();
+            let status = bridge.status();
+            if status.state != ConnectionState::Connected {
+                continue;
+            }
+            if let Ok(json) = serde_json::to_vec(&status) {
+                bridge
+                    .publish(&topic, json, true, QoS::AtMostOnce, None, None)
+                    .await;
+            }
+        }
+    })
+}
+
+/// Служебные топики по `mqtt.base` и `mqtt.discovery`, которые обслуживает
+/// сам Rust.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct AppWiring {
+    /// Маршрут `<base>/app/node`, если `mqtt.node_control`.
+    node_commands: Option<String>,
+    /// Маршрут `<prefix>/status` для повторной публикации discovery.
+    discovery_refresh: Option<String>,
+    /// `<base>/app/bridge` и период публикации статуса.
+    status: Option<(String, Duration)>,
+}
+
+fn app_wiring(mqtt: &serde_yaml::Value) -> Result<AppWiring, String> {
+    let mut wiring = AppWiring::default();
+    if let Some(topics) = read_app_topics(mqtt)? {
+        if topics.node_control {
+            wiring.node_commands = Some(format!("{}/app/node", topics.base));
+        }
+        if !topics.status_interval.is_zero() {
+            let topic = format!("{}/app/bridge", topics.base);
+            wiring.status = Some((topic, topics.status_interval));
+        }
+    }
+    if let Some(prefix) = read_discovery(mqtt)? {
+        wiring.discovery_refresh = Some(format!("{prefix}/status"));
+    }
+    Ok(wiring)
+}
+
+/// Приводит служебные топики к config.yml: маршруты `<base>/app/node` и
+/// `<prefix>/status`, публикацию `<base>/app/bridge`. Зовётся на старте и
+/// после каждой пересборки моста; что поменялось, снимается и заводится
+/// заново, остальное не трогается.
+async fn apply_app_topics(app: &tauri::AppHandle) {
+    let wanted = resolve_app_root(app)
+        .and_then(|root| read_config_value(&resolve_config_path(app, &root)))
+        .and_then(|config| match config.get("mqtt") {
+            Some(mqtt) => app_wiring(mqtt),
+            None => Ok(AppWiring::default()),
+        });
+    let wanted = match wanted {
+        Ok(wanted) => wanted,
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("MQTT config error: {e}"),
+                    level: "error".into(),
+                },
+            );
+            return;
+        }
+    };
+
+    let state = app.state::<AppTopicsState>();
+    let mut wired = state.0.lock().await;
+    let bridge = app.state::<BridgeState>().get();
+    if wired.wiring.node_commands != wanted.node_commands {
+        if let Some(topic) = &wired.wiring.node_commands {
+            bridge.unroute(topic).await;
+        }
+        if let Some(topic) = &wanted.node_commands {
+            route_node_commands(app, &bridge, topic).await;
+        }
+    }
+    if wired.wiring.discovery_refresh != wanted.discovery_refresh {
+        if let Some(topic) = &wired.wiring.discovery_refresh {
+            bridge.unroute(topic).await;
+        }
+        if let Some(topic) = &wanted.discovery_refresh {
+            route_discovery_refresh(app, &bridge, topic).await;
+        }
+    }
+    if wired.wiring.status != wanted.status {
+        if let Some(task) = wired.status_task.take() {
+            task.abort();
+        }
+        wired.status_task = wanted
+            .status
+            .clone()
+            .map(|(topic, interval)| spawn_status_publisher(app.clone(), topic, interval));
+    }
+    wired.wiring = wanted;
+}
+
 // Windows reports a native crash as an NTSTATUS exit code, not a signal, and
 // nothing in-process can catch it: an access violation in a native addon kills
 // node outright (verified — Node's process.report writes nothing for it, and
@@ -189,226 +600,2052 @@ fn read_mqtt_config(config_path: &PathBuf) -> Result<MqttConfig, String> {
 
     let config: serde_yaml::Value =
         serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse config: {}", e))?;
+    mqtt_config_from(&config, config_path)
+}
 
+/// Секция `mqtt` уже разобранного config.yml; `config_path` нужен для
+/// относительных путей к сертификатам.
+fn mqtt_config_from(config: &serde_yaml::Value, config_path: &Path) -> Result<MqttConfig, String> {
     let mqtt = config
         .get("mqtt")
         .ok_or_else(|| "Config does not define mqtt section".to_string())?;
 
-    let host = mqtt
-        .get("host")
-        .and_then(|v| v.as_str())
-        .unwrap_or("localhost")
-        .to_string();
-    let port = mqtt
-        .get("port")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(1883) as u16;
-    let username = mqtt.get("user").and_then(|v| v.as_str()).map(String::from);
-    let password = mqtt
-        .get("password")
-        .and_then(|v| v.as_str())
-        .map(String::from);
+    // `mqtt.brokers`, when given, replaces `mqtt.host`: the first entry is the
+    // primary, the rest are tried in order when it is unreachable.
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    let client_id = read_client_id(mqtt, config_dir)?;
+    let mut brokers = match mqtt.get("brokers") {
+        None => vec![read_broker(mqtt, mqtt, config_dir)?],
+        Some(list) => {
+            let entries = list
+                .as_sequence()
+                .filter(|entries| !entries.is_empty())
+                .ok_or("mqtt.brokers must be a non-empty list")?;
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    read_broker(entry, mqtt, config_dir)
+                        .map_err(|e| format!("mqtt.brokers[{i}]: {e}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let primary = brokers.remove(0);
+    let failover = read_failover(mqtt.get("failover"))?;
 
-    let client_id = format!(
-        "windows-mqtt-{}",
-        std::env::var("COMPUTERNAME")
-            .or_else(|_| std::env::var("HOSTNAME"))
-            .unwrap_or_else(|_| "unknown".into())
-    );
+    let availability = read_availability(mqtt)?;
+    let outbox = read_outbox_config(mqtt)?;
+
+    let keep_alive = match mqtt.get("keepalive") {
+        None => Duration::from_secs(30),
+        Some(v) => Duration::from_secs(
+            v.as_u64()
+                .ok_or("mqtt.keepalive must be a number of seconds (0 disables it)")?,
+        ),
+    };
+    let clean_session = match mqtt.get("clean_session") {
+        None => false,
+        Some(v) => v
+            .as_bool()
+            .ok_or("mqtt.clean_session must be true or false")?,
+    };
+    let max_inflight = match mqtt.get("max_inflight") {
+        None => 100,
+        Some(v) => v
+            .as_u64()
+            .and_then(|n| u16::try_from(n).ok())
+            .filter(|n| *n > 0)
+            .ok_or("mqtt.max_inflight must be between 1 and 65535")?,
+    };
+    let reconnect = read_backoff(mqtt.get("reconnect"))?;
+    let cache = read_cache_policy(mqtt.get("cache"))?;
+    let node_queue = read_node_queue(mqtt, keep_alive)?;
+    let embedded = read_embedded(mqtt)?;
+    let acl = read_acl(mqtt)?;
+    let signing = read_signing(mqtt)?;
+    let freshness = read_stale_commands(mqtt)?;
+    let protocol = read_protocol(mqtt, keep_alive)?;
+    if embedded.is_some() && !brokers.is_empty() {
+        return Err(
+            "mqtt.embedded shares topics with a single upstream broker, not mqtt.brokers".into(),
+        );
+    }
+    if embedded.is_some() && protocol == Protocol::V5 {
+        return Err("mqtt.embedded speaks MQTT 3.1.1 only, drop mqtt.protocol: 5".into());
+    }
 
     Ok(MqttConfig {
+        host: primary.host,
+        port: primary.port,
+        username: primary.username,
+        password: primary.password,
+        client_id,
+        tls: primary.tls,
+        ws_path: primary.ws_path,
+        availability,
+        outbox,
+        keep_alive,
+        clean_session,
+        max_inflight,
+        reconnect,
+        fallbacks: brokers,
+        failover,
+        cache,
+        node_queue,
+        embedded,
+        acl,
+        signing,
+        freshness,
+        protocol,
+    })
+}
+
+// `mqtt.client_id`: a template, `windows-mqtt-{hostname}` by default.
+// `{hostname}` and `{user}` come from the environment, `{profile}` is a short
+// hash of the config directory (two installs under one user differ).
+fn read_client_id(mqtt: &serde_yaml::Value, config_dir: &Path) -> Result<String, String> {
+    let template = match mqtt.get("client_id") {
+        None => "windows-mqtt-{hostname}",
+        Some(v) => v.as_str().ok_or("mqtt.client_id must be a string")?,
+    };
+    let env = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+    };
+    expand_client_id(
+        template,
+        &[
+            ("hostname", env(&["COMPUTERNAME", "HOSTNAME"])),
+            ("user", env(&["USERNAME", "USER"])),
+            ("profile", Some(profile_id(config_dir))),
+        ],
+    )
+}
+
+/// Подстановка `{имя}` в шаблон client id. Значение, которого в окружении
+/// нет, заменяется случайным: общий на всех запасной вариант вроде
+/// `unknown` у двух машин означал бы, что брокер выбивает их друг другом.
+fn expand_client_id(template: &str, vars: &[(&str, Option<String>)]) -> Result<String, String> {
+    let mut id = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        id.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or("mqtt.client_id: unclosed '{'")?;
+        let name = &rest[start + 1..end];
+        let (_, value) = vars.iter().find(|(var, _)| *var == name).ok_or_else(|| {
+            format!("mqtt.client_id: unknown placeholder {{{name}}} (hostname, user, profile)")
+        })?;
+        match value {
+            Some(value) => id.push_str(value),
+            None => id.push_str(&format!("{:08x}", fastrand::u32(..))),
+        }
+        rest = &rest[end + 1..];
+    }
+    id.push_str(rest);
+    if id.is_empty() {
+        return Err("mqtt.client_id must not be empty".into());
+    }
+    Ok(id)
+}
+
+/// `{profile}`: 8 hex-цифр FNV-1a от каталога конфига. Стабилен между
+/// запусками и версиями (в отличие от `DefaultHasher`); регистр не важен,
+/// как и в путях Windows.
+fn profile_id(config_dir: &Path) -> String {
+    let path = config_dir.to_string_lossy().to_lowercase();
+    let hash = path.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    format!("{hash:08x}")
+}
+
+// `mqtt.cache`: `retained` (default), `all`, or `false` to turn it off.
+fn read_cache_policy(value: Option<&serde_yaml::Value>) -> Result<CachePolicy, String> {
+    match value {
+        None => Ok(CachePolicy::default()),
+        Some(v) => match (v.as_bool(), v.as_str()) {
+            (Some(false), _) => Ok(CachePolicy::Off),
+            (Some(true), _) => Ok(CachePolicy::Retained),
+            (_, Some(name)) => CachePolicy::parse(name).map_err(|e| format!("mqtt.cache: {e}")),
+            _ => Err("mqtt.cache must be retained, all or false".into()),
+        },
+    }
+}
+
+// A broker is `mqtt` itself or an entry of `mqtt.brokers`: a host/URL string,
+// or a mapping with `host` and optionally `port`, `user`, `password`, `tls`.
+// What the entry leaves out comes from `mqtt`, except that `mqtt.tls` isn't
+// forced onto an entry whose URL says plain text.
+fn read_broker(
+    entry: &serde_yaml::Value,
+    mqtt: &serde_yaml::Value,
+    config_dir: &Path,
+) -> Result<BrokerEndpoint, String> {
+    let host = match entry.as_str() {
+        Some(host) => host,
+        None => entry
+            .get("host")
+            .and_then(|v| v.as_str())
+            .unwrap_or("localhost"),
+    };
+    let get = |key: &str| entry.get(key).or_else(|| mqtt.get(key));
+    let broker = parse_broker_host(
         host,
-        port,
+        get("port").and_then(|v| v.as_u64()).unwrap_or(1883) as u16,
+    )?;
+    let username = get("user").and_then(|v| v.as_str()).map(String::from);
+    let password = get("password").and_then(|v| v.as_str()).map(String::from);
+
+    let inherited_tls = match broker.secure {
+        Some(false) => None,
+        _ => mqtt.get("tls"),
+    };
+    let tls_value = entry.get("tls").or(inherited_tls);
+    let tls = match (broker.secure, read_tls_config(tls_value, config_dir)?) {
+        (Some(true), None) => Some(TlsConfig::default()),
+        (Some(false), Some(_)) => {
+            return Err(format!(
+                "mqtt.tls is set but mqtt.host '{}' is a plain-text URL",
+                broker.host
+            ))
+        }
+        (_, tls) => tls,
+    };
+
+    Ok(BrokerEndpoint {
+        host: broker.host,
+        port: broker.port,
         username,
         password,
-        client_id,
+        tls,
+        ws_path: broker.ws_path,
     })
 }
 
-// --- Spawn Node.js child with IPC bridge ---
+// `mqtt.failover`: when to leave a broker and how often to look back at the
+// primary. `probe_interval: 0` stays on the fallback until it fails too.
+fn read_failover(section: Option<&serde_yaml::Value>) -> Result<Failover, String> {
+    let mut failover = Failover::default();
+    let Some(section) = section else {
+        return Ok(failover);
+    };
+    if let Some(n) = section.get("after_failures") {
+        failover.after_failures = n
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|n| *n > 0)
+            .ok_or("mqtt.failover.after_failures must be a positive number")?;
+    }
+    if let Some(secs) = section.get("probe_interval") {
+        failover.probe_interval = Duration::from_secs(
+            secs.as_u64()
+                .ok_or("mqtt.failover.probe_interval must be a number of seconds")?,
+        );
+    }
+    Ok(failover)
+}
 
-fn spawn_node_server(
-    app: &tauri::AppHandle,
-    server_state: Arc<Mutex<Option<tauri_plugin_shell::process::CommandChild>>>,
-    bridge: Arc<MqttBridge>,
-) -> Result<tauri_plugin_shell::process::CommandChild, String> {
-    let app_root = resolve_app_root(app)?;
-    let server_path = app_root.join("src").join("index.js");
+// `mqtt.reconnect`: delays in seconds (fractions allowed), jitter as a
+// fraction of the delay.
+fn read_backoff(section: Option<&serde_yaml::Value>) -> Result<Backoff, String> {
+    let mut backoff = Backoff::default();
+    let Some(section) = section else {
+        return Ok(backoff);
+    };
+    let seconds = |key: &str| -> Result<Option<Duration>, String> {
+        section
+            .get(key)
+            .map(|v| {
+                v.as_f64()
+                    .filter(|s| s.is_finite() && *s >= 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or_else(|| format!("mqtt.reconnect.{key} must be a number of seconds"))
+            })
+            .transpose()
+    };
+    if let Some(min) = seconds("min_delay")? {
+        backoff.min_delay = min;
+    }
+    if let Some(max) = seconds("max_delay")? {
+        backoff.max_delay = max;
+    }
+    if backoff.max_delay < backoff.min_delay {
+        return Err("mqtt.reconnect.max_delay is shorter than min_delay".into());
+    }
+    if let Some(jitter) = section.get("jitter") {
+        backoff.jitter = jitter
+            .as_f64()
+            .filter(|j| (0.0..=1.0).contains(j))
+            .ok_or("mqtt.reconnect.jitter must be between 0 and 1")?;
+    }
+    Ok(backoff)
+}
 
-    if !server_path.exists() {
+// `mqtt.queue`: how publishes are held while the broker is down. The file
+// location isn't configurable — setup() puts it in the app data dir.
+fn read_outbox_config(mqtt: &serde_yaml::Value) -> Result<OutboxConfig, String> {
+    let mut outbox = OutboxConfig::default();
+    let Some(queue) = mqtt.get("queue") else {
+        return Ok(outbox);
+    };
+    if !queue.is_mapping() {
+        return Err("mqtt.queue must be a mapping".into());
+    }
+    if let Some(max) = queue.get("max_messages") {
+        outbox.max_messages =
+            max.as_u64()
+                .filter(|n| *n > 0)
+                .ok_or("mqtt.queue.max_messages must be a positive number")? as usize;
+    }
+    if let Some(policy) = queue.get("policy") {
+        let name = policy
+            .as_str()
+            .ok_or("mqtt.queue.policy must be a string")?;
+        outbox.default_policy =
+            QueuePolicy::parse(name).map_err(|e| format!("mqtt.queue.policy: {e}"))?;
+    }
+    if let Some(topics) = queue.get("topics") {
+        let topics = topics
+            .as_mapping()
+            .ok_or("mqtt.queue.topics maps topic filters to policy names")?;
+        for (filter, policy) in topics {
+            let (Some(filter), Some(name)) = (filter.as_str(), policy.as_str()) else {
+                return Err("mqtt.queue.topics maps topic filters to policy names".into());
+            };
+            let policy =
+                QueuePolicy::parse(name).map_err(|e| format!("mqtt.queue.topics.{filter}: {e}"))?;
+            outbox.policies.push((filter.to_string(), policy));
+        }
+    }
+    Ok(outbox)
+}
+
+// `mqtt.node_queue`: inbound messages waiting for the Node child. `block`
+// may hold the event loop for up to half the keep-alive, so pings still go
+// out in time.
+fn read_node_queue(
+    mqtt: &serde_yaml::Value,
+    keep_alive: Duration,
+) -> Result<ChannelConfig, String> {
+    let mut config = ChannelConfig {
+        block_limit: (!keep_alive.is_zero()).then(|| keep_alive / 2),
+        ..ChannelConfig::default()
+    };
+    let Some(queue) = mqtt.get("node_queue") else {
+        return Ok(config);
+    };
+    if !queue.is_mapping() {
+        return Err("mqtt.node_queue must be a mapping".into());
+    }
+    if let Some(max) = queue.get("max_messages") {
+        config.capacity = max
+            .as_u64()
+            .filter(|n| *n > 0)
+            .ok_or("mqtt.node_queue.max_messages must be a positive number")?
+            as usize;
+    }
+    if let Some(overflow) = queue.get("overflow") {
+        let name = overflow
+            .as_str()
+            .ok_or("mqtt.node_queue.overflow must be a string")?;
+        config.overflow =
+            Overflow::parse(name).map_err(|e| format!("mqtt.node_queue.overflow: {e}"))?;
+    }
+    Ok(config)
+}
+
+/// `mqtt.base` без завершающего слэша — префикс служебных топиков.
+fn mqtt_base(mqtt: &serde_yaml::Value) -> Option<&str> {
+    mqtt.get("base")
+        .and_then(|v| v.as_str())
+        .map(|base| base.trim_end_matches('/'))
+}
+
+/// Служебные топики приложения под `mqtt.base`.
+#[derive(Debug, PartialEq)]
+struct AppTopics {
+    base: String,
+    /// Период публикации `<base>/app/bridge`; ноль — не публиковать.
+    status_interval: Duration,
+    /// `mqtt.node_control`: слушать `<base>/app/node`.
+    node_control: bool,
+}
+
+fn read_app_topics(mqtt: &serde_yaml::Value) -> Result<Option<AppTopics>, String> {
+    let Some(base) = mqtt_base(mqtt) else {
+        return Ok(None);
+    };
+    let status_interval = match mqtt.get("status_interval") {
+        None => Duration::from_secs(60),
+        Some(v) => Duration::from_secs(
+            v.as_u64()
+                .ok_or("mqtt.status_interval must be a number of seconds (0 disables it)")?,
+        ),
+    };
+    let node_control = match mqtt.get("node_control") {
+        None => false,
+        Some(v) => v
+            .as_bool()
+            .ok_or("mqtt.node_control must be true or false")?,
+    };
+    Ok(Some(AppTopics {
+        base: base.to_string(),
+        status_interval,
+        node_control,
+    }))
+}
+
+// `mqtt.discovery`: `true` announces to Home Assistant under the usual
+// `homeassistant` prefix, `{ prefix: ... }` under another one. Off by default.
+fn read_discovery(mqtt: &serde_yaml::Value) -> Result<Option<String>, String> {
+    match mqtt.get("discovery") {
+        None => Ok(None),
+        Some(serde_yaml::Value::Bool(on)) => Ok(on.then(|| "homeassistant".to_string())),
+        Some(section) if section.is_mapping() => {
+            let prefix = section
+                .get("prefix")
+                .map(|v| v.as_str().ok_or("mqtt.discovery.prefix must be a string"))
+                .transpose()?
+                .unwrap_or("homeassistant")
+                .trim_end_matches('/');
+            if prefix.is_empty() {
+                return Err("mqtt.discovery.prefix must not be empty".into());
+            }
+            Ok(Some(prefix.to_string()))
+        }
+        Some(_) => Err("mqtt.discovery must be true, false or a section".into()),
+    }
+}
+
+/// Что анонсировать в Home Assistant: топики — те же, что собирает
+/// `initModules` в `src/helpers.js` (база модуля — `modules.<name>.base`
+/// или `<mqtt.base>/<name>`, у power — историческая `<mqtt.base>/windows`).
+fn discovery_exposed(config: &serde_yaml::Value, base: &str) -> Result<Exposed, String> {
+    let enabled = enabled_modules(config)?;
+    let module = |name: &str| config.get("modules").and_then(|m| m.get(name));
+    let module_base = |name: &str, default: String| {
+        module(name)
+            .and_then(|m| m.get("base"))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .unwrap_or(default)
+    };
+    let topic = |section: Option<&serde_yaml::Value>, key: &str, default: &str| {
+        section
+            .and_then(|s| s.get(key))
+            .and_then(|v| v.as_str())
+            .unwrap_or(default)
+            .to_string()
+    };
+
+    let windows_base = format!("{base}/windows");
+    let power_base = enabled
+        .iter()
+        .any(|m| m == "power")
+        .then(|| module_base("power", module_base("windows", windows_base.clone())));
+    let audio = enabled.iter().any(|m| m == "audio").then(|| {
+        let audio_base = module_base("audio", format!("{base}/audio"));
+        let audio = module("audio");
+        let volume = audio.and_then(|a| a.get("volume"));
+        let mute = audio.and_then(|a| a.get("mute"));
+        let device = audio.and_then(|a| a.get("device"));
+        let devices = match device {
+            Some(serde_yaml::Value::Bool(false)) => None,
+            _ => Some((
+                format!("{audio_base}{}", topic(device, "stat", "/device")),
+                format!(
+                    "{audio_base}{}",
+                    topic(device, "playbackStat", "/device/playback")
+                ),
+            )),
+        };
+        AudioTopics {
+            volume_set: format!("{audio_base}{}", topic(volume, "set", "/volume/set")),
+            volume_stat: format!("{audio_base}{}", topic(volume, "stat", "/volume")),
+            mute_set: format!("{audio_base}{}", topic(mute, "set", "/mute/set")),
+            mute_stat: format!("{audio_base}{}", topic(mute, "stat", "/mute")),
+            devices,
+        }
+    });
+    Ok(Exposed {
+        windows_base,
+        power_base,
+        audio,
+    })
+}
+
+// A list of topic filters at config key `key`, `None` when it isn't there.
+// Checked the way `mqtt_topics` matches them, so a filter the bridge would
+// never match is an error here rather than a rule that silently does nothing.
+fn read_topic_filters(
+    list: Option<&serde_yaml::Value>,
+    key: &str,
+) -> Result<Option<Vec<String>>, String> {
+    let Some(list) = list else {
+        return Ok(None);
+    };
+    let list = list
+        .as_sequence()
+        .ok_or_else(|| format!("{key} must be a list of topic filters"))?;
+    list.iter()
+        .map(|filter| {
+            filter
+                .as_str()
+                .filter(|filter| mqtt_topics::valid_filter(filter))
+                .map(str::to_string)
+                .ok_or_else(|| format!("{key}: invalid topic filter {filter:?}"))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// `mqtt.embedded`: `true` runs a broker on 127.0.0.1:1883 for the bridge
+// and local clients. `listen` takes a port or an address, and
+// `upstream: { out: [...], in: [...] }` lists the filters shared with the
+// broker in `mqtt.host` while it is reachable. `user` and `password` make
+// clients log in; they are required when `listen` isn't a loopback address.
+fn read_embedded(mqtt: &serde_yaml::Value) -> Result<Option<EmbeddedConfig>, String> {
+    let section = match mqtt.get("embedded") {
+        None | Some(serde_yaml::Value::Bool(false)) => return Ok(None),
+        Some(serde_yaml::Value::Bool(true)) => None,
+        Some(section) if section.is_mapping() => Some(section),
+        Some(_) => return Err("mqtt.embedded must be true, false or a section".into()),
+    };
+    let listen = match section.and_then(|s| s.get("listen")) {
+        None => std::net::SocketAddr::from(([127, 0, 0, 1], 1883)),
+        Some(serde_yaml::Value::Number(port)) => port
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .map(|port| std::net::SocketAddr::from(([127, 0, 0, 1], port)))
+            .ok_or("mqtt.embedded.listen must be a port or an address like 127.0.0.1:1883")?,
+        Some(v) => v
+            .as_str()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or("mqtt.embedded.listen must be a port or an address like 127.0.0.1:1883")?,
+    };
+    if listen.port() == 0 {
+        return Err("mqtt.embedded.listen needs a fixed port".into());
+    }
+    let get = |key: &str| section.and_then(|s| s.get(key)).and_then(|v| v.as_str());
+    let (username, password) = match (get("user"), get("password")) {
+        (Some(user), Some(password)) => (Some(user.to_string()), Some(password.to_string())),
+        (None, None) => (None, None),
+        _ => return Err("mqtt.embedded needs both user and password, or neither".into()),
+    };
+    if username.is_none() && !listen.ip().is_loopback() {
         return Err(format!(
-            "Server entry not found at {}",
-            server_path.display()
+            "mqtt.embedded.listen {listen} is reachable from other machines: \
+             set mqtt.embedded.user and password, or listen on 127.0.0.1"
         ));
     }
+    let upstream = section.and_then(|s| s.get("upstream"));
+    let filters = |key: &str| {
+        let list = upstream.and_then(|u| u.get(key));
+        read_topic_filters(list, &format!("mqtt.embedded.upstream.{key}"))
+            .map(Option::unwrap_or_default)
+    };
+    Ok(Some(EmbeddedConfig {
+        listen,
+        username,
+        password,
+        outgoing: filters("out")?,
+        incoming: filters("in")?,
+    }))
+}
+
+// `mqtt.acl`: `inbound` limits what reaches Node, `outbound` what Node may
+// publish and subscribe to. Each takes `allow` and `deny` lists of topic
+// filters; without `allow` everything not denied passes.
+fn read_acl(mqtt: &serde_yaml::Value) -> Result<Acl, String> {
+    let Some(section) = mqtt.get("acl") else {
+        return Ok(Acl::default());
+    };
+    if !section.is_mapping() {
+        return Err("mqtt.acl must be a section with inbound and outbound".into());
+    }
+    let rules = |direction: &str| -> Result<Rules, String> {
+        let Some(rules) = section.get(direction) else {
+            return Ok(Rules::default());
+        };
+        let filters =
+            |key: &str| read_topic_filters(rules.get(key), &format!("mqtt.acl.{direction}.{key}"));
+        Ok(Rules {
+            allow: filters("allow")?,
+            deny: filters("deny")?.unwrap_or_default(),
+        })
+    };
+    Ok(Acl {
+        inbound: rules("inbound")?,
+        outbound: rules("outbound")?,
+    })
+}
+
+// `mqtt.signing`: messages on `topics` only reach Node wrapped as
+// `{payload, ts, sig}`, signed with `secret` (see `--sign`) no more than
+// `max_age` seconds (30 by default) from this PC's clock.
+fn read_signing(mqtt: &serde_yaml::Value) -> Result<Option<SigningConfig>, String> {
+    let Some(section) = mqtt.get("signing") else {
+        return Ok(None);
+    };
+    let secret = section
+        .get("secret")
+        .and_then(|v| v.as_str())
+        .filter(|secret| !secret.is_empty())
+        .ok_or("mqtt.signing.secret must be a non-empty string")?;
+    let topics = read_topic_filters(section.get("topics"), "mqtt.signing.topics")?
+        .filter(|topics| !topics.is_empty())
+        .ok_or("mqtt.signing.topics must be a non-empty list of topic filters")?;
+    let max_age = match section.get("max_age") {
+        None => Duration::from_secs(30),
+        Some(v) => Duration::from_secs(
+            v.as_u64()
+                .filter(|secs| *secs > 0)
+                .ok_or("mqtt.signing.max_age must be a positive number of seconds")?,
+        ),
+    };
+    Ok(Some(SigningConfig {
+        secret: secret.to_string(),
+        topics,
+        max_age,
+    }))
+}
+
+// `mqtt.stale_commands`: with `clean_session: false` the broker keeps QoS 1/2
+// messages for us while the PC is off. Messages on `topics` that arrive within
+// `session_grace` seconds (10 by default) of resuming such a session are
+// dropped, as are JSON payloads whose `ts` is older than `max_age` seconds.
+fn read_stale_commands(mqtt: &serde_yaml::Value) -> Result<Option<FreshnessConfig>, String> {
+    let Some(section) = mqtt.get("stale_commands") else {
+        return Ok(None);
+    };
+    let topics = read_topic_filters(section.get("topics"), "mqtt.stale_commands.topics")?
+        .filter(|topics| !topics.is_empty())
+        .ok_or("mqtt.stale_commands.topics must be a non-empty list of topic filters")?;
+    let seconds = |key: &str| -> Result<Option<Duration>, String> {
+        section
+            .get(key)
+            .map(|v| {
+                v.as_u64()
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| format!("mqtt.stale_commands.{key} must be a number of seconds"))
+            })
+            .transpose()
+    };
+    Ok(Some(FreshnessConfig {
+        topics,
+        max_age: seconds("max_age")?,
+        session_grace: seconds("session_grace")?.unwrap_or(Duration::from_secs(10)),
+    }))
+}
+
+// `mqtt.protocol`: 4 (MQTT 3.1.1, the default) or 5. Under 5 Node sees and
+// sets message properties, and refusals come with the broker's reason code.
+// rumqttc's MQTT 5 client can't go below a 5 second keep-alive or turn it off.
+fn read_protocol(mqtt: &serde_yaml::Value, keep_alive: Duration) -> Result<Protocol, String> {
+    let protocol = match mqtt.get("protocol") {
+        None => return Ok(Protocol::V311),
+        Some(v) => match v.as_u64() {
+            Some(4) => Protocol::V311,
+            Some(5) => Protocol::V5,
+            _ => return Err("mqtt.protocol must be 4 (MQTT 3.1.1) or 5".into()),
+        },
+    };
+    if protocol == Protocol::V5 && keep_alive < Duration::from_secs(5) {
+        return Err("mqtt.protocol: 5 needs mqtt.keepalive of at least 5 seconds".into());
+    }
+    Ok(protocol)
+}
+
+// `mqtt.availability` defaults to `<mqtt.base>/status` with online/offline
+// payloads; `availability: false` turns it off, and without a base there is
+// nowhere sensible to put it unless a topic is given explicitly.
+fn read_availability(mqtt: &serde_yaml::Value) -> Result<Option<Availability>, String> {
+    let section = mqtt.get("availability");
+    if section.and_then(|v| v.as_bool()) == Some(false) {
+        return Ok(None);
+    }
+    if let Some(value) = section {
+        if !value.is_mapping() && !value.is_null() && value.as_bool().is_none() {
+            return Err("mqtt.availability must be true/false or a mapping".into());
+        }
+    }
+
+    let get = |key: &str| {
+        section
+            .and_then(|v| v.get(key))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    let base = mqtt_base(mqtt);
+    let Some(topic) = get("topic").or_else(|| base.map(|base| format!("{base}/status"))) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Availability {
+        topic,
+        online: get("online").unwrap_or_else(|| "online".into()),
+        offline: get("offline").unwrap_or_else(|| "offline".into()),
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+struct BrokerHost {
+    host: String,
+    port: u16,
+    /// `None` for a bare host name: TLS is then decided by `mqtt.tls` alone.
+    secure: Option<bool>,
+    ws_path: Option<String>,
+}
+
+// `mqtt.host` is either a bare host (with `mqtt.port`) or a URL:
+// `mqtt://`, `mqtts://`, `ws://`, `wss://`. A URL carries its own port — or
+// the scheme's default — so a leftover `port: 1883` can't silently point a
+// `wss://` URL at the plain-text listener.
+fn parse_broker_host(host: &str, port: u16) -> Result<BrokerHost, String> {
+    let Some((scheme, rest)) = host.split_once("://") else {
+        return Ok(BrokerHost {
+            host: host.to_string(),
+            port,
+            secure: None,
+            ws_path: None,
+        });
+    };
+
+    let (secure, websocket, default_port) = match scheme.to_ascii_lowercase().as_str() {
+        "mqtt" | "tcp" => (false, false, 1883),
+        "mqtts" | "ssl" => (true, false, 8883),
+        "ws" => (false, true, 80),
+        "wss" => (true, true, 443),
+        _ => return Err(format!("mqtt.host: unsupported scheme '{scheme}://'")),
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if !websocket && !path.trim_matches('/').is_empty() {
+        return Err(format!(
+            "mqtt.host: '{host}' has a path, which only ws:// and wss:// use"
+        ));
+    }
+
+    // `[v6addr]:port`, `host:port` or a lone host.
+    let (name, port_text) = if let Some(v6) = authority.strip_prefix('[') {
+        let (name, after) = v6
+            .split_once(']')
+            .ok_or_else(|| format!("mqtt.host: unclosed '[' in '{host}'"))?;
+        (name, after.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if name.is_empty() {
+        return Err(format!("mqtt.host: no host name in '{host}'"));
+    }
+    let port = match port_text {
+        Some(text) => text
+            .parse::<u16>()
+            .map_err(|_| format!("mqtt.host: invalid port '{text}' in '{host}'"))?,
+        None => default_port,
+    };
+
+    Ok(BrokerHost {
+        host: name.to_string(),
+        port,
+        secure: Some(secure),
+        ws_path: websocket.then(|| if path.is_empty() { "/" } else { path }.to_string()),
+    })
+}
+
+// `mqtt.tls` is either `true` (system trust store, no client cert) or a
+// mapping. Relative file paths resolve against the config file's directory,
+// so a config.yml and its certs/ folder can be moved together.
+fn read_tls_config(
+    value: Option<&serde_yaml::Value>,
+    config_dir: &Path,
+) -> Result<Option<TlsConfig>, String> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if let Some(enabled) = value.as_bool() {
+        return Ok(enabled.then(TlsConfig::default));
+    }
+    if value.is_null() {
+        return Ok(None);
+    }
+    if !value.is_mapping() {
+        return Err("mqtt.tls must be true/false or a mapping".into());
+    }
+
+    // `server_name` is the old name of `cert_name`: it never changed the
+    // SNI, only the name the certificate is checked against. Still read, with
+    // a warning when the bridge sets up TLS.
+    let cert_name = value.get("cert_name").or_else(|| value.get("server_name"));
+    let path = |key: &str| {
+        value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(|p| config_dir.join(p))
+    };
+    Ok(Some(TlsConfig {
+        ca_file: path("ca"),
+        client_cert: path("cert"),
+        client_key: path("key"),
+        cert_name: cert_name.and_then(|v| v.as_str()).map(String::from),
+        legacy_server_name: value.get("cert_name").is_none() && value.get("server_name").is_some(),
+        insecure: value
+            .get("insecure")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    }))
+}
+
+// --- Spawn Node.js child with IPC bridge ---
+
+fn spawn_node_server(
+    app: &tauri::AppHandle,
+    server_state: Arc<Mutex<Option<tauri_plugin_shell::process::CommandChild>>>,
+) -> Result<tauri_plugin_shell::process::CommandChild, String> {
+    let app_root = resolve_app_root(app)?;
+    let server_path = app_root.join("src").join("index.js");
+
+    if !server_path.exists() {
+        return Err(format!(
+            "Server entry not found at {}",
+            server_path.display()
+        ));
+    }
+
+    // Resolve the config path here so the Node child reads exactly the same
+    // file the Rust side does (single source of truth, no drift).
+    let config_path = resolve_config_path(app, &app_root);
+
+    let (mut rx, child) = app
+        .shell()
+        .command("node")
+        .args([server_path.to_string_lossy().to_string()])
+        .env("TAURI_BRIDGE", "1")
+        .env("CONFIG", config_path.to_string_lossy().to_string())
+        .current_dir(app_root)
+        .spawn()
+        .map_err(|error| error.to_string())?;
+
+    let app_handle = app.clone();
+
+    // Task: read stdout from JS, dispatch IPC messages or log
+    tauri::async_runtime::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            match event {
+                CommandEvent::Stdout(buf) => {
+                    let line = String::from_utf8_lossy(&buf).to_string();
+                    let trimmed = line.trim();
+                    if trimmed.is_empty() {
+                        continue;
+                    }
+
+                    // The bridge may have been rebuilt since the last line.
+                    let bridge = app_handle.state::<BridgeState>().get();
+                    // Try to parse as IPC JSON
+                    match serde_json::from_str::<IpcFromJs>(trimmed) {
+                        Ok(ipc) => match ipc {
+                            IpcFromJs::Publish { topic, id, .. }
+                                if app_handle.state::<TrafficState>().replaying() =>
+                            {
+                                record_from_node(&app_handle, trimmed);
+                                if let Some(id) = id {
+                                    bridge
+                                        .reject_publish(id, "not published during a replay".into())
+                                        .await;
+                                }
+                                let _ = app_handle.emit(
+                                    "server-log",
+                                    LogPayload {
+                                        message: format!("Replay: held back publish to {topic}"),
+                                        level: "debug".into(),
+                                    },
+                                );
+                            }
+                            IpcFromJs::Subscribe { topics, owner } => {
+                                let mut allowed = Vec::with_capacity(topics.len());
+                                for topic in topics {
+                                    let (topic, qos) = topic.into_pair();
+                                    if bridge.node_may(Check::Subscribe, &topic).await {
+                                        allowed.push((topic, qos));
+                                    }
+                                }
+                                if !allowed.is_empty() {
+                                    bridge.subscribe(&owner, &allowed).await;
+                                }
+                            }
+                            IpcFromJs::Unsubscribe { topics, owner } => {
+                                bridge.unsubscribe(&owner, &topics).await;
+                            }
+                            IpcFromJs::Publish {
+                                topic,
+                                payload,
+                                encoding,
+                                options,
+                                id,
+                            } => {
+                                record_from_node(&app_handle, trimmed);
+                                if !bridge.node_may(Check::Publish, &topic).await {
+                                    if let Some(id) = id {
+                                        bridge.reject_publish(id, ACL_DENIED.into()).await;
+                                    }
+                                    continue;
+                                }
+                                let qos = qos_from_level(options.qos);
+                                match decode_payload(payload, encoding) {
+                                    Ok(payload) => {
+                                        bridge
+                                            .publish(
+                                                &topic,
+                                                payload,
+                                                options.retain,
+                                                qos,
+                                                options.properties,
+                                                id,
+                                            )
+                                            .await;
+                                    }
+                                    Err(e) => {
+                                        if let Some(id) = id {
+                                            bridge.reject_publish(id, e.clone()).await;
+                                        }
+                                        let _ = app_handle.emit(
+                                            "server-log",
+                                            LogPayload {
+                                                message: format!(
+                                                    "Dropped publish to {}: {}",
+                                                    topic, e
+                                                ),
+                                                level: "error".into(),
+                                            },
+                                        );
+                                    }
+                                }
+                            }
+                            IpcFromJs::GetTopicValue { id, topic } => {
+                                let value = bridge
+                                    .topic_value(&topic)
+                                    .map(|value| TopicValue::new(&topic, value));
+                                write_to_child(&app_handle, &IpcToJs::TopicValue { id, value })
+                                    .await;
+                            }
+                            IpcFromJs::ListTopics { id, filter } => {
+                                let topics = bridge.cached_topics(filter.as_deref());
+                                write_to_child(&app_handle, &IpcToJs::TopicList { id, topics })
+                                    .await;
+                            }
+                        },
+                        Err(_) => {
+                            // Not JSON — treat as log output
+                            let _ = app_handle.emit(
+                                "server-log",
+                                LogPayload {
+                                    message: line,
+                                    level: "info".into(),
+                                },
+                            );
+                        }
+                    }
+                }
+                CommandEvent::Stderr(buf) => {
+                    // In bridge mode the child redirects ALL console output to
+                    // stderr with a "[level] " tag; untagged lines (crash
+                    // traces, direct stderr writes) default to "info".
+                    let line = String::from_utf8_lossy(&buf).to_string();
+                    let (level, message) = parse_stderr_log(&line);
+                    let _ = app_handle.emit(
+                        "server-log",
+                        LogPayload {
+                            message,
+                            level: level.into(),
+                        },
+                    );
+                }
+                CommandEvent::Terminated(payload) => {
+                    let (level, message) = match payload.code {
+                        Some(code) => describe_child_exit(code),
+                        None => ("warn", "Node server stopped (no exit code)".to_string()),
+                    };
+                    let _ = app_handle.emit(
+                        "server-log",
+                        LogPayload {
+                            message,
+                            level: level.into(),
+                        },
+                    );
+                    let mut guard = server_state.lock().await;
+                    *guard = None;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    Ok(child)
+}
+
+// --- Task: forward MQTT events from Rust bridge to JS child's stdin ---
+
+const ACL_DENIED: &str = "denied by mqtt.acl";
+
+/// Ошибка моста: в лог на уровне error и модулям — они могут переподписаться
+/// или сообщить пользователю сами.
+fn bridge_error(
+    app: &tauri::AppHandle,
+    operation: &str,
+    topic: Option<String>,
+    reason: String,
+) -> IpcToJs {
+    let target = topic
+        .as_deref()
+        .map(|t| format!(" {t}"))
+        .unwrap_or_default();
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message: format!("MQTT {operation}{target} failed: {reason}"),
+            level: "error".into(),
+        },
+    );
+    IpcToJs::Error {
+        operation: operation.to_string(),
+        topic,
+        reason,
+    }
+}
+
+/// Трей кладётся в managed state в конце setup(), а мост может ответить
+/// раньше — до этого момента подсказку просто не трогаем.
+fn set_tray_tooltip(app: &tauri::AppHandle, text: &str) {
+    if let Some(tray) = app.try_state::<tauri::tray::TrayIcon>() {
+        let _ = tray.set_tooltip(Some(text));
+    }
+}
+
+fn spawn_bridge_to_js_writer(
+    app: tauri::AppHandle,
+    mut event_rx: EventReceiver,
+    connected: Arc<std::sync::atomic::AtomicBool>,
+) {
+    tauri::async_runtime::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            let ipc = match event {
+                MqttEvent::Message {
+                    topic,
+                    payload,
+                    properties,
+                } => {
+                    let traffic = app.state::<TrafficState>();
+                    if traffic.replaying() {
+                        continue;
+                    }
+                    let (payload, encoding) = encode_payload(payload);
+                    let message = IpcToJs::Message {
+                        topic,
+                        payload,
+                        encoding,
+                        properties,
+                    };
+                    traffic.record(Direction::ToNode, &message);
+                    message
+                }
+                MqttEvent::Connected { broker } => {
+                    connected.store(true, std::sync::atomic::Ordering::Relaxed);
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("MQTT connected to {} (Rust bridge)", broker),
+                            level: "info".into(),
+                        },
+                    );
+                    set_tray_tooltip(&app, &format!("windows-mqtt: {broker}"));
+                    IpcToJs::Connected
+                }
+                MqttEvent::Disconnected(reason) => {
+                    connected.store(false, std::sync::atomic::Ordering::Relaxed);
+                    set_tray_tooltip(&app, "windows-mqtt: disconnected");
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("MQTT disconnected: {}", reason),
+                            level: "warn".into(),
+                        },
+                    );
+                    IpcToJs::Disconnected { reason }
+                }
+                MqttEvent::ConnectFailed(reason) => {
+                    // Node already treats the bridge as offline; this is for
+                    // the user, who otherwise sees nothing while it retries.
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("MQTT connection failed: {}", reason),
+                            level: "error".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::SubscribeFailed { topic, reason } => {
+                    bridge_error(&app, "subscribe", Some(topic), reason)
+                }
+                MqttEvent::Error {
+                    operation,
+                    topic,
+                    reason,
+                } => bridge_error(&app, operation, topic, reason),
+                MqttEvent::PublishResult { id, error } => IpcToJs::PublishResult {
+                    id,
+                    ok: error.is_none(),
+                    error,
+                },
+                MqttEvent::Failover { from, to } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("MQTT switching broker: {} -> {}", from, to),
+                            level: "warn".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::Reconnecting { attempt, delay } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!(
+                                "MQTT reconnect attempt {} in {:.1}s",
+                                attempt,
+                                delay.as_secs_f64()
+                            ),
+                            level: "info".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::OutboxDrained(stats) => {
+                    let mut message = format!(
+                        "MQTT outbox: sent {} queued publishes ({} queued during the outage)",
+                        stats.sent, stats.queued
+                    );
+                    if stats.dropped_by_policy > 0 {
+                        message += &format!(", {} dropped by policy", stats.dropped_by_policy);
+                    }
+                    if stats.dropped_overflow > 0 {
+                        message += &format!(
+                            ", {} dropped over the {}-message limit",
+                            stats.dropped_overflow, stats.limit
+                        );
+                    }
+                    if stats.pending > 0 {
+                        message += &format!(", {} still waiting", stats.pending);
+                    }
+                    let level = if stats.dropped_overflow > 0 {
+                        "warn"
+                    } else {
+                        "info"
+                    };
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message,
+                            level: level.into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::OutboxError(e) => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: e,
+                            level: "error".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::ConfigWarning(message) => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message,
+                            level: "warn".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::SessionTakeover { client_id } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!(
+                                "MQTT client id '{client_id}' is in use by another client: \
+                                 the broker keeps closing the connection in its favour. Give \
+                                 each install its own mqtt.client_id ({{hostname}}, {{user}}, \
+                                 {{profile}}). Not reconnecting until Reconnect MQTT in the \
+                                 tray menu or a config change."
+                            ),
+                            level: "error".into(),
+                        },
+                    );
+                    set_tray_tooltip(&app, "windows-mqtt: client id in use elsewhere");
+                    continue;
+                }
+                MqttEvent::AclDenied {
+                    check,
+                    topic,
+                    first,
+                } => {
+                    if first {
+                        let _ = app.emit(
+                            "server-log",
+                            LogPayload {
+                                message: format!(
+                                    "MQTT {} {topic} {ACL_DENIED}; further refusals of this \
+                                     topic are only counted (see get_mqtt_status)",
+                                    check.as_str()
+                                ),
+                                level: "warn".into(),
+                            },
+                        );
+                    }
+                    if check == Check::Inbound {
+                        continue;
+                    }
+                    IpcToJs::Error {
+                        operation: check.as_str().to_string(),
+                        topic: Some(topic),
+                        reason: ACL_DENIED.into(),
+                    }
+                }
+                MqttEvent::Unsigned { topic, reason } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!(
+                                "MQTT rejected {topic} (mqtt.signing): {reason}; further \
+                                 refusals of this topic are only counted (see get_mqtt_status)"
+                            ),
+                            level: "warn".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::Stale { topic, reason } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!(
+                                "MQTT discarded stale command on {topic}: {reason}; further \
+                                 ones on this topic are only counted (see get_mqtt_status)"
+                            ),
+                            level: "warn".into(),
+                        },
+                    );
+                    continue;
+                }
+                MqttEvent::NodeBehind { capacity, overflow } => {
+                    let _ = app.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!(
+                                "Node is not keeping up with MQTT: {} messages waiting, \
+                                 {} from here on (see get_mqtt_status)",
+                                capacity,
+                                match overflow {
+                                    Overflow::Coalesce => "keeping only the latest per topic",
+                                    Overflow::DropOldest => "dropping the oldest",
+                                    Overflow::Block => "holding the event loop back",
+                                }
+                            ),
+                            level: "warn".into(),
+                        },
+                    );
+                    continue;
+                }
+            };
+
+            write_to_child(&app, &ipc).await;
+        }
+    });
+}
+
+/// Строка IPC в stdin Node; без запущенного процесса просто пропадает.
+async fn write_to_child(app: &tauri::AppHandle, ipc: &IpcToJs) {
+    let line = match serde_json::to_string(ipc) {
+        Ok(s) => s + "\n",
+        Err(_) => return,
+    };
+    write_line_to_child(app, &line).await;
+}
+
+/// Публикация Node — в запись трафика, если она идёт. Пишется строка как
+/// есть, вместе с полями, которые мост не разбирает.
+fn record_from_node(app: &tauri::AppHandle, line: &str) {
+    if let Ok(line) = serde_json::from_str::<serde_json::Value>(line) {
+        app.state::<TrafficState>()
+            .record(Direction::FromNode, &line);
+    }
+}
+
+async fn write_line_to_child(app: &tauri::AppHandle, line: &str) {
+    let state = app.state::<ServerState>();
+    let mut guard = state.0.lock().await;
+    if let Some(ref mut child) = *guard {
+        let _ = child.write(line.as_bytes());
+    }
+}
+
+// If MQTT connected before the child existed, the 'connected' IPC line was
+// dropped by spawn_bridge_to_js_writer (no child to write to yet) — replay it
+// to the freshly spawned child so its modules see the connected state.
+fn replay_connected_if_needed(
+    app: &tauri::AppHandle,
+    child: &mut tauri_plugin_shell::process::CommandChild,
+) {
+    let connected = app
+        .state::<MqttConnected>()
+        .0
+        .load(std::sync::atomic::Ordering::Relaxed);
+    if connected {
+        let line = serde_json::to_string(&IpcToJs::Connected)
+            .map(|s| s + "\n")
+            .unwrap_or_default();
+        let _ = child.write(line.as_bytes());
+    }
+}
+
+/// Файл очереди исходящих сообщений: рядом с config.yml в каталоге данных
+/// приложения, чтобы переживал обновления.
+fn outbox_path(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("mqtt-outbox.jsonl"))
+}
+
+/// Перечитывает секцию `mqtt` из config.yml и пересобирает мост: старый
+/// цикл событий закрывается (DISCONNECT, будет-сообщение не уходит), новый
+/// мост получает таблицу подписок, маршруты, кэш и очередь старого. Node при
+/// этом не перезапускается — он видит `disconnected`, затем `connected`.
+///
+/// Если конфиг не читается, мост остаётся со старыми настройками и просто
+/// переподключается.
+async fn reload_mqtt(app: &tauri::AppHandle) {
+    let config =
+        resolve_app_root(app).and_then(|root| read_mqtt_config(&resolve_config_path(app, &root)));
+    match config {
+        Ok(config) => rebuild_bridge(app, config).await,
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!(
+                        "MQTT config error: {e}; reconnecting with the current settings"
+                    ),
+                    level: "error".into(),
+                },
+            );
+            app.state::<BridgeState>().get().disconnect().await;
+        }
+    }
+}
+
+/// Встроенный брокер (`mqtt.embedded`), пока он запущен.
+struct EmbeddedBrokerState(std::sync::Mutex<Option<(EmbeddedSetup, EmbeddedBroker)>>);
+
+/// С чем запущен встроенный брокер: его раздел и то, как он ходит к
+/// `mqtt.host`. Правка остального `mqtt` (ACL, кеш, подписи) его не
+/// перезапускает — локальные клиенты и retained-сообщения остаются.
+#[derive(Debug, PartialEq)]
+struct EmbeddedSetup {
+    embedded: EmbeddedConfig,
+    upstream: BrokerEndpoint,
+    client_id: String,
+    keep_alive: Duration,
+    clean_session: bool,
+    max_inflight: u16,
+    availability: Option<Availability>,
+}
+
+impl EmbeddedSetup {
+    fn new(embedded: EmbeddedConfig, upstream: &MqttConfig) -> Self {
+        Self {
+            embedded,
+            upstream: BrokerEndpoint {
+                host: upstream.host.clone(),
+                port: upstream.port,
+                username: upstream.username.clone(),
+                password: upstream.password.clone(),
+                tls: upstream.tls.clone(),
+                ws_path: upstream.ws_path.clone(),
+            },
+            client_id: upstream.client_id.clone(),
+            keep_alive: upstream.keep_alive,
+            clean_session: upstream.clean_session,
+            max_inflight: upstream.max_inflight,
+            availability: upstream.availability.clone(),
+        }
+    }
+}
+
+/// Запускает встроенный брокер по `config.embedded` и возвращает конфиг, с
+/// которым мост подключается к нему, а не к `mqtt.host`: тот становится
+/// брокером, с которым делятся топиками. Сессия всегда чистая: встроенный
+/// брокер их не хранит. Уже запущенный брокер перезапускается, только если
+/// поменялся `EmbeddedSetup`, — зовите, когда прежний мост уже отключён
+/// (`MqttBridge::retire`). Без `embedded` брокер останавливается, а если он
+/// не запустился, мост идёт прямо к `mqtt.host`: в обоих случаях конфиг
+/// возвращается как есть.
+fn serve_embedded(app: &tauri::AppHandle, config: MqttConfig) -> MqttConfig {
+    let state = app.state::<EmbeddedBrokerState>();
+    let mut running = state.0.lock().unwrap();
+    let Some(embedded) = config.embedded.clone() else {
+        *running = None;
+        return config;
+    };
+    let upstream = MqttConfig {
+        client_id: format!("{}-upstream", config.client_id),
+        embedded: None,
+        ..config.clone()
+    };
+    let setup = EmbeddedSetup::new(embedded.clone(), &upstream);
+    if running.as_ref().map(|(current, _)| current) == Some(&setup) {
+        return local_bridge_config(&embedded, config);
+    }
+    // Сначала освободить порт: новый брокер обычно слушает тот же.
+    *running = None;
+
+    let log_handle = app.clone();
+    let log: mqtt_broker::Log = Arc::new(move |message, level| {
+        let _ = log_handle.emit(
+            "server-log",
+            LogPayload {
+                message,
+                level: level.into(),
+            },
+        );
+    });
+    let mut warnings = Vec::new();
+    let started = mqtt_bridge::mqtt_options(&upstream, &mut warnings)
+        .and_then(|options| EmbeddedBroker::start(&embedded, Some(options), log.clone()));
+    for warning in warnings {
+        log(format!("{}: {warning}", upstream.broker_label()), "warn");
+    }
+    match started {
+        Ok(broker) => {
+            *running = Some((setup, broker));
+            local_bridge_config(&embedded, config)
+        }
+        Err(e) => {
+            log(
+                format!("{e}; connecting to {} directly", config.broker_label()),
+                "error",
+            );
+            MqttConfig {
+                embedded: None,
+                ..config
+            }
+        }
+    }
+}
+
+/// Конфиг моста для встроенного брокера на `embedded.listen`.
+fn local_bridge_config(embedded: &EmbeddedConfig, config: MqttConfig) -> MqttConfig {
+    let host = match embedded.listen.ip() {
+        ip if ip.is_unspecified() => "127.0.0.1".to_string(),
+        ip => ip.to_string(),
+    };
+    MqttConfig {
+        host,
+        port: embedded.listen.port(),
+        username: embedded.username.clone(),
+        password: embedded.password.clone(),
+        clean_session: true,
+        tls: None,
+        ws_path: None,
+        fallbacks: Vec::new(),
+        ..config
+    }
+}
+
+/// Замена моста на собранный по `config` (см. `reload_mqtt`).
+async fn rebuild_bridge(app: &tauri::AppHandle, config: MqttConfig) {
+    let state = app.state::<BridgeState>();
+    let _reloading = state.reloading.lock().await;
+    let old = state.get();
+    // Offline-статус и DISCONNECT должны дойти до брокера, пока он жив:
+    // встроенный может перезапуститься ниже.
+    old.retire().await;
+    let mut config = serve_embedded(app, config);
+    config.outbox.path = outbox_path(app);
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message: format!(
+                "MQTT reconnecting to {}:{} (Rust bridge)...",
+                config.host, config.port
+            ),
+            level: "info".into(),
+        },
+    );
+
+    let (bridge, event_rx) = old.replace(&config).await;
+    *state.bridge.write().unwrap() = Arc::new(bridge);
+
+    let connected = app.state::<MqttConnected>().0.clone();
+    connected.store(false, std::sync::atomic::Ordering::Relaxed);
+    write_to_child(
+        app,
+        &IpcToJs::Disconnected {
+            reason: "reconfiguring".into(),
+        },
+    )
+    .await;
+    spawn_bridge_to_js_writer(app.clone(), event_rx, connected);
+    apply_app_topics(app).await;
+}
+
+fn read_config_value(config_path: &Path) -> Result<serde_yaml::Value, String> {
+    let content = std::fs::read_to_string(config_path)
+        .map_err(|e| format!("Failed to read config: {}", e))?;
+    serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse config: {}", e))
+}
+
+/// Следит за config.yml и применяет сохранения (см. `apply_config_change`).
+fn watch_config(app: &tauri::AppHandle, path: PathBuf) {
+    // Точка отсчёта — то, с чем приложение стартовало. Если на старте файл
+    // не разбирался, первое удачное сохранение применится целиком.
+    let initial = read_config_value(&path).unwrap_or(serde_yaml::Value::Null);
+    let handle = app.clone();
+    let errors = handle.clone();
+    let on_error = move |e: String| {
+        let _ = errors.emit(
+            "server-log",
+            LogPayload {
+                message: format!("Config watcher: {e}"),
+                level: "warn".into(),
+            },
+        );
+    };
+    let watcher = config_watch::watch(
+        &path,
+        move || {
+            let app = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                apply_config_change(&app).await;
+            });
+        },
+        on_error,
+    );
+    match watcher {
+        Ok(watcher) => {
+            app.manage(ConfigWatch {
+                path,
+                last_good: Mutex::new(initial),
+                _watcher: std::sync::Mutex::new(watcher),
+            });
+        }
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Not watching {} for changes: {e}", path.display()),
+                    level: "warn".into(),
+                },
+            );
+        }
+    }
+}
+
+/// Сохранённый config.yml применяется по секциям: `mqtt` пересобирает мост,
+/// `modules` перезапускает Node, `tray` заново ставит горячую клавишу и
+/// интервал. Всё проверяется до того, как что-то применено: при ошибке
+/// разбора она пишется в лог и продолжает работать последний удачный конфиг.
+async fn apply_config_change(app: &tauri::AppHandle) {
+    let Some(watch) = app.try_state::<ConfigWatch>() else {
+        return;
+    };
+    let mut last_good = watch.last_good.lock().await;
+    let parsed = read_config_value(&watch.path).and_then(|config| {
+        let changes = Changes::between(&last_good, &config);
+        let mqtt = if changes.mqtt {
+            Some(mqtt_config_from(&config, &watch.path)?)
+        } else {
+            None
+        };
+        let tray = if changes.tray {
+            Some(read_tray_settings(config.get("tray"))?)
+        } else {
+            None
+        };
+        Ok((config, changes, mqtt, tray))
+    });
+    let (config, changes, mqtt, tray) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!(
+                        "{}: {e}; keeping the last good config",
+                        watch.path.display()
+                    ),
+                    level: "error".into(),
+                },
+            );
+            return;
+        }
+    };
+    if !changes.any() {
+        return;
+    }
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message: format!(
+                "{} changed, applying: {}",
+                watch.path.display(),
+                changes.describe()
+            ),
+            level: "info".into(),
+        },
+    );
+    *last_good = config;
+
+    if let Some(mqtt) = mqtt {
+        rebuild_bridge(app, mqtt).await;
+    }
+    if changes.modules {
+        shutdown_node(app).await;
+        start_node(app).await;
+    }
+    if let Some(tray) = tray {
+        set_hotkey(app, tray.hotkey).await;
+        set_autoplace_interval(app, tray.autoplace_interval).await;
+    }
+    if changes.mqtt || changes.modules {
+        publish_discovery(app).await;
+    }
+}
+
+/// Discovery для Home Assistant (`mqtt.discovery`): retained-конфиги
+/// сущностей этого ПК, а для выключенных модулей — пустые, чтобы HA их убрал.
+/// Зовётся при старте, на `online` от самого HA и после правки config.yml.
+/// Без соединения публикации ложатся в офлайн-очередь.
+async fn publish_discovery(app: &tauri::AppHandle) {
+    let config =
+        resolve_app_root(app).and_then(|root| read_config_value(&resolve_config_path(app, &root)));
+    let Ok(config) = config else {
+        // Ошибку чтения конфига уже показал тот, кто читал его первым.
+        return;
+    };
+    let Some(mqtt) = config.get("mqtt") else {
+        return;
+    };
+    let announced = read_discovery(mqtt).and_then(|prefix| {
+        let Some(prefix) = prefix else {
+            return Ok(None);
+        };
+        let base = mqtt_base(mqtt).ok_or("mqtt.discovery needs mqtt.base")?;
+        let exposed = discovery_exposed(&config, base)?;
+        let device = DeviceInfo {
+            node_id: mqtt_discovery::node_id(base),
+            name: std::env::var("COMPUTERNAME")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .unwrap_or_else(|_| base.to_string()),
+            version: app.package_info().version.to_string(),
+        };
+        let availability = read_availability(mqtt)?;
+        Ok(Some(mqtt_discovery::messages(
+            &prefix,
+            &device,
+            availability.as_ref(),
+            &exposed,
+        )))
+    });
+    let messages = match announced {
+        Ok(Some(messages)) => messages,
+        Ok(None) => return,
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Home Assistant discovery: {e}"),
+                    level: "error".into(),
+                },
+            );
+            return;
+        }
+    };
+    let bridge = app.state::<BridgeState>().get();
+    for (topic, payload) in messages {
+        bridge
+            .publish(&topic, payload, true, QoS::AtLeastOnce, None, None)
+            .await;
+    }
+}
+
+/// `<prefix>/status` = `online` — Home Assistant перезапустился и ждёт
+/// конфиги заново.
+async fn route_discovery_refresh(app: &tauri::AppHandle, bridge: &MqttBridge, topic: &str) {
+    let handler_app = app.clone();
+    let handler = Arc::new(move |_topic: &str, payload: &[u8]| {
+        if payload == b"online" {
+            let app = handler_app.clone();
+            tauri::async_runtime::spawn(async move {
+                publish_discovery(&app).await;
+            });
+        }
+    });
+    if let Err(e) = bridge.route(topic, QoS::AtLeastOnce, handler).await {
+        let _ = app.emit(
+            "server-log",
+            LogPayload {
+                message: format!("MQTT route {topic}: {e}"),
+                level: "error".into(),
+            },
+        );
+    }
+}
+
+/// Записи трафика лежат рядом с офлайн-очередью, в `captures/`.
+fn captures_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("captures"))
+}
+
+/// Включает или выключает запись IPC-трафика (пункт трея, `--capture`).
+fn set_capture(app: &tauri::AppHandle, on: bool) {
+    let traffic = app.state::<TrafficState>();
+    let mut capture = traffic.capture.lock().unwrap();
+    let (message, level) = match (capture.take(), on) {
+        (Some(running), true) => {
+            let path = running.path().display().to_string();
+            *capture = Some(running);
+            (format!("Already capturing IPC traffic to {path}"), "info")
+        }
+        (None, true) => match captures_dir(app)
+            .ok_or_else(|| "no app data directory".to_string())
+            .and_then(|dir| Capture::create(&dir))
+        {
+            Ok(started) => {
+                let message = format!("Capturing IPC traffic to {}", started.path().display());
+                *capture = Some(started);
+                (message, "info")
+            }
+            Err(e) => (format!("Capture not started: {e}"), "error"),
+        },
+        (Some(stopped), false) => {
+            let (written, failed) = stopped.counts();
+            let mut message = format!(
+                "Capture stopped: {written} lines in {}",
+                stopped.path().display()
+            );
+            if failed > 0 {
+                message += &format!(", {failed} lost to write errors");
+            }
+            (message, if failed > 0 { "warn" } else { "info" })
+        }
+        (None, false) => return,
+    };
+    if let Some(item) = app.try_state::<CaptureMenuItem>() {
+        let _ = item.0.set_checked(capture.is_some());
+    }
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message,
+            level: level.into(),
+        },
+    );
+}
+
+/// Подпись скорости для лога: 1 — как было, 0 — подряд без пауз.
+fn replay_speed_label(speed: f64) -> String {
+    if speed <= 0.0 {
+        "as fast as possible".into()
+    } else if speed == 1.0 {
+        "original timing".into()
+    } else {
+        format!("{speed}x speed")
+    }
+}
+
+/// Скармливает запись Node (см. `TrafficState`). Идущее воспроизведение
+/// сначала останавливается. Записанные строки идут в Node мимо `mqtt.acl`,
+/// `mqtt.stale_commands` и `mqtt.signing`, поэтому при включённой подписи
+/// воспроизведение не запускается вовсе: запись подписанной команды
+/// выполнилась бы заново без всякой проверки.
+fn start_replay(app: &tauri::AppHandle, path: &Path, speed: f64) {
+    stop_replay(app);
+    let signing = app
+        .try_state::<BridgeState>()
+        .is_some_and(|bridge| bridge.get().checks_signatures());
+    if signing {
+        let _ = app.emit(
+            "server-log",
+            LogPayload {
+                message: "Replay not started: mqtt.signing is set, and replayed commands \
+                          would reach Node without a signature check"
+                    .into(),
+                level: "error".into(),
+            },
+        );
+        return;
+    }
+    let plan = match traffic_capture::load(path) {
+        Ok(records) => traffic_capture::schedule(records, speed),
+        Err(e) => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Replay not started: {e}"),
+                    level: "error".into(),
+                },
+            );
+            return;
+        }
+    };
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message: format!(
+                "Replaying {} lines from {} ({}); live MQTT messages and Node's publishes \
+                 are held back until it ends. Replayed lines bypass mqtt.acl and \
+                 mqtt.stale_commands",
+                plan.len(),
+                path.display(),
+                replay_speed_label(speed)
+            ),
+            level: "warn".into(),
+        },
+    );
 
-    // Resolve the config path here so the Node child reads exactly the same
-    // file the Rust side does (single source of truth, no drift).
-    let config_path = resolve_config_path(app, &app_root);
+    let traffic = app.state::<TrafficState>();
+    traffic
+        .replaying
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    let app = app.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        let started = tokio::time::Instant::now();
+        let lines = plan.len();
+        for (at, line) in plan {
+            tokio::time::sleep_until(started + at).await;
+            write_line_to_child(&app, &(line + "\n")).await;
+        }
+        let traffic = app.state::<TrafficState>();
+        if traffic
+            .replaying
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Replay finished: {lines} lines sent to Node"),
+                    level: "info".into(),
+                },
+            );
+        }
+    });
+    *traffic.replay.lock().unwrap() = Some(task);
+}
 
-    let (mut rx, child) = app
-        .shell()
-        .command("node")
-        .args([server_path.to_string_lossy().to_string()])
-        .env("TAURI_BRIDGE", "1")
-        .env("CONFIG", config_path.to_string_lossy().to_string())
-        .current_dir(app_root)
-        .spawn()
-        .map_err(|error| error.to_string())?;
+fn stop_replay(app: &tauri::AppHandle) {
+    let traffic = app.state::<TrafficState>();
+    if let Some(task) = traffic.replay.lock().unwrap().take() {
+        task.abort();
+    }
+    if traffic
+        .replaying
+        .swap(false, std::sync::atomic::Ordering::Relaxed)
+    {
+        let _ = app.emit(
+            "server-log",
+            LogPayload {
+                message: "Replay stopped".into(),
+                level: "info".into(),
+            },
+        );
+    }
+}
 
-    let app_handle = app.clone();
+/// Пункт трея «Replay latest capture»: самая свежая запись из `captures/`.
+fn replay_latest(app: &tauri::AppHandle, speed: f64) {
+    let latest = captures_dir(app).and_then(|dir| traffic_capture::latest(&dir));
+    match latest {
+        Some(path) => start_replay(app, &path, speed),
+        None => {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: "No capture to replay yet: turn on Capture traffic first".into(),
+                    level: "warn".into(),
+                },
+            );
+        }
+    }
+}
 
-    // Task: read stdout from JS, dispatch IPC messages or log
-    tauri::async_runtime::spawn(async move {
-        while let Some(event) = rx.recv().await {
-            match event {
-                CommandEvent::Stdout(buf) => {
-                    let line = String::from_utf8_lossy(&buf).to_string();
-                    let trimmed = line.trim();
-                    if trimmed.is_empty() {
-                        continue;
-                    }
+/// Ключи командной строки для записи и воспроизведения трафика.
+#[derive(Debug, Default, PartialEq)]
+struct TrafficFlags {
+    capture: bool,
+    replay: Option<PathBuf>,
+    /// См. `traffic_capture::schedule`.
+    speed: f64,
+}
 
-                    // Try to parse as IPC JSON
-                    match serde_json::from_str::<IpcFromJs>(trimmed) {
-                        Ok(ipc) => match ipc {
-                            IpcFromJs::Subscribe { topics } => {
-                                bridge.subscribe(&topics).await;
-                            }
-                            IpcFromJs::Unsubscribe { topics } => {
-                                bridge.unsubscribe(&topics).await;
-                            }
-                            IpcFromJs::Publish {
-                                topic,
-                                payload,
-                                options,
-                            } => {
-                                let qos = match options.qos {
-                                    1 => QoS::AtLeastOnce,
-                                    2 => QoS::ExactlyOnce,
-                                    _ => QoS::AtMostOnce,
-                                };
-                                bridge
-                                    .publish(&topic, &payload, options.retain, qos)
-                                    .await;
-                            }
-                        },
-                        Err(_) => {
-                            // Not JSON — treat as log output
-                            let _ = app_handle.emit(
-                                "server-log",
-                                LogPayload {
-                                    message: line,
-                                    level: "info".into(),
-                                },
-                            );
-                        }
-                    }
-                }
-                CommandEvent::Stderr(buf) => {
-                    // In bridge mode the child redirects ALL console output to
-                    // stderr with a "[level] " tag; untagged lines (crash
-                    // traces, direct stderr writes) default to "info".
-                    let line = String::from_utf8_lossy(&buf).to_string();
-                    let (level, message) = parse_stderr_log(&line);
-                    let _ = app_handle.emit(
-                        "server-log",
-                        LogPayload {
-                            message,
-                            level: level.into(),
-                        },
-                    );
-                }
-                CommandEvent::Terminated(payload) => {
-                    let (level, message) = match payload.code {
-                        Some(code) => describe_child_exit(code),
-                        None => ("warn", "Node server stopped (no exit code)".to_string()),
-                    };
-                    let _ = app_handle.emit(
-                        "server-log",
-                        LogPayload {
-                            message,
-                            level: level.into(),
-                        },
-                    );
-                    let mut guard = server_state.lock().await;
-                    *guard = None;
-                    break;
-                }
-                _ => {}
+// `--capture` records from startup; `--replay <file>` feeds a capture to
+// Node once it is up, at `--replay-speed <x>` (1 by default, 0 = no pauses,
+// otherwise at least 0.01). Anything else on the command line is left alone.
+fn read_traffic_flags(args: impl IntoIterator<Item = String>) -> Result<TrafficFlags, String> {
+    let mut flags = TrafficFlags {
+        speed: 1.0,
+        ..TrafficFlags::default()
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--capture" => flags.capture = true,
+            "--replay" => {
+                let path = args.next().ok_or("--replay needs a capture file")?;
+                flags.replay = Some(PathBuf::from(path));
             }
+            "--replay-speed" => {
+                flags.speed = args
+                    .next()
+                    .and_then(|speed| speed.parse::<f64>().ok())
+                    .filter(|speed| {
+                        speed.is_finite() && (*speed == 0.0 || *speed >= traffic_capture::MIN_SPEED)
+                    })
+                    .ok_or(
+                        "--replay-speed needs 0 (no pauses) or a number from 0.01 \
+                         (1 as recorded)",
+                    )?;
+            }
+            _ => {}
         }
-    });
-
-    Ok(child)
+    }
+    Ok(flags)
 }
 
-// --- Task: forward MQTT events from Rust bridge to JS child's stdin ---
-
-fn spawn_bridge_to_js_writer(
-    app: tauri::AppHandle,
-    mut event_rx: tokio::sync::mpsc::Receiver<MqttEvent>,
-    connected: Arc<std::sync::atomic::AtomicBool>,
-) {
-    tauri::async_runtime::spawn(async move {
-        while let Some(event) = event_rx.recv().await {
-            let ipc = match event {
-                MqttEvent::Message { topic, payload } => IpcToJs::Message { topic, payload },
-                MqttEvent::Connected => {
-                    connected.store(true, std::sync::atomic::Ordering::Relaxed);
-                    let _ = app.emit(
-                        "server-log",
-                        LogPayload {
-                            message: "MQTT connected (Rust bridge)".into(),
-                            level: "info".into(),
-                        },
-                    );
-                    IpcToJs::Connected
-                }
-                MqttEvent::Disconnected(reason) => {
-                    connected.store(false, std::sync::atomic::Ordering::Relaxed);
-                    let _ = app.emit(
-                        "server-log",
-                        LogPayload {
-                            message: format!("MQTT disconnected: {}", reason),
-                            level: "warn".into(),
-                        },
-                    );
-                    IpcToJs::Disconnected { reason }
-                }
-            };
+/// `--sign`: сообщение для топика из `mqtt.signing`, чтобы скрипты могли
+/// отправлять команды, которые мост пропустит.
+#[derive(Debug, PartialEq)]
+struct SignFlags {
+    topic: String,
+    payload: String,
+    config: Option<PathBuf>,
+}
 
-            let line = match serde_json::to_string(&ipc) {
-                Ok(s) => s + "\n",
-                Err(_) => continue,
-            };
+// `--sign <topic> <payload>` prints the signed message to publish on `topic`
+// and exits instead of starting the app. The secret comes from
+// `--config <file>`, or the config.yml the app itself would find.
+fn read_sign_flags(args: impl IntoIterator<Item = String>) -> Result<Option<SignFlags>, String> {
+    let mut sign = None;
+    let mut config = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sign" => {
+                let topic = args.next().ok_or("--sign needs a topic and a payload")?;
+                let payload = args.next().ok_or("--sign needs a topic and a payload")?;
+                sign = Some((topic, payload));
+            }
+            "--config" => {
+                let path = args.next().ok_or("--config needs a config.yml")?;
+                config = Some(PathBuf::from(path));
+            }
+            _ => {}
+        }
+    }
+    Ok(sign.map(|(topic, payload)| SignFlags {
+        topic,
+        payload,
+        config,
+    }))
+}
 
-            let state = app.state::<ServerState>();
-            let mut guard = state.0.lock().await;
-            if let Some(ref mut child) = *guard {
-                let _ = child.write(line.as_bytes());
+/// Подписывает `--sign` секретом из config.yml. Без Tauri: ищет конфиг
+/// там же, где `config_candidates`, кроме каталога ресурсов.
+fn signed_message(flags: &SignFlags) -> Result<String, String> {
+    let config_path = match &flags.config {
+        Some(path) => path.clone(),
+        None => {
+            let mut candidates = Vec::new();
+            if let Ok(path) = std::env::var("CONFIG") {
+                candidates.push(PathBuf::from(path));
             }
+            candidates.push(PathBuf::from("data").join("config.yml"));
+            if let Ok(appdata) = std::env::var("APPDATA") {
+                candidates.push(
+                    PathBuf::from(appdata)
+                        .join("windows-mqtt")
+                        .join("config.yml"),
+                );
+            }
+            candidates.push(PathBuf::from("config.yml"));
+            candidates
+                .into_iter()
+                .find(|path| path.exists())
+                .ok_or("No config.yml found; pass --config <file>")?
         }
-    });
+    };
+    let content = std::fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read {}: {e}", config_path.display()))?;
+    let config: serde_yaml::Value =
+        serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse config: {e}"))?;
+    let signing = config
+        .get("mqtt")
+        .map(read_signing)
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| format!("{} has no mqtt.signing section", config_path.display()))?;
+    if !mqtt_signing::Verifier::new(signing.clone()).covers(&flags.topic) {
+        eprintln!(
+            "warning: {} is not in mqtt.signing.topics; the bridge passes it on unchecked",
+            flags.topic
+        );
+    }
+    Ok(mqtt_signing::sign(
+        &signing.secret,
+        &flags.topic,
+        &flags.payload,
+        mqtt_signing::unix_now(),
+    ))
 }
 
-// If MQTT connected before the child existed, the 'connected' IPC line was
-// dropped by spawn_bridge_to_js_writer (no child to write to yet) — replay it
-// to the freshly spawned child so its modules see the connected state.
-fn replay_connected_if_needed(
-    app: &tauri::AppHandle,
-    child: &mut tauri_plugin_shell::process::CommandChild,
-) {
-    let connected = app
-        .state::<MqttConnected>()
-        .0
-        .load(std::sync::atomic::Ordering::Relaxed);
-    if connected {
-        let line = serde_json::to_string(&IpcToJs::Connected)
-            .map(|s| s + "\n")
-            .unwrap_or_default();
-        let _ = child.write(line.as_bytes());
+/// `--replay` при старте: Node должен успеть подняться и подписать
+/// модули, иначе первые строки записи уйдут в пустоту.
+async fn replay_when_node_is_up(app: tauri::AppHandle, path: PathBuf, speed: f64) {
+    for _ in 0..150 {
+        if app.state::<ServerState>().0.lock().await.is_some() {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            start_replay(&app, &path, speed);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
     }
+    let _ = app.emit(
+        "server-log",
+        LogPayload {
+            message: format!("Replay of {} skipped: Node did not start", path.display()),
+            level: "error".into(),
+        },
+    );
 }
 
 // --- Tauri commands ---
@@ -423,14 +2660,51 @@ async fn start_mqtt_server(
         return Ok(());
     }
 
-    let bridge = app.state::<BridgeState>();
-    let mut child = spawn_node_server(&app, state.0.clone(), bridge.0.clone())?;
+    let mut child = spawn_node_server(&app, state.0.clone())?;
     replay_connected_if_needed(&app, &mut child);
     *child_guard = Some(child);
 
     Ok(())
 }
 
+/// Таблица подписок моста: какие топики держит брокер и какие модули их
+/// просили. Для отладки из devtools: `invoke('get_mqtt_subscriptions')`.
+#[tauri::command]
+async fn get_mqtt_subscriptions(
+    bridge: State<'_, BridgeState>,
+) -> Result<Vec<SubscriptionInfo>, String> {
+    Ok(bridge.get().subscriptions().await)
+}
+
+/// Состояние моста для диагностики: соединение, с какого момента,
+/// переподключения, последняя ошибка, трафик и самые активные топики.
+#[tauri::command]
+async fn get_mqtt_status(bridge: State<'_, BridgeState>) -> Result<BridgeStatus, String> {
+    Ok(bridge.get().status())
+}
+
+/// Последнее значение топика из кэша моста (retained или, при
+/// `mqtt.cache: all`, любое): `invoke('get_topic_value', { topic })`.
+#[tauri::command]
+async fn get_topic_value(
+    topic: String,
+    bridge: State<'_, BridgeState>,
+) -> Result<Option<TopicValue>, String> {
+    Ok(bridge
+        .get()
+        .topic_value(&topic)
+        .map(|value| TopicValue::new(&topic, value)))
+}
+
+/// Топики в кэше моста, по алфавиту; `filter` — MQTT-фильтр с `+`/`#`.
+#[tauri::command]
+async fn list_topics(
+    filter: Option<String>,
+    bridge: State<'_, BridgeState>,
+) -> Result<Vec<String>, String> {
+    Ok(bridge.get().cached_topics(filter.as_deref()))
+}
+
 /// Версия приложения для UI (окно About, заголовок главного окна).
 ///
 /// Источник — `tauri.conf.json`, тот же, что даёт имя инсталлятору, поэтому
@@ -468,7 +2742,10 @@ fn read_enabled_modules(config_path: &PathBuf) -> Result<Vec<String>, String> {
 
     let config: serde_yaml::Value = serde_yaml::from_str(&content)
         .map_err(|error| format!("Failed to parse config: {}", error))?;
+    enabled_modules(&config)
+}
 
+fn enabled_modules(config: &serde_yaml::Value) -> Result<Vec<String>, String> {
     let modules = config
         .get("modules")
         .and_then(|value| value.as_mapping())
@@ -594,6 +2871,58 @@ const INTERVAL_OPTIONS: &[(&str, u64)] = &[
     ("300s", 300),
 ];
 
+/// Секция `tray`: то же, что выбирается в Settings трея, — индексы в
+/// `HOTKEY_OPTIONS` и `INTERVAL_OPTIONS`.
+#[derive(Debug, Default, PartialEq)]
+struct TraySettings {
+    hotkey: usize,
+    autoplace_interval: usize,
+}
+
+fn read_tray_settings(section: Option<&serde_yaml::Value>) -> Result<TraySettings, String> {
+    let Some(section) = section else {
+        return Ok(TraySettings::default());
+    };
+    let hotkey = match section.get("hotkey") {
+        None => 0,
+        Some(v) => v
+            .as_str()
+            .map(str::trim)
+            .and_then(|name| {
+                HOTKEY_OPTIONS.iter().position(|(label, shortcut)| {
+                    label.eq_ignore_ascii_case(name) || shortcut.eq_ignore_ascii_case(name)
+                })
+            })
+            .ok_or_else(|| {
+                let names: Vec<_> = HOTKEY_OPTIONS
+                    .iter()
+                    .map(|(label, _)| label.to_lowercase())
+                    .collect();
+                format!("tray.hotkey must be one of: {}", names.join(", "))
+            })?,
+    };
+    let autoplace_interval = match section.get("autoplace_interval") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|secs| INTERVAL_OPTIONS.iter().position(|(_, s)| *s == secs))
+            .ok_or_else(|| {
+                let secs: Vec<_> = INTERVAL_OPTIONS
+                    .iter()
+                    .map(|(_, s)| s.to_string())
+                    .collect();
+                format!(
+                    "tray.autoplace_interval must be one of: {} (seconds)",
+                    secs.join(", ")
+                )
+            })?,
+    };
+    Ok(TraySettings {
+        hotkey,
+        autoplace_interval,
+    })
+}
+
 /// Время сборки, вшитое `build.rs`. Ноль значит релизную сборку (а не полночь
 /// 1970-го): там штамп не нужен, версии достаточно.
 fn build_time() -> Option<NaiveDateTime> {
@@ -620,11 +2949,14 @@ fn version_item_label(version: &str, built: Option<NaiveDateTime>, today: NaiveD
 
 fn build_tray_menu(
     app: &tauri::AppHandle,
+    settings: &TraySettings,
+    capturing: bool,
 ) -> Result<
     (
         Menu<tauri::Wry>,
         Vec<CheckMenuItem<tauri::Wry>>,
         Vec<CheckMenuItem<tauri::Wry>>,
+        CheckMenuItem<tauri::Wry>,
     ),
     String,
 > {
@@ -723,10 +3055,55 @@ fn build_tray_menu(
     menu.append(&PredefinedMenuItem::separator(app).map_err(m)?)
         .map_err(m)?;
 
+    // Traffic submenu: capture IPC traffic and replay it into Node
+    let capture = CheckMenuItem::with_id(
+        app,
+        "capture",
+        "Capture traffic",
+        true,
+        capturing,
+        None::<&str>,
+    )
+    .map_err(m)?;
+    let replay = MenuItem::with_id(
+        app,
+        "replay_latest",
+        "Replay latest capture",
+        true,
+        None::<&str>,
+    )
+    .map_err(m)?;
+    let replay_fast = MenuItem::with_id(
+        app,
+        "replay_latest_fast",
+        "Replay latest capture (no pauses)",
+        true,
+        None::<&str>,
+    )
+    .map_err(m)?;
+    let replay_stop =
+        MenuItem::with_id(app, "replay_stop", "Stop replay", true, None::<&str>).map_err(m)?;
+    let traffic_submenu = Submenu::with_id_and_items(
+        app,
+        "traffic",
+        "Traffic",
+        true,
+        &[
+            &capture as &dyn tauri::menu::IsMenuItem<tauri::Wry>,
+            &replay as &dyn tauri::menu::IsMenuItem<tauri::Wry>,
+            &replay_fast as &dyn tauri::menu::IsMenuItem<tauri::Wry>,
+            &replay_stop as &dyn tauri::menu::IsMenuItem<tauri::Wry>,
+        ],
+    )
+    .map_err(m)?;
+    menu.append(&traffic_submenu).map_err(m)?;
+    menu.append(&PredefinedMenuItem::separator(app).map_err(m)?)
+        .map_err(m)?;
+
     // Settings submenu — Hotkey
     let mut hotkey_items: Vec<CheckMenuItem<tauri::Wry>> = Vec::new();
     for (i, (label, _shortcut_str)) in HOTKEY_OPTIONS.iter().enumerate() {
-        let checked = i == 0;
+        let checked = i == settings.hotkey;
         let item = CheckMenuItem::with_id(
             app,
             format!("hotkey_{}", i),
@@ -755,7 +3132,7 @@ fn build_tray_menu(
     // Settings submenu — Interval
     let mut interval_items: Vec<CheckMenuItem<tauri::Wry>> = Vec::new();
     for (i, (label, _secs)) in INTERVAL_OPTIONS.iter().enumerate() {
-        let checked = i == 0;
+        let checked = i == settings.autoplace_interval;
         let item = CheckMenuItem::with_id(
             app,
             format!("interval_{}", i),
@@ -806,7 +3183,7 @@ fn build_tray_menu(
     let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>).map_err(m)?;
     menu.append(&quit).map_err(m)?;
 
-    Ok((menu, hotkey_items, interval_items))
+    Ok((menu, hotkey_items, interval_items, capture))
 }
 
 fn register_shortcut(app: &tauri::AppHandle, shortcut_str: &str) -> Result<(), String> {
@@ -903,23 +3280,93 @@ fn register_shortcut_with_retry(
     });
 }
 
+/// Горячая клавиша раскладки — пункт `idx` из `HOTKEY_OPTIONS`: галочка в
+/// меню трея и перерегистрация. Из меню и из `tray.hotkey` в config.yml.
+async fn set_hotkey(app: &tauri::AppHandle, idx: usize) {
+    for (i, item) in app.state::<HotkeyMenuItems>().0.iter().enumerate() {
+        let _ = item.set_checked(i == idx);
+    }
+    let current = app.state::<CurrentShortcut>();
+    let mut guard = current.0.lock().await;
+    if let Some(ref old) = *guard {
+        unregister_shortcut(app, old);
+    }
+    let new_shortcut = HOTKEY_OPTIONS[idx].1.to_string();
+    if !new_shortcut.is_empty() {
+        if let Err(e) = register_shortcut(app, &new_shortcut) {
+            let _ = app.emit(
+                "server-log",
+                LogPayload {
+                    message: format!("Failed to register hotkey: {}", e),
+                    level: "error".into(),
+                },
+            );
+        }
+    }
+    *guard = Some(new_shortcut);
+}
+
+/// Период авто-раскладки — пункт `idx` из `INTERVAL_OPTIONS`. Из меню и из
+/// `tray.autoplace_interval` в config.yml.
+async fn set_autoplace_interval(app: &tauri::AppHandle, idx: usize) {
+    for (i, item) in app.state::<IntervalMenuItems>().0.iter().enumerate() {
+        let _ = item.set_checked(i == idx);
+    }
+    let secs = INTERVAL_OPTIONS[idx].1;
+    let timer_state = app.state::<AutoplaceTimer>();
+    let mut guard = timer_state.0.lock().await;
+    if let Some(handle) = guard.take() {
+        handle.abort();
+    }
+    if secs > 0 {
+        let app_for_timer = app.clone();
+        let duration = Duration::from_secs(secs);
+        let handle = tauri::async_runtime::spawn(async move {
+            loop {
+                tokio::time::sleep(duration).await;
+                send_command(&app_for_timer, "windows/autoplace").await;
+            }
+        });
+        *guard = Some(handle);
+    }
+}
 
 // --- Main ---
 
 fn main() {
+    // `--sign` is a command-line helper, not another tray instance.
+    match read_sign_flags(std::env::args().skip(1)) {
+        Ok(None) => {}
+        Ok(Some(flags)) => match signed_message(&flags) {
+            Ok(message) => {
+                println!("{message}");
+                return;
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    }
+
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_global_shortcut::Builder::new().build())
         .manage(ServerState::default())
         .manage(AutoplaceTimer(Mutex::new(None)))
-        .manage(CurrentShortcut(Mutex::new(Some(
-            "ctrl+alt+shift+p".to_string(),
-        ))))
         .invoke_handler(tauri::generate_handler![
             start_mqtt_server,
             get_enabled_modules,
             get_app_version,
+            get_mqtt_subscriptions,
+            get_mqtt_status,
+            get_topic_value,
+            list_topics,
             open_external_url
         ])
         .on_window_event(|window, event| match event {
@@ -972,61 +3419,123 @@ fn main() {
                         port: 1883,
                         username: None,
                         password: None,
-                        client_id: "windows-mqtt-unconfigured".into(),
+                        // Random tail: every unconfigured install would
+                        // otherwise share one id and kick the others off.
+                        client_id: format!("windows-mqtt-unconfigured-{:08x}", fastrand::u32(..)),
+                        tls: None,
+                        ws_path: None,
+                        availability: None,
+                        outbox: OutboxConfig::default(),
+                        keep_alive: Duration::from_secs(30),
+                        clean_session: false,
+                        max_inflight: 100,
+                        reconnect: Backoff::default(),
+                        fallbacks: Vec::new(),
+                        failover: Failover::default(),
+                        cache: CachePolicy::default(),
+                        node_queue: ChannelConfig::default(),
+                        embedded: None,
+                        acl: Acl::default(),
+                        signing: None,
+                        freshness: None,
+                        protocol: Protocol::V311,
                     }
                 });
+            app.manage(EmbeddedBrokerState(std::sync::Mutex::new(None)));
+            let mut mqtt_config = serve_embedded(&app_handle, mqtt_config);
+            mqtt_config.outbox.path = outbox_path(&app_handle);
 
             let (bridge, event_rx) = MqttBridge::new(&mqtt_config);
             let bridge = Arc::new(bridge);
-            app.manage(BridgeState(bridge.clone()));
+            app.manage(BridgeState::new(bridge.clone()));
 
             // Forward MQTT events to JS child
             let connected = Arc::new(std::sync::atomic::AtomicBool::new(false));
             app.manage(MqttConnected(connected.clone()));
+            app.manage(TrafficState::default());
             spawn_bridge_to_js_writer(app_handle.clone(), event_rx, connected.clone());
 
-            // Start the Node server immediately — do not depend on the hidden
-            // webview invoking start_mqtt_server.
-            let autostart_handle = app_handle.clone();
-            tauri::async_runtime::spawn(async move {
-                let state = autostart_handle.state::<ServerState>();
-                let mut guard = state.0.lock().await;
-                if guard.is_none() {
-                    let bridge = autostart_handle.state::<BridgeState>().0.clone();
-                    match spawn_node_server(&autostart_handle, state.0.clone(), bridge) {
-                        Ok(mut child) => {
-                            replay_connected_if_needed(&autostart_handle, &mut child);
-                            *guard = Some(child);
-                        }
-                        Err(e) => {
-                            let _ = autostart_handle.emit(
-                                "server-log",
-                                LogPayload {
-                                    message: format!("Failed to start Node server: {e}"),
-                                    level: "error".into(),
-                                },
-                            );
-                        }
-                    }
-                }
+            // Topics under mqtt.base served by Rust itself (native routes
+            // work while Node is down, the bridge status), then Home
+            // Assistant discovery. Re-applied after every bridge rebuild.
+            app.manage(AppTopicsState::default());
+            let topics_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                apply_app_topics(&topics_handle).await;
+                publish_discovery(&topics_handle).await;
+            });
+
+            // Start the Node server immediately — do not depend on the hidden
+            // webview invoking start_mqtt_server.
+            let autostart_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                start_node(&autostart_handle).await;
             });
 
-            let (menu, hotkey_items, interval_items) =
-                build_tray_menu(&app_handle).expect("failed to build tray menu");
+            let tray_settings = app_root_result
+                .clone()
+                .and_then(|root| read_config_value(&resolve_config_path(&app_handle, &root)))
+                .and_then(|config| read_tray_settings(config.get("tray")))
+                .unwrap_or_else(|e| {
+                    let _ = app_handle.emit(
+                        "server-log",
+                        LogPayload {
+                            message: format!("Tray settings: {e}"),
+                            level: "warn".into(),
+                        },
+                    );
+                    TraySettings::default()
+                });
+            let traffic_flags = read_traffic_flags(std::env::args().skip(1)).unwrap_or_else(|e| {
+                let _ = app_handle.emit(
+                    "server-log",
+                    LogPayload {
+                        message: format!("Command line: {e}"),
+                        level: "error".into(),
+                    },
+                );
+                TrafficFlags::default()
+            });
+            let (menu, hotkey_items, interval_items, capture_item) =
+                build_tray_menu(&app_handle, &tray_settings, traffic_flags.capture)
+                    .expect("failed to build tray menu");
 
             // Store menu items for later toggling
             app.manage(HotkeyMenuItems(hotkey_items));
             app.manage(IntervalMenuItems(interval_items));
+            app.manage(CaptureMenuItem(capture_item));
+            if traffic_flags.capture {
+                set_capture(&app_handle, true);
+            }
+            if let Some(path) = traffic_flags.replay {
+                tauri::async_runtime::spawn(replay_when_node_is_up(
+                    app_handle.clone(),
+                    path,
+                    traffic_flags.speed,
+                ));
+            }
 
-            // Register default (autoplace) hotkey with the same retry
-            // treatment as the picker hotkey above — see
-            // register_shortcut_with_retry's doc comment for why.
+            // Register the autoplace hotkey with the same retry treatment as
+            // the picker hotkey above — see register_shortcut_with_retry's
+            // doc comment for why.
+            let shortcut = HOTKEY_OPTIONS[tray_settings.hotkey].1.to_string();
+            app.manage(CurrentShortcut(Mutex::new(Some(shortcut.clone()))));
             register_shortcut_with_retry(
                 app_handle.clone(),
-                "ctrl+alt+shift+p".to_string(),
+                shortcut,
                 ShortcutAction::Autoplace,
                 "autoplace",
             );
+            if tray_settings.autoplace_interval != 0 {
+                let interval_handle = app_handle.clone();
+                let idx = tray_settings.autoplace_interval;
+                tauri::async_runtime::spawn(async move {
+                    set_autoplace_interval(&interval_handle, idx).await;
+                });
+            }
+            if let Ok(root) = &app_root_result {
+                watch_config(&app_handle, resolve_config_path(&app_handle, root));
+            }
 
             let mut tray_builder = TrayIconBuilder::new()
                 .menu(&menu)
@@ -1069,6 +3578,9 @@ fn main() {
                             let app_handle = app.clone();
                             tauri::async_runtime::spawn(async move {
                                 shutdown_node(&app_handle).await;
+                                // After Node: its onStop handlers may still
+                                // publish through the bridge.
+                                app_handle.state::<BridgeState>().get().shutdown().await;
                                 app_handle.exit(0);
                             });
                         }
@@ -1085,54 +3597,32 @@ fn main() {
                             }
                         }
                         "reconnect" => {
-                            // Reconnect Rust MQTT bridge (no need to restart Node)
+                            // Rebuild the Rust MQTT bridge from config.yml
+                            // (no need to restart Node)
                             let app_handle = app.clone();
                             tauri::async_runtime::spawn(async move {
-                                let bridge_state = app_handle.state::<BridgeState>();
-                                bridge_state.0.disconnect().await;
-                                let _ = app_handle.emit(
-                                    "server-log",
-                                    LogPayload {
-                                        message: "MQTT reconnecting (Rust bridge)...".into(),
-                                        level: "info".into(),
-                                    },
-                                );
-                                // rumqttc will auto-reconnect after disconnect
+                                reload_mqtt(&app_handle).await;
                             });
                         }
+                        "capture" => {
+                            let capturing = app
+                                .state::<TrafficState>()
+                                .capture
+                                .lock()
+                                .unwrap()
+                                .is_some();
+                            set_capture(app, !capturing);
+                        }
+                        "replay_latest" => replay_latest(app, 1.0),
+                        "replay_latest_fast" => replay_latest(app, 0.0),
+                        "replay_stop" => stop_replay(app),
                         _ => {
                             // Hotkey selection
                             if let Some(idx_str) = id.strip_prefix("hotkey_") {
                                 if let Ok(idx) = idx_str.parse::<usize>() {
-                                    let hotkey_items = app.state::<HotkeyMenuItems>();
-                                    for (i, item) in hotkey_items.0.iter().enumerate() {
-                                        let _ = item.set_checked(i == idx);
-                                    }
                                     let app_handle = app.clone();
                                     tauri::async_runtime::spawn(async move {
-                                        let current = app_handle.state::<CurrentShortcut>();
-                                        let mut guard = current.0.lock().await;
-                                        if let Some(ref old) = *guard {
-                                            unregister_shortcut(&app_handle, old);
-                                        }
-                                        let new_shortcut = HOTKEY_OPTIONS[idx].1.to_string();
-                                        if !new_shortcut.is_empty() {
-                                            if let Err(e) =
-                                                register_shortcut(&app_handle, &new_shortcut)
-                                            {
-                                                let _ = app_handle.emit(
-                                                    "server-log",
-                                                    LogPayload {
-                                                        message: format!(
-                                                            "Failed to register hotkey: {}",
-                                                            e
-                                                        ),
-                                                        level: "error".into(),
-                                                    },
-                                                );
-                                            }
-                                        }
-                                        *guard = Some(new_shortcut);
+                                        set_hotkey(&app_handle, idx).await;
                                     });
                                 }
                             }
@@ -1140,35 +3630,9 @@ fn main() {
                             // Interval selection
                             if let Some(idx_str) = id.strip_prefix("interval_") {
                                 if let Ok(idx) = idx_str.parse::<usize>() {
-                                    let interval_items = app.state::<IntervalMenuItems>();
-                                    for (i, item) in interval_items.0.iter().enumerate() {
-                                        let _ = item.set_checked(i == idx);
-                                    }
-                                    let secs = INTERVAL_OPTIONS[idx].1;
                                     let app_handle = app.clone();
                                     tauri::async_runtime::spawn(async move {
-                                        let timer_state =
-                                            app_handle.state::<AutoplaceTimer>();
-                                        let mut guard = timer_state.0.lock().await;
-                                        if let Some(handle) = guard.take() {
-                                            handle.abort();
-                                        }
-                                        if secs > 0 {
-                                            let app_for_timer = app_handle.clone();
-                                            let duration = Duration::from_secs(secs);
-                                            let handle =
-                                                tauri::async_runtime::spawn(async move {
-                                                    loop {
-                                                        tokio::time::sleep(duration).await;
-                                                        send_command(
-                                                            &app_for_timer,
-                                                            "windows/autoplace",
-                                                        )
-                                                        .await;
-                                                    }
-                                                });
-                                            *guard = Some(handle);
-                                        }
+                                        set_autoplace_interval(&app_handle, idx).await;
                                     });
                                 }
                             }
@@ -1209,8 +3673,19 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
-    use super::{describe_child_exit, find_app_root, version_item_label};
+    use super::{
+        app_wiring, decode_payload, describe_child_exit, discovery_exposed, encode_payload,
+        expand_client_id, find_app_root, mqtt_config_from, parse_broker_host, profile_id, read_acl,
+        read_app_topics, read_availability, read_backoff, read_broker, read_cache_policy,
+        read_discovery, read_embedded, read_failover, read_node_queue, read_outbox_config,
+        read_protocol, read_sign_flags, read_signing, read_stale_commands, read_tls_config,
+        read_traffic_flags, read_tray_settings, version_item_label, Acl, AppWiring, BrokerHost,
+        CachePolicy, CachedValue, EmbeddedSetup, IpcFromJs, IpcToJs, MessageProperties, Overflow,
+        PayloadEncoding, Protocol, SignFlags, SubscribeTopic, TopicValue, TraySettings,
+    };
     use chrono::NaiveDate;
+    use rumqttc::QoS;
+    use std::path::{Path, PathBuf};
 
     fn dt(y: i32, m: u32, d: u32, h: u32, min: u32) -> chrono::NaiveDateTime {
         NaiveDate::from_ymd_opt(y, m, d)
@@ -1239,7 +3714,6 @@ mod tests {
         assert_eq!(version_item_label("1.1.0", None, today), "v1.1.0");
     }
 
-
     #[test]
     fn reports_access_violation_as_a_native_crash_error() {
         // 0xC0000005 arrives as a negative i32 from the OS.
@@ -1292,4 +3766,818 @@ mod tests {
         let missing = std::env::temp_dir().join("wmqtt-approot-none");
         assert_eq!(find_app_root(&[missing]), None);
     }
+
+    fn tls_from(yaml: &str) -> Result<Option<super::TlsConfig>, String> {
+        let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        read_tls_config(value.get("tls"), Path::new("/cfg"))
+    }
+
+    #[test]
+    fn tls_is_off_unless_configured() {
+        assert_eq!(tls_from("host: x"), Ok(None));
+        assert_eq!(tls_from("tls: false"), Ok(None));
+    }
+
+    #[test]
+    fn tls_true_uses_the_system_store() {
+        assert_eq!(tls_from("tls: true"), Ok(Some(Default::default())));
+    }
+
+    #[test]
+    fn tls_paths_resolve_against_the_config_dir() {
+        let yaml = "tls:
+  ca: certs/ca.crt
+  cert: /abs/client.crt
+  key: certs/client.key
+  cert_name: mqtt.home
+";
+        let tls = tls_from(yaml).unwrap().unwrap();
+        assert_eq!(tls.ca_file.as_deref(), Some(Path::new("/cfg/certs/ca.crt")));
+        assert_eq!(
+            tls.client_cert.as_deref(),
+            Some(Path::new("/abs/client.crt"))
+        );
+        assert_eq!(
+            tls.client_key.as_deref(),
+            Some(Path::new("/cfg/certs/client.key"))
+        );
+        assert_eq!(tls.cert_name.as_deref(), Some("mqtt.home"));
+        assert!(!tls.insecure);
+        let legacy = tls_from("tls: { server_name: mqtt.home }")
+            .unwrap()
+            .unwrap();
+        assert_eq!(legacy.cert_name.as_deref(), Some("mqtt.home"));
+        assert!(legacy.legacy_server_name);
+        assert!(!tls.legacy_server_name);
+    }
+
+    #[test]
+    fn tls_rejects_a_scalar() {
+        assert!(tls_from("tls: yes please").is_err());
+    }
+
+    #[test]
+    fn text_payloads_stay_readable() {
+        let (payload, encoding) = encode_payload("22.5 °C".as_bytes().to_vec());
+        assert_eq!(payload, "22.5 °C");
+        assert_eq!(encoding, PayloadEncoding::Utf8);
+    }
+
+    #[test]
+    fn binary_payloads_survive_the_round_trip() {
+        let png_header = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0xFF];
+        let (payload, encoding) = encode_payload(png_header.clone());
+        assert_eq!(encoding, PayloadEncoding::Base64);
+        assert_eq!(decode_payload(payload, encoding), Ok(png_header));
+    }
+
+    #[test]
+    fn bad_base64_is_an_error_not_garbage() {
+        assert!(decode_payload("not base64!".into(), PayloadEncoding::Base64).is_err());
+    }
+
+    #[test]
+    fn message_line_carries_its_encoding() {
+        let line = serde_json::to_string(&IpcToJs::Message {
+            topic: "t".into(),
+            payload: "AAE=".into(),
+            encoding: PayloadEncoding::Base64,
+            properties: None,
+        })
+        .unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"message","topic":"t","payload":"AAE=","encoding":"base64"}"#
+        );
+    }
+
+    #[test]
+    fn errors_reach_node_with_their_topic() {
+        let line = serde_json::to_string(&IpcToJs::Error {
+            operation: "subscribe".into(),
+            topic: Some("pc/cmd".into()),
+            reason: "denied".into(),
+        })
+        .unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"error","operation":"subscribe","topic":"pc/cmd","reason":"denied"}"#
+        );
+    }
+
+    #[test]
+    fn publish_ids_round_trip() {
+        let ipc: IpcFromJs = serde_json::from_str(
+            r#"{"type":"publish","topic":"t","payload":"on","options":{"qos":1},"id":"7"}"#,
+        )
+        .unwrap();
+        let IpcFromJs::Publish { id, .. } = ipc else {
+            panic!("expected publish");
+        };
+        assert_eq!(id.as_deref(), Some("7"));
+
+        let line = serde_json::to_string(&IpcToJs::PublishResult {
+            id: "7".into(),
+            ok: true,
+            error: None,
+        })
+        .unwrap();
+        assert_eq!(line, r#"{"type":"publishResult","id":"7","ok":true}"#);
+    }
+
+    #[test]
+    fn message_properties_cross_ipc() {
+        let ipc: IpcFromJs = serde_json::from_str(
+            r#"{"type":"publish","topic":"t","payload":"on","options":{"qos":1,"properties":{
+                "responseTopic":"pc/reply","correlationData":"AAE=","userProperties":[["a","b"]]
+            }}}"#,
+        )
+        .unwrap();
+        let IpcFromJs::Publish { options, .. } = ipc else {
+            panic!("expected publish");
+        };
+        let properties = options.properties.unwrap();
+        assert_eq!(properties.response_topic.as_deref(), Some("pc/reply"));
+        assert_eq!(properties.correlation_data, Some(vec![0, 1]));
+
+        let line = serde_json::to_string(&IpcToJs::Message {
+            topic: "t".into(),
+            payload: "on".into(),
+            encoding: PayloadEncoding::Utf8,
+            properties: Some(MessageProperties {
+                content_type: Some("text/plain".into()),
+                message_expiry_interval: Some(30),
+                ..Default::default()
+            }),
+        })
+        .unwrap();
+        assert_eq!(
+            line,
+            concat!(
+                r#"{"type":"message","topic":"t","payload":"on","encoding":"utf8","#,
+                r#""properties":{"contentType":"text/plain","messageExpiryInterval":30}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn publish_without_encoding_is_utf8() {
+        // Older children never send the field.
+        let ipc: IpcFromJs =
+            serde_json::from_str(r#"{"type":"publish","topic":"t","payload":"on"}"#).unwrap();
+        let IpcFromJs::Publish { encoding, .. } = ipc else {
+            panic!("expected publish");
+        };
+        assert_eq!(encoding, PayloadEncoding::Utf8);
+    }
+
+    #[test]
+    fn subscribe_accepts_bare_filters_and_per_topic_qos() {
+        let ipc: IpcFromJs = serde_json::from_str(
+            r#"{"type":"subscribe","topics":["a/b",{"topic":"pc/cmd","qos":1},{"topic":"c"}]}"#,
+        )
+        .unwrap();
+        let IpcFromJs::Subscribe { topics, owner } = ipc else {
+            panic!("expected subscribe");
+        };
+        assert_eq!(owner, "node");
+        let pairs: Vec<_> = topics.into_iter().map(SubscribeTopic::into_pair).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a/b".to_string(), QoS::AtMostOnce),
+                ("pc/cmd".to_string(), QoS::AtLeastOnce),
+                ("c".to_string(), QoS::AtMostOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe_name_their_owner() {
+        let ipc: IpcFromJs =
+            serde_json::from_str(r#"{"type":"unsubscribe","topics":["a"],"owner":"exec"}"#)
+                .unwrap();
+        let IpcFromJs::Unsubscribe { topics, owner } = ipc else {
+            panic!("expected unsubscribe");
+        };
+        assert_eq!((topics, owner.as_str()), (vec!["a".to_string()], "exec"));
+    }
+
+    #[test]
+    fn queue_policies_come_from_mqtt_queue() {
+        use crate::mqtt_outbox::QueuePolicy;
+        let yaml = "queue:
+  max_messages: 50
+  policy: drop
+  topics:
+    home/pc/sysstats/#: keep-latest
+    home/pc/dirwatch/#: keep-all
+";
+        let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        let outbox = read_outbox_config(&value).unwrap();
+        assert_eq!(outbox.max_messages, 50);
+        assert_eq!(outbox.default_policy, QueuePolicy::Drop);
+        assert_eq!(
+            outbox.policies,
+            vec![
+                ("home/pc/sysstats/#".to_string(), QueuePolicy::KeepLatest),
+                ("home/pc/dirwatch/#".to_string(), QueuePolicy::KeepAll),
+            ]
+        );
+
+        let value: serde_yaml::Value =
+            serde_yaml::from_str("queue:\n  topics:\n    a/#: keep-some").unwrap();
+        let err = read_outbox_config(&value).unwrap_err();
+        assert!(err.contains("mqtt.queue.topics.a/#"), "{err}");
+
+        for yaml in ["queue: { topics: [a/#] }", "queue: { max_messages: 0 }"] {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            assert!(read_outbox_config(&value).is_err(), "{yaml}");
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff_is_read_in_seconds() {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str("min_delay: 0.5\nmax_delay: 600\njitter: 0").unwrap();
+        let backoff = read_backoff(Some(&value)).unwrap();
+        assert_eq!(backoff.min_delay, std::time::Duration::from_millis(500));
+        assert_eq!(backoff.max_delay, std::time::Duration::from_secs(600));
+        assert_eq!(backoff.jitter, 0.0);
+
+        let value: serde_yaml::Value = serde_yaml::from_str("min_delay: 60\nmax_delay: 5").unwrap();
+        assert!(read_backoff(Some(&value)).is_err());
+        let value: serde_yaml::Value = serde_yaml::from_str("jitter: 2").unwrap();
+        assert!(read_backoff(Some(&value)).is_err());
+    }
+
+    #[test]
+    fn app_topics_need_a_base() {
+        let mqtt: serde_yaml::Value = serde_yaml::from_str("host: nas").unwrap();
+        assert_eq!(read_app_topics(&mqtt), Ok(None));
+
+        let mqtt: serde_yaml::Value = serde_yaml::from_str("base: home/room/pc/").unwrap();
+        let topics = read_app_topics(&mqtt).unwrap().unwrap();
+        assert_eq!(topics.base, "home/room/pc");
+        assert_eq!(topics.status_interval, std::time::Duration::from_secs(60));
+        assert!(!topics.node_control);
+
+        let mqtt: serde_yaml::Value = serde_yaml::from_str("base: pc\nnode_control: true").unwrap();
+        assert!(read_app_topics(&mqtt).unwrap().unwrap().node_control);
+        let mqtt: serde_yaml::Value = serde_yaml::from_str("base: pc\nnode_control: on").unwrap();
+        assert!(read_app_topics(&mqtt).is_err());
+
+        let mqtt: serde_yaml::Value =
+            serde_yaml::from_str("base: pc\nstatus_interval: off").unwrap();
+        assert!(read_app_topics(&mqtt).is_err());
+    }
+
+    #[test]
+    fn app_wiring_follows_base_and_discovery() {
+        let wiring = |yaml: &str| {
+            let mqtt: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            app_wiring(&mqtt)
+        };
+        assert_eq!(wiring("host: nas"), Ok(AppWiring::default()));
+
+        let yaml = "base: pc\nnode_control: true\nstatus_interval: 30\ndiscovery: true";
+        let all = wiring(yaml).unwrap();
+        assert_eq!(all.node_commands.as_deref(), Some("pc/app/node"));
+        assert_eq!(
+            all.discovery_refresh.as_deref(),
+            Some("homeassistant/status")
+        );
+        assert_eq!(
+            all.status,
+            Some((
+                "pc/app/bridge".to_string(),
+                std::time::Duration::from_secs(30)
+            ))
+        );
+
+        let quiet = wiring("base: other/pc\nstatus_interval: 0").unwrap();
+        assert_eq!(quiet, AppWiring::default());
+        assert!(wiring("base: pc\ndiscovery: 1").is_err());
+    }
+
+    #[test]
+    fn cache_mode_defaults_to_retained() {
+        assert_eq!(read_cache_policy(None), Ok(CachePolicy::Retained));
+        let off: serde_yaml::Value = serde_yaml::from_str("false").unwrap();
+        assert_eq!(read_cache_policy(Some(&off)), Ok(CachePolicy::Off));
+        let all: serde_yaml::Value = serde_yaml::from_str("all").unwrap();
+        assert_eq!(read_cache_policy(Some(&all)), Ok(CachePolicy::All));
+        let bad: serde_yaml::Value = serde_yaml::from_str("7").unwrap();
+        assert!(read_cache_policy(Some(&bad)).is_err());
+    }
+
+    #[test]
+    fn client_id_placeholders() {
+        let vars = [
+            ("hostname", Some("DESKTOP-1".to_string())),
+            ("user", Some("stas".to_string())),
+            ("profile", None),
+        ];
+        assert_eq!(
+            expand_client_id("windows-mqtt-{hostname}-{user}", &vars),
+            Ok("windows-mqtt-DESKTOP-1-stas".to_string())
+        );
+        assert_eq!(expand_client_id("fixed", &vars), Ok("fixed".to_string()));
+
+        // A value the environment doesn't have is random, not shared.
+        let id = expand_client_id("pc-{profile}", &vars).unwrap();
+        assert_eq!(id.len(), "pc-".len() + 8);
+        assert_ne!(id, expand_client_id("pc-{profile}", &vars).unwrap());
+
+        assert!(expand_client_id("pc-{host}", &vars).is_err());
+        assert!(expand_client_id("pc-{hostname", &vars).is_err());
+        assert!(expand_client_id("", &vars).is_err());
+    }
+
+    #[test]
+    fn profile_id_is_stable_per_directory() {
+        let id = |dir: &str| profile_id(std::path::Path::new(dir));
+        let installed = id("C:\\Users\\stas\\AppData\\Roaming\\windows-mqtt");
+        assert_eq!(installed.len(), 8);
+        assert_eq!(
+            installed,
+            id("c:\\users\\stas\\appdata\\roaming\\windows-mqtt")
+        );
+        assert_ne!(installed, id("D:\\portable\\data"));
+    }
+
+    #[test]
+    fn node_queue_blocks_for_half_the_keepalive_at_most() {
+        let mqtt: serde_yaml::Value = serde_yaml::from_str("host: nas").unwrap();
+        let config = read_node_queue(&mqtt, std::time::Duration::from_secs(30)).unwrap();
+        assert_eq!(config.capacity, 512);
+        assert_eq!(config.overflow, Overflow::Coalesce);
+        assert_eq!(config.block_limit, Some(std::time::Duration::from_secs(15)));
+        assert_eq!(
+            read_node_queue(&mqtt, std::time::Duration::ZERO)
+                .unwrap()
+                .block_limit,
+            None
+        );
+
+        let mqtt: serde_yaml::Value =
+            serde_yaml::from_str("node_queue:\n  max_messages: 64\n  overflow: block").unwrap();
+        let config = read_node_queue(&mqtt, std::time::Duration::from_secs(30)).unwrap();
+        assert_eq!((config.capacity, config.overflow), (64, Overflow::Block));
+        let mqtt: serde_yaml::Value =
+            serde_yaml::from_str("node_queue: { overflow: drop-newest }").unwrap();
+        assert!(read_node_queue(&mqtt, std::time::Duration::from_secs(30)).is_err());
+    }
+
+    #[test]
+    fn tray_settings_pick_menu_entries() {
+        assert_eq!(read_tray_settings(None), Ok(TraySettings::default()));
+        let tray: serde_yaml::Value =
+            serde_yaml::from_str("hotkey: Ctrl+Alt+P\nautoplace_interval: 120").unwrap();
+        assert_eq!(
+            read_tray_settings(Some(&tray)),
+            Ok(TraySettings {
+                hotkey: 2,
+                autoplace_interval: 3,
+            })
+        );
+        let none: serde_yaml::Value = serde_yaml::from_str("hotkey: none").unwrap();
+        assert_eq!(read_tray_settings(Some(&none)).unwrap().hotkey, 3);
+        let bad: serde_yaml::Value = serde_yaml::from_str("autoplace_interval: 45").unwrap();
+        assert!(read_tray_settings(Some(&bad)).is_err());
+        let bad: serde_yaml::Value = serde_yaml::from_str("hotkey: ctrl+q").unwrap();
+        assert!(read_tray_settings(Some(&bad)).is_err());
+    }
+
+    #[test]
+    fn topic_queries_are_answered_by_id() {
+        let ipc: IpcFromJs =
+            serde_json::from_str(r#"{"type":"getTopicValue","id":"3","topic":"pc/status"}"#)
+                .unwrap();
+        assert!(matches!(ipc, IpcFromJs::GetTopicValue { ref id, .. } if id == "3"));
+        let ipc: IpcFromJs = serde_json::from_str(r#"{"type":"listTopics","id":"4"}"#).unwrap();
+        assert!(matches!(ipc, IpcFromJs::ListTopics { filter: None, .. }));
+
+        let value = TopicValue::new(
+            "pc/status",
+            CachedValue {
+                payload: b"online".to_vec(),
+                retained: true,
+                broker_copy: true,
+                received: std::time::SystemTime::now(),
+            },
+        );
+        let line = serde_json::to_value(IpcToJs::TopicValue {
+            id: "3".into(),
+            value: Some(value),
+        })
+        .unwrap();
+        assert_eq!(line["type"], "topicValue");
+        assert_eq!(line["value"]["payload"], "online");
+        assert_eq!(line["value"]["retained"], true);
+        assert!(line["value"]["receivedAt"].is_string());
+    }
+
+    #[test]
+    fn fallback_brokers_inherit_what_they_dont_set() {
+        let mqtt: serde_yaml::Value = serde_yaml::from_str(
+            "user: pc
+password: secret
+tls:
+  ca: certs/ca.crt
+",
+        )
+        .unwrap();
+        let dir = Path::new("/etc/wmqtt");
+
+        let entry: serde_yaml::Value = serde_yaml::from_str("mqtts://nas.local").unwrap();
+        let nas = read_broker(&entry, &mqtt, dir).unwrap();
+        assert_eq!((nas.host.as_str(), nas.port), ("nas.local", 8883));
+        assert_eq!(nas.username.as_deref(), Some("pc"));
+        assert_eq!(
+            nas.tls.unwrap().ca_file,
+            Some(Path::new("/etc/wmqtt/certs/ca.crt").to_path_buf())
+        );
+
+        // A plain URL doesn't pick up the shared TLS settings...
+        let entry: serde_yaml::Value = serde_yaml::from_str("mqtt://192.168.1.5").unwrap();
+        assert_eq!(read_broker(&entry, &mqtt, dir).unwrap().tls, None);
+
+        // ...and a mapping overrides the login.
+        let entry: serde_yaml::Value =
+            serde_yaml::from_str("host: cloud.example.com\nport: 1884\nuser: other").unwrap();
+        let cloud = read_broker(&entry, &mqtt, dir).unwrap();
+        assert_eq!(cloud.port, 1884);
+        assert_eq!(cloud.username.as_deref(), Some("other"));
+        assert!(cloud.tls.is_some());
+    }
+
+    #[test]
+    fn failover_settings() {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str("after_failures: 5\nprobe_interval: 0").unwrap();
+        let failover = read_failover(Some(&value)).unwrap();
+        assert_eq!(failover.after_failures, 5);
+        assert!(failover.probe_interval.is_zero());
+
+        let value: serde_yaml::Value = serde_yaml::from_str("after_failures: 0").unwrap();
+        assert!(read_failover(Some(&value)).is_err());
+    }
+
+    fn availability_from(yaml: &str) -> Result<Option<super::Availability>, String> {
+        let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        read_availability(&value)
+    }
+
+    #[test]
+    fn availability_defaults_to_base_status() {
+        let availability = availability_from("base: home/room/pc/").unwrap().unwrap();
+        assert_eq!(availability.topic, "home/room/pc/status");
+        assert_eq!(availability.online, "online");
+        assert_eq!(availability.offline, "offline");
+    }
+
+    #[test]
+    fn availability_can_be_customised_or_disabled() {
+        let yaml = "base: home/pc
+availability:
+  topic: pcs/office/state
+  online: '1'
+  offline: '0'
+";
+        let availability = availability_from(yaml).unwrap().unwrap();
+        assert_eq!(availability.topic, "pcs/office/state");
+        assert_eq!(availability.online, "1");
+        assert_eq!(availability.offline, "0");
+
+        assert_eq!(
+            availability_from("base: home/pc\navailability: false"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn availability_needs_a_base_or_a_topic() {
+        assert_eq!(availability_from("host: x"), Ok(None));
+    }
+
+    #[test]
+    fn traffic_flags_from_the_command_line() {
+        let flags = |args: &[&str]| read_traffic_flags(args.iter().map(|a| a.to_string()));
+        let none = flags(&[]).unwrap();
+        assert!(!none.capture);
+        assert_eq!(none.replay, None);
+        assert_eq!(none.speed, 1.0);
+
+        let replay = flags(&[
+            "--capture",
+            "--replay",
+            "capture-1.jsonl",
+            "--replay-speed",
+            "0",
+        ])
+        .unwrap();
+        assert!(replay.capture);
+        assert_eq!(replay.replay.as_deref(), Some(Path::new("capture-1.jsonl")));
+        assert_eq!(replay.speed, 0.0);
+
+        assert!(flags(&["--replay"]).is_err());
+        assert!(flags(&["--replay-speed", "fast"]).is_err());
+        assert!(flags(&["--replay-speed", "-2"]).is_err());
+        assert!(flags(&["--replay-speed", "1e-300"]).is_err());
+        assert_eq!(flags(&["--replay-speed", "0.01"]).unwrap().speed, 0.01);
+    }
+
+    #[test]
+    fn embedded_broker_listens_locally_by_default() {
+        let embedded = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_embedded(&value)
+        };
+        assert_eq!(embedded("host: nas"), Ok(None));
+        let local = embedded("embedded: true").unwrap().unwrap();
+        assert_eq!(local.listen, "127.0.0.1:1883".parse().unwrap());
+        assert!(!local.bridges());
+
+        let yaml = "embedded:
+  listen: 1884
+  upstream:
+    out: ['home/room/pc/#']
+    in: ['home/hall/+/set']
+";
+        let shared = embedded(yaml).unwrap().unwrap();
+        assert_eq!(shared.listen, "127.0.0.1:1884".parse().unwrap());
+        assert_eq!(shared.outgoing, vec!["home/room/pc/#".to_string()]);
+        assert_eq!(shared.incoming, vec!["home/hall/+/set".to_string()]);
+        // Reachable from the LAN only with a login.
+        assert!(embedded("embedded: { listen: '0.0.0.0:1883' }").is_err());
+        let yaml = "embedded: { listen: '0.0.0.0:1883', user: pc, password: secret }";
+        let open = embedded(yaml).unwrap().unwrap();
+        assert_eq!(open.listen, "0.0.0.0:1883".parse().unwrap());
+        assert_eq!(open.username.as_deref(), Some("pc"));
+        assert_eq!(open.password.as_deref(), Some("secret"));
+        assert!(embedded("embedded: { user: pc }").is_err());
+
+        assert!(embedded("embedded: { listen: 0 }").is_err());
+        assert!(embedded("embedded: { upstream: { out: ['home/#/pc'] } }").is_err());
+        assert!(embedded("embedded: yes please").is_err());
+    }
+
+    #[test]
+    fn embedded_broker_ignores_unrelated_mqtt_changes() {
+        let setup = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            let config = mqtt_config_from(&value, Path::new("/cfg/config.yml")).unwrap();
+            EmbeddedSetup::new(config.embedded.clone().unwrap(), &config)
+        };
+        let base = "mqtt:\n  host: nas\n  client_id: pc\n  embedded: true\n";
+        let acl = format!("{base}  acl: {{ inbound: {{ deny: ['pc/exec/#'] }} }}\n");
+        assert_eq!(setup(base), setup(&acl));
+        assert_ne!(setup(base), setup(&base.replace("nas", "nas2")));
+        let port = base.replace("embedded: true", "embedded: { listen: 1884 }");
+        assert_ne!(setup(base), setup(&port));
+    }
+
+    #[test]
+    fn acl_rules_from_config() {
+        let acl = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_acl(&value)
+        };
+        assert_eq!(acl("host: nas"), Ok(Acl::default()));
+
+        let yaml = "acl:
+  inbound:
+    deny: ['home/room/pc/exec/#']
+  outbound:
+    allow: ['home/room/pc/#', 'home/+/status']
+";
+        let rules = acl(yaml).unwrap();
+        assert_eq!(rules.inbound.allow, None);
+        assert_eq!(rules.inbound.deny, vec!["home/room/pc/exec/#".to_string()]);
+        assert_eq!(
+            rules.outbound.allow,
+            Some(vec![
+                "home/room/pc/#".to_string(),
+                "home/+/status".to_string()
+            ])
+        );
+        assert!(rules.outbound.deny.is_empty());
+        // `allow: []` lets nothing through, unlike leaving it out.
+        assert_eq!(
+            acl("acl: { inbound: { allow: [] } }")
+                .unwrap()
+                .inbound
+                .allow,
+            Some(Vec::new())
+        );
+
+        assert!(acl("acl: { outbound: { deny: ['home/#/x'] } }").is_err());
+        assert!(acl("acl: { inbound: { allow: 'home/#' } }").is_err());
+        assert!(acl("acl: yes").is_err());
+    }
+
+    #[test]
+    fn signing_needs_a_secret_and_topics() {
+        let signing = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_signing(&value)
+        };
+        assert_eq!(signing("host: nas"), Ok(None));
+        let yaml = "signing:
+  secret: 'k3y'
+  topics: ['home/room/pc/windows/shutdown', 'home/room/pc/exec/#']
+";
+        let config = signing(yaml).unwrap().unwrap();
+        assert_eq!(config.secret, "k3y");
+        assert_eq!(config.topics.len(), 2);
+        assert_eq!(config.max_age, std::time::Duration::from_secs(30));
+        let yaml = "signing: { secret: 'k3y', topics: ['a/#'], max_age: 120 }";
+        assert_eq!(
+            signing(yaml).unwrap().unwrap().max_age,
+            std::time::Duration::from_secs(120)
+        );
+
+        assert!(signing("signing: { topics: ['a/#'] }").is_err());
+        assert!(signing("signing: { secret: '', topics: ['a/#'] }").is_err());
+        assert!(signing("signing: { secret: 'k3y', topics: [] }").is_err());
+        assert!(signing("signing: { secret: 'k3y', topics: ['a/#/b'] }").is_err());
+        assert!(signing("signing: { secret: 'k3y', topics: ['a'], max_age: 0 }").is_err());
+    }
+
+    #[test]
+    fn stale_command_rules() {
+        let stale = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_stale_commands(&value)
+        };
+        assert_eq!(stale("host: nas"), Ok(None));
+        let config = stale("stale_commands: { topics: ['home/room/pc/windows/shutdown'] }")
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.max_age, None);
+        assert_eq!(config.session_grace, std::time::Duration::from_secs(10));
+        let yaml = "stale_commands: { topics: ['a/#'], max_age: 300, session_grace: 0 }";
+        let config = stale(yaml).unwrap().unwrap();
+        assert_eq!(config.max_age, Some(std::time::Duration::from_secs(300)));
+        assert!(config.session_grace.is_zero());
+
+        assert!(stale("stale_commands: { topics: [] }").is_err());
+        assert!(stale("stale_commands: { topics: ['a/#'], max_age: '5m' }").is_err());
+    }
+
+    #[test]
+    fn protocol_from_config() {
+        let secs = std::time::Duration::from_secs;
+        let protocol = |yaml: &str, keep_alive| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_protocol(&value, keep_alive)
+        };
+        assert_eq!(protocol("host: nas", secs(0)), Ok(Protocol::V311));
+        assert_eq!(protocol("protocol: 4", secs(30)), Ok(Protocol::V311));
+        assert_eq!(protocol("protocol: 5", secs(30)), Ok(Protocol::V5));
+        assert!(protocol("protocol: 3", secs(30)).is_err());
+        assert!(protocol("protocol: '5.0'", secs(30)).is_err());
+        // rumqttc's MQTT 5 client has no way to turn keep-alive off.
+        assert!(protocol("protocol: 5", secs(0)).is_err());
+    }
+
+    #[test]
+    fn sign_flags_from_the_command_line() {
+        let flags = |args: &[&str]| read_sign_flags(args.iter().map(|a| a.to_string()));
+        assert_eq!(flags(&[]), Ok(None));
+        assert_eq!(flags(&["--capture"]), Ok(None));
+        assert_eq!(
+            flags(&["--sign", "home/room/pc/exec/cmd", "notepad"]),
+            Ok(Some(SignFlags {
+                topic: "home/room/pc/exec/cmd".into(),
+                payload: "notepad".into(),
+                config: None,
+            }))
+        );
+        assert_eq!(
+            flags(&["--config", "C:/mqtt/config.yml", "--sign", "a/b", "1"])
+                .unwrap()
+                .unwrap()
+                .config,
+            Some(PathBuf::from("C:/mqtt/config.yml"))
+        );
+        assert!(flags(&["--sign", "a/b"]).is_err());
+    }
+
+    #[test]
+    fn discovery_is_off_unless_asked_for() {
+        let discovery = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_discovery(&value)
+        };
+        assert_eq!(discovery("host: x"), Ok(None));
+        assert_eq!(discovery("discovery: false"), Ok(None));
+        assert_eq!(
+            discovery("discovery: true"),
+            Ok(Some("homeassistant".into()))
+        );
+        assert_eq!(
+            discovery("discovery: { prefix: ha/ }"),
+            Ok(Some("ha".into()))
+        );
+        assert!(discovery("discovery: { prefix: '' }").is_err());
+    }
+
+    #[test]
+    fn discovery_must_be_a_bool_or_a_section() {
+        let discovery = |yaml: &str| {
+            let value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+            read_discovery(&value)
+        };
+        assert!(discovery("discovery: 'yes'").is_err());
+        assert!(discovery("discovery: 1").is_err());
+        assert!(discovery("discovery: [ha]").is_err());
+    }
+
+    #[test]
+    fn discovery_follows_the_module_bases() {
+        let yaml = "modules:
+  windows: { base: pcs/desk/win }
+  power: {}
+  audio:
+    base: pcs/desk/sound
+    mute: { set: /muted/set }
+    device: false
+  exec: { enabled: false }
+";
+        let config: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        let exposed = discovery_exposed(&config, "home/pc").unwrap();
+        assert_eq!(exposed.windows_base, "home/pc/windows");
+        assert_eq!(exposed.power_base.as_deref(), Some("pcs/desk/win"));
+        let audio = exposed.audio.unwrap();
+        assert_eq!(audio.volume_set, "pcs/desk/sound/volume/set");
+        assert_eq!(audio.mute_set, "pcs/desk/sound/muted/set");
+        assert_eq!(audio.mute_stat, "pcs/desk/sound/mute");
+        assert_eq!(audio.devices, None);
+
+        let config: serde_yaml::Value =
+            serde_yaml::from_str("modules:\n  power: { enabled: false }\n  audio: {}").unwrap();
+        let exposed = discovery_exposed(&config, "home/pc").unwrap();
+        assert_eq!(exposed.power_base, None);
+        assert_eq!(
+            exposed.audio.unwrap().devices,
+            Some((
+                "home/pc/audio/device".into(),
+                "home/pc/audio/device/playback".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn bare_host_keeps_the_configured_port() {
+        assert_eq!(
+            parse_broker_host("mqtt.home", 1884),
+            Ok(BrokerHost {
+                host: "mqtt.home".into(),
+                port: 1884,
+                secure: None,
+                ws_path: None,
+            })
+        );
+    }
+
+    #[test]
+    fn url_schemes_pick_transport_and_default_port() {
+        let cases = [
+            ("mqtt://h", 1883, Some(false), None),
+            ("mqtts://h", 8883, Some(true), None),
+            ("ws://h/mqtt", 80, Some(false), Some("/mqtt")),
+            ("wss://h", 443, Some(true), Some("/")),
+        ];
+        for (url, port, secure, path) in cases {
+            let parsed = parse_broker_host(url, 1883).unwrap();
+            assert_eq!(parsed.host, "h", "{url}");
+            assert_eq!(parsed.port, port, "{url}");
+            assert_eq!(parsed.secure, secure, "{url}");
+            assert_eq!(parsed.ws_path.as_deref(), path, "{url}");
+        }
+    }
+
+    #[test]
+    fn url_port_wins_over_mqtt_port() {
+        let parsed = parse_broker_host("wss://proxy.example.com:8443/ws/mqtt", 1883).unwrap();
+        assert_eq!(parsed.port, 8443);
+        assert_eq!(parsed.ws_path.as_deref(), Some("/ws/mqtt"));
+    }
+
+    #[test]
+    fn url_with_ipv6_host() {
+        let parsed = parse_broker_host("mqtt://[fd00::1]:1884", 1883).unwrap();
+        assert_eq!(parsed.host, "fd00::1");
+        assert_eq!(parsed.port, 1884);
+    }
+
+    #[test]
+    fn bad_urls_are_rejected() {
+        assert!(parse_broker_host("http://h", 1883).is_err());
+        assert!(parse_broker_host("mqtt://h/path", 1883).is_err());
+        assert!(parse_broker_host("mqtt://h:notaport", 1883).is_err());
+        assert!(parse_broker_host("ws://:80/", 1883).is_err());
+    }
 }
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long a sent QoS 1/2 publish may wait for PubAck/PubComp before its
+/// caller hears that it failed.
+pub const PUBLISH_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The answer to a publish that carried an id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishOutcome {
+    pub id: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    id: String,
+    deadline: Instant,
+}
+
+/// Ties broker acks back to the publishes that asked for one.
+///
+/// rumqttc picks the packet id inside its event loop and reports it only as
+/// `Outgoing::Publish(pkid)`. Every publish handed to the client is queued
+/// here, in the order it enters the request channel, and takes the next id
+/// reported. After a reconnect rumqttc re-sends unacked publishes with their
+/// old id; those are recognised by the id still being in flight.
+#[derive(Debug, Default)]
+pub struct PublishTracker {
+    queued: VecDeque<Option<String>>,
+    /// QoS 1/2 publishes awaiting an ack. `None` when no one is waiting for
+    /// the answer, kept anyway so a re-send isn't mistaken for a new publish.
+    inflight: HashMap<u16, Option<Tracked>>,
+}
+
+impl PublishTracker {
+    pub fn queued(&mut self, id: Option<String>) {
+        self.queued.push_back(id);
+    }
+
+    /// The request never reached the channel.
+    pub fn not_sent(&mut self) -> Option<String> {
+        self.queued.pop_back().flatten()
+    }
+
+    /// `Outgoing::Publish(pkid)`. QoS 0 publishes (pkid 0) are done once
+    /// written; the rest wait for their ack from here on.
+    pub fn sent(&mut self, pkid: u16, now: Instant) -> Option<PublishOutcome> {
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            return None;
+        }
+        let id = self.queued.pop_front().flatten();
+        if pkid == 0 {
+            return id.map(|id| PublishOutcome { id, error: None });
+        }
+        let tracked = id.map(|id| Tracked {
+            id,
+            deadline: now + PUBLISH_ACK_TIMEOUT,
+        });
+        self.inflight.insert(pkid, tracked);
+        None
+    }
+
+    /// PubAck (QoS 1) or PubComp (QoS 2).
+    pub fn acked(&mut self, pkid: u16) -> Option<PublishOutcome> {
+        let tracked = self.inflight.remove(&pkid).flatten()?;
+        Some(PublishOutcome {
+            id: tracked.id,
+            error: None,
+        })
+    }
+
+    /// The connection is being torn down for good: fail everything still
+    /// waiting, sent or not.
+    pub fn abandon(&mut self, reason: &str) -> Vec<PublishOutcome> {
+        let queued = self.queued.drain(..).flatten();
+        let inflight = self
+            .inflight
+            .drain()
+            .filter_map(|(_, slot)| slot.map(|t| t.id));
+        queued
+            .chain(inflight)
+            .map(|id| PublishOutcome {
+                id,
+                error: Some(reason.to_string()),
+            })
+            .collect()
+    }
+
+    /// Fail everything past its deadline. The packet stays in flight: rumqttc
+    /// will still deliver it, but the caller has been told not to count on it.
+    pub fn expire(&mut self, now: Instant) -> Vec<PublishOutcome> {
+        let mut expired = Vec::new();
+        for slot in self.inflight.values_mut() {
+            if slot.as_ref().is_some_and(|t| t.deadline <= now) {
+                let tracked = slot.take().unwrap();
+                expired.push(PublishOutcome {
+                    id: tracked.id,
+                    error: Some(format!(
+                        "no acknowledgement from the broker within {}s",
+                        PUBLISH_ACK_TIMEOUT.as_secs()
+                    )),
+                });
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(id: &str) -> Option<PublishOutcome> {
+        Some(PublishOutcome {
+            id: id.into(),
+            error: None,
+        })
+    }
+
+    #[test]
+    fn qos0_is_done_when_written() {
+        let mut tracker = PublishTracker::default();
+        tracker.queued(Some("a".into()));
+        tracker.queued(None);
+        assert_eq!(tracker.sent(0, Instant::now()), ok("a"));
+        assert_eq!(tracker.sent(0, Instant::now()), None);
+    }
+
+    #[test]
+    fn qos1_waits_for_its_own_puback() {
+        let mut tracker = PublishTracker::default();
+        let now = Instant::now();
+        tracker.queued(Some("first".into()));
+        tracker.queued(Some("second".into()));
+        assert_eq!(tracker.sent(1, now), None);
+        assert_eq!(tracker.sent(2, now), None);
+        assert_eq!(tracker.acked(2), ok("second"));
+        assert_eq!(tracker.acked(1), ok("first"));
+        assert_eq!(tracker.acked(1), None);
+    }
+
+    #[test]
+    fn resent_publishes_keep_their_caller() {
+        let mut tracker = PublishTracker::default();
+        let now = Instant::now();
+        tracker.queued(Some("cmd-result".into()));
+        tracker.sent(1, now);
+        // Reconnect: rumqttc re-sends pkid 1 before anything new.
+        tracker.queued(Some("next".into()));
+        assert_eq!(tracker.sent(1, now), None);
+        tracker.sent(2, now);
+        assert_eq!(tracker.acked(1), ok("cmd-result"));
+        assert_eq!(tracker.acked(2), ok("next"));
+    }
+
+    #[test]
+    fn unacked_publishes_time_out_once() {
+        let mut tracker = PublishTracker::default();
+        let now = Instant::now();
+        tracker.queued(Some("slow".into()));
+        tracker.sent(3, now);
+        assert!(tracker.expire(now).is_empty());
+
+        let later = now + PUBLISH_ACK_TIMEOUT;
+        let expired = tracker.expire(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, "slow");
+        assert!(expired[0].error.is_some());
+        assert!(tracker.expire(later).is_empty());
+        // A late ack has no one left to tell.
+        assert_eq!(tracker.acked(3), None);
+    }
+
+    #[test]
+    fn abandoning_fails_everything_still_waiting() {
+        let mut tracker = PublishTracker::default();
+        tracker.queued(Some("sent".into()));
+        tracker.queued(None);
+        tracker.sent(1, Instant::now());
+        tracker.sent(2, Instant::now());
+        tracker.queued(Some("queued".into()));
+        let mut ids: Vec<_> = tracker.abandon("gone").into_iter().map(|o| o.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["queued", "sent"]);
+        assert_eq!(tracker.acked(1), None);
+    }
+
+    #[test]
+    fn a_publish_that_never_left_gives_its_id_back() {
+        let mut tracker = PublishTracker::default();
+        tracker.queued(None);
+        tracker.queued(Some("x".into()));
+        assert_eq!(tracker.not_sent().as_deref(), Some("x"));
+        assert_eq!(tracker.sent(0, Instant::now()), None);
+    }
+}
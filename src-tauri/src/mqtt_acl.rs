@@ -0,0 +1,233 @@
+use crate::mqtt_topics::filter_matches;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Distinct topics whose first violation is logged; past this, violations
+/// are only counted. `mqtt_stats::RefusalCounter` uses it too.
+pub const REPORTED_LIMIT: usize = 1000;
+
+/// One direction of `mqtt.acl`: with `allow`, only what it lists passes;
+/// `deny` wins over `allow`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<String>,
+}
+
+impl Rules {
+    pub fn permits_topic(&self, topic: &str) -> bool {
+        let allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|filter| filter_matches(filter, topic)));
+        allowed && !self.deny.iter().any(|filter| filter_matches(filter, topic))
+    }
+
+    /// A subscription passes when `allow` covers everything it can match and
+    /// `deny` doesn't cover all of it. A filter that only overlaps a denied
+    /// one is let through: what it brings in still goes past the inbound
+    /// rules.
+    pub fn permits_filter(&self, filter: &str) -> bool {
+        let allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|outer| covers(outer, filter)));
+        allowed && !self.deny.iter().any(|outer| covers(outer, filter))
+    }
+}
+
+/// Whether every topic `inner` matches is also matched by `outer`. Same
+/// `$` rule as `filter_matches`: a leading wildcard doesn't reach `$SYS/...`.
+fn covers(outer: &str, inner: &str) -> bool {
+    if inner.starts_with('$') && outer.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut outer = outer.split('/');
+    let mut inner = inner.split('/');
+    loop {
+        match (outer.next(), inner.next()) {
+            (Some("#"), _) => return true,
+            (Some(_), Some("#")) => return false,
+            (Some("+"), Some(_)) => {}
+            (Some(_), Some("+")) => return false,
+            (Some(a), Some(b)) if a == b => {}
+            (None, None) => return true,
+            // `a/#` matches `a` itself too.
+            (Some(rest), None) => return rest == "#",
+            _ => return false,
+        }
+    }
+}
+
+/// `mqtt.acl`: what reaches Node, and what Node may publish and subscribe
+/// to. Topics the bridge publishes itself are not restricted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    pub inbound: Rules,
+    pub outbound: Rules,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Check {
+    /// A message from the broker on its way to Node.
+    Inbound,
+    /// A publish from Node.
+    Publish,
+    /// A subscription Node asked for.
+    Subscribe,
+}
+
+impl Check {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Check::Inbound => "inbound",
+            Check::Publish => "publish",
+            Check::Subscribe => "subscribe",
+        }
+    }
+}
+
+/// Refusals so far, for `get_mqtt_status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AclStats {
+    pub inbound: u64,
+    pub publish: u64,
+    pub subscribe: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// `first` is set on the first refusal of this topic in this direction:
+    /// the caller logs those, the rest only show in the counters.
+    Denied {
+        first: bool,
+    },
+}
+
+/// The rules plus their counters.
+#[derive(Debug, Default)]
+pub struct AclGuard {
+    acl: Acl,
+    counts: Mutex<(AclStats, HashSet<(Check, String)>)>,
+}
+
+impl AclGuard {
+    pub fn new(acl: Acl) -> Self {
+        Self {
+            acl,
+            counts: Mutex::default(),
+        }
+    }
+
+    pub fn check(&self, check: Check, topic: &str) -> Verdict {
+        let permitted = match check {
+            Check::Inbound => self.acl.inbound.permits_topic(topic),
+            Check::Publish => self.acl.outbound.permits_topic(topic),
+            Check::Subscribe => self.acl.outbound.permits_filter(topic),
+        };
+        if permitted {
+            return Verdict::Allowed;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        let (stats, reported) = &mut *counts;
+        *match check {
+            Check::Inbound => &mut stats.inbound,
+            Check::Publish => &mut stats.publish,
+            Check::Subscribe => &mut stats.subscribe,
+        } += 1;
+        let first = reported.len() < REPORTED_LIMIT && reported.insert((check, topic.to_string()));
+        Verdict::Denied { first }
+    }
+
+    pub fn stats(&self) -> AclStats {
+        self.counts.lock().unwrap().0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: Option<&[&str]>, deny: &[&str]) -> Rules {
+        let owned = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        Rules {
+            allow: allow.map(owned),
+            deny: owned(deny),
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let rules = rules(Some(&["home/room/pc/#"]), &["home/room/pc/exec/cmd"]);
+        assert!(rules.permits_topic("home/room/pc/audio/volume"));
+        assert!(!rules.permits_topic("home/room/pc/exec/cmd"));
+        assert!(!rules.permits_topic("home/kitchen/light"));
+        assert!(Rules::default().permits_topic("anything/at/all"));
+    }
+
+    #[test]
+    fn subscriptions_must_stay_inside_allow() {
+        let rules = rules(
+            Some(&["home/room/pc/#", "home/+/status"]),
+            &["home/room/pc/exec/#"],
+        );
+        assert!(rules.permits_filter("home/room/pc/#"));
+        assert!(rules.permits_filter("home/room/pc"));
+        assert!(rules.permits_filter("home/hall/status"));
+        assert!(!rules.permits_filter("home/#"));
+        assert!(!rules.permits_filter("home/+/light"));
+        // Entirely denied, or only overlapping a denied filter.
+        assert!(!rules.permits_filter("home/room/pc/exec/+"));
+        assert!(rules.permits_filter("home/room/pc/+/cmd"));
+    }
+
+    #[test]
+    fn wildcard_coverage() {
+        assert!(covers("a/+/c", "a/b/c"));
+        assert!(covers("a/+/c", "a/+/c"));
+        assert!(!covers("a/b/c", "a/+/c"));
+        assert!(covers("a/#", "a/+/#"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("a/b", "a/b/c"));
+        // Matched the way `filter_matches` matches topics.
+        assert!(!covers("#", "$SYS/#"));
+        assert!(covers("$SYS/#", "$SYS/broker/load"));
+        let rules = rules(Some(&["#"]), &[]);
+        assert!(!rules.permits_topic("$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn violations_are_counted_and_reported_once_per_topic() {
+        let guard = AclGuard::new(Acl {
+            inbound: rules(None, &["pc/exec/cmd"]),
+            outbound: rules(Some(&["pc/#"]), &[]),
+        });
+        assert_eq!(guard.check(Check::Inbound, "pc/audio"), Verdict::Allowed);
+        assert_eq!(
+            guard.check(Check::Inbound, "pc/exec/cmd"),
+            Verdict::Denied { first: true }
+        );
+        assert_eq!(
+            guard.check(Check::Inbound, "pc/exec/cmd"),
+            Verdict::Denied { first: false }
+        );
+        assert_eq!(
+            guard.check(Check::Publish, "other/x"),
+            Verdict::Denied { first: true }
+        );
+        assert_eq!(
+            guard.check(Check::Subscribe, "#"),
+            Verdict::Denied { first: true }
+        );
+        assert_eq!(
+            guard.stats(),
+            AclStats {
+                inbound: 2,
+                publish: 1,
+                subscribe: 1
+            }
+        );
+    }
+}
@@ -1,7 +1,27 @@
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use crate::mqtt_acks::{PublishOutcome, PublishTracker};
+use crate::mqtt_acl::{Acl, AclGuard, Check, Verdict};
+use crate::mqtt_broker::EmbeddedConfig;
+use crate::mqtt_cache::{CachePolicy, CachedValue, TopicCache};
+use crate::mqtt_channel::{self, ChannelConfig, Delivery, Droppable, Overflow};
+use crate::mqtt_client::{
+    self, Client, ClientError, ClientOptions, EventLoop, MessageProperties, Polled, Protocol,
+    Received,
+};
+use crate::mqtt_freshness::{FreshnessConfig, FreshnessGuard};
+use crate::mqtt_outbox::{Outbox, OutboxConfig, OutboxStats, QueuedPublish};
+use crate::mqtt_signing::{self, SigningConfig, Verifier};
+use crate::mqtt_stats::{BridgeStats, BridgeStatus, Refusal, RefusalCounter};
+use crate::mqtt_subscriptions::{BrokerChange, SubAckTracker, SubscriptionInfo, SubscriptionTable};
+use crate::mqtt_takeover::TakeoverDetector;
+use crate::mqtt_tls::{self, TlsConfig};
+use crate::mqtt_topics::{Handler, Router};
+use rumqttc::v5::mqttbytes::v5::{ConnectProperties, LastWill as LastWillV5};
+use rumqttc::{v5, LastWill, MqttOptions, QoS, Transport};
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, Mutex, Notify};
 
 #[derive(Clone, Debug)]
 pub struct MqttConfig {
@@ -10,18 +30,519 @@ pub struct MqttConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub client_id: String,
+    pub tls: Option<TlsConfig>,
+    /// Set when `mqtt.host` was a `ws://`/`wss://` URL: the HTTP path of the
+    /// broker's WebSocket endpoint (e.g. `/mqtt`).
+    pub ws_path: Option<String>,
+    pub availability: Option<Availability>,
+    pub outbox: OutboxConfig,
+    /// Zero disables keep-alive pings.
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    /// Outgoing QoS 1/2 publishes awaiting an ack before rumqttc holds back.
+    pub max_inflight: u16,
+    pub reconnect: Backoff,
+    /// Brokers to fall back to, in order, when the one above is unreachable.
+    pub fallbacks: Vec<BrokerEndpoint>,
+    pub failover: Failover,
+    pub cache: CachePolicy,
+    /// Inbound messages held for Node, and what to do with more.
+    pub node_queue: ChannelConfig,
+    /// `mqtt.embedded`: the app runs a broker of its own and the bridge
+    /// connects to that; the broker above becomes its upstream. The app
+    /// starts it before handing the config over, see `mqtt_broker`.
+    pub embedded: Option<EmbeddedConfig>,
+    /// What reaches Node and what Node may publish and subscribe to.
+    pub acl: Acl,
+    /// Topics that only reach Node signed (`mqtt.signing`).
+    pub signing: Option<SigningConfig>,
+    /// Commands dropped when they arrive too late (`mqtt.stale_commands`).
+    pub freshness: Option<FreshnessConfig>,
+    /// MQTT 3.1.1 or 5, for the primary and every fallback.
+    pub protocol: Protocol,
+}
+
+/// Where a fallback broker is and how to log in to it. The primary's are
+/// the matching `MqttConfig` fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrokerEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub ws_path: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failover {
+    /// Failed attempts on one broker before moving to the next.
+    pub after_failures: u32,
+    /// While on a fallback, how often to check whether the primary is back.
+    /// Zero never checks.
+    pub probe_interval: Duration,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Self {
+            after_failures: 3,
+            probe_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Delay before reconnect attempt `n`: `min_delay` doubled per failed
+/// attempt up to `max_delay`, spread by +/- `jitter` so a fleet of PCs
+/// doesn't reconnect in lockstep after a broker restart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay, 0.0..=1.0.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// `attempt` counts from 1; `random` is uniform in 0.0..1.0.
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let base = self
+            .min_delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay);
+        let spread = 1.0 + self.jitter * (2.0 * random - 1.0);
+        base.mul_f64(spread.max(0.0)).min(self.max_delay)
+    }
+}
+
+/// Retained online/offline status of this PC: `online` on every ConnAck,
+/// `offline` as the Last Will and on a clean shutdown.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Availability {
+    pub topic: String,
+    pub online: String,
+    pub offline: String,
+}
+
+impl MqttConfig {
+    /// The broker as the user would recognise it in a log line.
+    pub fn broker_label(&self) -> String {
+        match &self.ws_path {
+            Some(_) => self.ws_url(),
+            None => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /// The primary and then each fallback, each as a complete config.
+    fn broker_configs(&self) -> Vec<MqttConfig> {
+        let mut configs = vec![self.clone()];
+        configs.extend(self.fallbacks.iter().map(|broker| MqttConfig {
+            host: broker.host.clone(),
+            port: broker.port,
+            username: broker.username.clone(),
+            password: broker.password.clone(),
+            tls: broker.tls.clone(),
+            ws_path: broker.ws_path.clone(),
+            fallbacks: Vec::new(),
+            ..self.clone()
+        }));
+        configs
+    }
+
+    fn ws_url(&self) -> String {
+        let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let path = self.ws_path.as_deref().unwrap_or("/");
+        format!("{scheme}://{host}:{}{path}", self.port)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum MqttEvent {
-    Message { topic: String, payload: String },
-    Connected,
+    Message {
+        topic: String,
+        payload: Vec<u8>,
+        /// MQTT 5 only, and only when the sender set any.
+        properties: Option<MessageProperties>,
+    },
+    /// ConnAck from `broker` (its `broker_label`).
+    Connected {
+        broker: String,
+    },
     Disconnected(String),
+    /// A connection attempt failed before ConnAck (refused, TLS handshake,
+    /// unreachable). Only sent when the reason changes, not on every retry.
+    ConnectFailed(String),
+    /// The link is down; attempt `attempt` starts after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Moving to another broker: `from` kept failing, or `to` is the primary
+    /// and answered a probe again.
+    Failover {
+        from: String,
+        to: String,
+    },
+    /// Store-and-forward counters, sent after the outbox was drained.
+    OutboxDrained(OutboxStats),
+    /// The outbox couldn't be read or written; it keeps working in memory.
+    OutboxError(String),
+    /// Something in the config the user should know about that doesn't
+    /// stop the bridge (unparsable CA certificates, skipped).
+    ConfigWarning(String),
+    /// The broker answered a SUBSCRIBE with a failure code (ACL, bad filter).
+    SubscribeFailed {
+        topic: String,
+        reason: String,
+    },
+    /// The answer to a publish that carried an IPC id: acked by the broker
+    /// (or written, for QoS 0) when `error` is `None`.
+    PublishResult {
+        id: String,
+        error: Option<String>,
+    },
+    /// A client call didn't go through. `operation` is `subscribe`,
+    /// `unsubscribe`, `publish` or `disconnect`.
+    Error {
+        operation: &'static str,
+        topic: Option<String>,
+        reason: String,
+    },
+    /// `capacity` messages are waiting for Node and `overflow` has started
+    /// thinning them out. Once per episode: the next one is only sent after
+    /// the queue has been emptied.
+    NodeBehind {
+        capacity: usize,
+        overflow: Overflow,
+    },
+    /// Connections keep being closed right after their ConnAck, or an MQTT 5
+    /// broker said `SessionTakenOver`: another client has the same
+    /// `client_id`. The bridge stops reconnecting until it is rebuilt
+    /// (Reconnect MQTT in the tray, or a config change).
+    SessionTakeover {
+        client_id: String,
+    },
+    /// `mqtt.acl` refused `topic`. Inbound refusals are only sent for the
+    /// first message on a topic; Node's own are all sent, so it hears about
+    /// each one. `first` says whether to log it.
+    AclDenied {
+        check: Check,
+        topic: String,
+        first: bool,
+    },
+    /// A message on a `mqtt.signing` topic without a valid, fresh signature.
+    /// Only the first refusal on a topic is sent, like `AclDenied`; the rest
+    /// are counted in `get_mqtt_status` under `refused`.
+    Unsigned {
+        topic: String,
+        reason: String,
+    },
+    /// A command from `mqtt.stale_commands` that arrived too late to act on.
+    /// Sent and counted like `Unsigned`.
+    Stale {
+        topic: String,
+        reason: String,
+    },
+}
+
+impl Droppable for MqttEvent {
+    fn topic(&self) -> Option<&str> {
+        match self {
+            MqttEvent::Message { topic, .. } => Some(topic),
+            _ => None,
+        }
+    }
+}
+
+pub type EventSender = mqtt_channel::Sender<MqttEvent>;
+pub type EventReceiver = mqtt_channel::Receiver<MqttEvent>;
+type WeakEventSender = mqtt_channel::WeakSender<MqttEvent>;
+
+/// Who holds which filter; replayed at the stored QoS after reconnect.
+type Subscriptions = Arc<Mutex<SubscriptionTable>>;
+type SubAcks = Arc<std::sync::Mutex<SubAckTracker>>;
+type Routes = Arc<std::sync::RwLock<Router>>;
+type Cache = Arc<std::sync::Mutex<TopicCache>>;
+type Stats = Arc<std::sync::Mutex<BridgeStats>>;
+
+/// Subscription owner for the native routes.
+const NATIVE_OWNER: &str = "rust";
+
+/// The one way a PUBLISH enters rumqttc. `order` keeps the tracker's queue
+/// in the same order as the request channel (see `PublishTracker`). The
+/// event loop never takes it, so a sender waiting for room in the channel
+/// can't stall the loop that makes the room.
+struct Publisher {
+    client: Client,
+    order: Mutex<()>,
+    tracker: std::sync::Mutex<PublishTracker>,
+    stats: Stats,
+}
+
+impl Publisher {
+    fn new(client: Client, stats: Stats) -> Self {
+        Self {
+            client,
+            order: Mutex::new(()),
+            tracker: Default::default(),
+            stats,
+        }
+    }
+
+    fn count_sent(&self, topic: &str, bytes: usize) {
+        self.stats
+            .lock()
+            .unwrap()
+            .sent(topic, bytes, Instant::now());
+    }
+
+    async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: Option<&MessageProperties>,
+        id: Option<String>,
+    ) -> Result<(), ClientError> {
+        let _order = self.order.lock().await;
+        self.tracker.lock().unwrap().queued(id);
+        let bytes = payload.len();
+        let result = self
+            .client
+            .publish(topic, qos, retain, payload, properties)
+            .await;
+        match result {
+            Ok(()) => self.count_sent(topic, bytes),
+            Err(_) => {
+                self.tracker.lock().unwrap().not_sent();
+            }
+        }
+        result
+    }
+
+    /// Like `publish`, but fails instead of waiting for room in the channel.
+    async fn try_publish(&self, msg: &QueuedPublish) -> Result<(), ClientError> {
+        let _order = self.order.lock().await;
+        self.tracker.lock().unwrap().queued(msg.id.clone());
+        let result = self.client.try_publish(
+            &msg.topic,
+            qos_from_level(msg.qos),
+            msg.retain,
+            msg.payload.clone(),
+            msg.properties.as_ref(),
+        );
+        match result {
+            Ok(()) => self.count_sent(&msg.topic, msg.payload.len()),
+            Err(_) => {
+                self.tracker.lock().unwrap().not_sent();
+            }
+        }
+        result
+    }
+}
+
+/// What an inbound message goes through before anyone sees it: `mqtt.acl`,
+/// `mqtt.stale_commands`, `mqtt.signing`, then the cache and the native
+/// routes. Node gets the same payload they did, unwrapped when it was signed.
+struct Inbound {
+    acl: Arc<AclGuard>,
+    signing: Option<Verifier>,
+    freshness: Option<std::sync::Mutex<FreshnessGuard>>,
+    /// Signing and freshness refusals, see `RefusalCounter`.
+    refusals: std::sync::Mutex<RefusalCounter>,
+    cache: Cache,
+    routes: Routes,
+}
+
+/// What `Inbound::receive` made of a message.
+#[derive(Debug)]
+enum Admission {
+    /// Passed: the payload for Node.
+    Deliver(Vec<u8>),
+    /// Refused, with the event that reports it when it is reported.
+    Refused(Option<MqttEvent>),
+}
+
+impl Inbound {
+    /// Check a message; if it passes, cache it and run the native routes.
+    fn receive(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        retain: bool,
+        now: Instant,
+        unix_now: i64,
+    ) -> Admission {
+        let topic_owned = || topic.to_string();
+        if let Verdict::Denied { first } = self.acl.check(Check::Inbound, topic) {
+            let check = Check::Inbound;
+            let topic = topic_owned();
+            let event = MqttEvent::AclDenied {
+                check,
+                topic,
+                first,
+            };
+            return Admission::Refused(first.then_some(event));
+        }
+        if let Some(freshness) = &self.freshness {
+            let stale = freshness
+                .lock()
+                .unwrap()
+                .stale(topic, &payload, now, unix_now);
+            if let Some(reason) = stale {
+                let first = self.refused(Refusal::Stale, topic);
+                let topic = topic_owned();
+                return Admission::Refused(first.then_some(MqttEvent::Stale { topic, reason }));
+            }
+        }
+        let payload = match &self.signing {
+            Some(signing) if signing.covers(topic) => {
+                match signing.verify(topic, &payload, unix_now) {
+                    Ok(payload) => payload,
+                    Err(reason) => {
+                        let first = self.refused(Refusal::Unsigned, topic);
+                        let topic = topic_owned();
+                        let event = MqttEvent::Unsigned { topic, reason };
+                        return Admission::Refused(first.then_some(event));
+                    }
+                }
+            }
+            _ => payload,
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .update(topic, &payload, retain, SystemTime::now());
+        // Native routes before Node: they must not wait behind a backed-up
+        // Node child.
+        let handlers = self.routes.read().unwrap().handlers_for(topic);
+        for handler in handlers {
+            handler(topic, &payload);
+        }
+        Admission::Deliver(payload)
+    }
+
+    /// Counts a refusal; `true` when it is the first of its kind on `topic`.
+    fn refused(&self, refusal: Refusal, topic: &str) -> bool {
+        self.refusals.lock().unwrap().count(refusal, topic)
+    }
+
+    /// The cached values under `filter` for a subscriber that won't get the
+    /// broker's retained copies, as `Message` events; and `AclDenied` for
+    /// the first refusal of a topic `mqtt.acl` no longer lets through.
+    /// Signed and `mqtt.stale_commands` topics are never replayed: each of
+    /// their messages is checked once, as it arrives.
+    fn replay(&self, filter: &str) -> Vec<MqttEvent> {
+        let cached = self.cache.lock().unwrap().retained_matching(filter);
+        let mut events = Vec::new();
+        for (topic, payload) in cached {
+            if self
+                .signing
+                .as_ref()
+                .is_some_and(|signing| signing.covers(&topic))
+                || self
+                    .freshness
+                    .as_ref()
+                    .is_some_and(|freshness| freshness.lock().unwrap().covers(&topic))
+            {
+                continue;
+            }
+            if let Verdict::Denied { first } = self.acl.check(Check::Inbound, &topic) {
+                if first {
+                    let check = Check::Inbound;
+                    events.push(MqttEvent::AclDenied {
+                        check,
+                        topic,
+                        first,
+                    });
+                }
+                continue;
+            }
+            let properties = None;
+            events.push(MqttEvent::Message {
+                topic,
+                payload,
+                properties,
+            });
+        }
+        events
+    }
 }
 
 pub struct MqttBridge {
-    client: AsyncClient,
-    subscriptions: Arc<Mutex<HashSet<String>>>,
+    client: Client,
+    subscriptions: Subscriptions,
+    availability: Option<Availability>,
+    /// Fired by the event loop once a DISCONNECT has been written out.
+    disconnect_sent: Arc<Notify>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// Between ConnAck and the next connection error. Publishes go to the
+    /// outbox when this is false, instead of piling up in rumqttc's channel.
+    online: Arc<AtomicBool>,
+    tx: EventSender,
+    sub_acks: SubAcks,
+    publisher: Arc<Publisher>,
+    routes: Routes,
+    cache: Cache,
+    stats: Stats,
+    acl: Arc<AclGuard>,
+    inbound: Arc<Inbound>,
+    /// Ends the event loop for good (see `replace`).
+    stop: Arc<Notify>,
+    event_loop_task: std::sync::Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
+}
+
+/// What outlives a connection setup: handed from a bridge to the one that
+/// replaces it when the configuration changes.
+struct Carried {
+    subscriptions: Subscriptions,
+    routes: Routes,
+    cache: Cache,
+    outbox: Arc<Mutex<Outbox>>,
+}
+
+/// What the event loop task shares with `MqttBridge`.
+struct EventLoopContext {
+    tx: EventSender,
+    subscriptions: Subscriptions,
+    client: Client,
+    /// The primary first, then usable fallbacks.
+    brokers: Vec<BrokerTarget>,
+    failover: Failover,
+    /// How to check on the primary while on a fallback.
+    primary_probe: ClientOptions,
+    availability: Option<Availability>,
+    disconnect_sent: Arc<Notify>,
+    outbox: Arc<Mutex<Outbox>>,
+    online: Arc<AtomicBool>,
+    reconnect: Backoff,
+    sub_acks: SubAcks,
+    publisher: Arc<Publisher>,
+    stats: Stats,
+    inbound: Arc<Inbound>,
+    stop: Arc<Notify>,
+}
+
+struct BrokerTarget {
+    label: String,
+    options: ClientOptions,
 }
 
 /// Decide the MQTT credentials to send.
@@ -36,115 +557,955 @@ fn credentials_for(config: &MqttConfig) -> Option<(&str, &str)> {
         .map(|user| (user, config.password.as_deref().unwrap_or("")))
 }
 
+/// rumqttc caps packets at 10 KiB by default, which a clipboard screenshot
+/// blows through; the broker's own limit is the one that should bite.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// For WebSockets rumqttc takes the whole URL as the broker address and
+/// dials the host and port it parses out of it.
+fn broker_address(config: &MqttConfig) -> String {
+    match config.ws_path {
+        Some(_) => config.ws_url(),
+        None => config.host.clone(),
+    }
+}
+
+fn transport(config: &MqttConfig, warnings: &mut Vec<String>) -> Result<Transport, String> {
+    let tls_config = match &config.tls {
+        Some(tls) => {
+            let (tls_config, tls_warnings) = mqtt_tls::client_config(tls)?;
+            warnings.extend(tls_warnings);
+            Some(tls_config)
+        }
+        None => None,
+    };
+    Ok(match (config.ws_path.is_some(), tls_config) {
+        (false, None) => Transport::tcp(),
+        (false, Some(tls)) => Transport::tls_with_config(tls.into()),
+        (true, None) => Transport::Ws,
+        (true, Some(tls)) => Transport::wss_with_config(tls.into()),
+    })
+}
+
+/// MQTT 3.1.1 options, whatever `config.protocol` says: the embedded
+/// broker's upstream link speaks 3.1.1 too. Setup problems that don't stop
+/// the connection, such as a skipped CA certificate, go to `warnings`.
+pub fn mqtt_options(
+    config: &MqttConfig,
+    warnings: &mut Vec<String>,
+) -> Result<MqttOptions, String> {
+    let mut opts = MqttOptions::new(&config.client_id, broker_address(config), config.port);
+    opts.set_keep_alive(config.keep_alive);
+    opts.set_clean_session(config.clean_session);
+    opts.set_inflight(config.max_inflight);
+    opts.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+
+    if let Some(availability) = &config.availability {
+        opts.set_last_will(LastWill::new(
+            &availability.topic,
+            availability.offline.as_bytes(),
+            QoS::AtLeastOnce,
+            true,
+        ));
+    }
+
+    if let Some((user, pass)) = credentials_for(config) {
+        opts.set_credentials(user, pass);
+    }
+
+    opts.set_transport(transport(config, warnings)?);
+
+    Ok(opts)
+}
+
+fn mqtt5_options(
+    config: &MqttConfig,
+    warnings: &mut Vec<String>,
+) -> Result<v5::MqttOptions, String> {
+    // rumqttc's MQTT 5 client panics below 5 s, and has no way to turn
+    // keep-alive off.
+    if config.keep_alive < Duration::from_secs(5) {
+        return Err("MQTT 5 needs mqtt.keepalive of at least 5 seconds".into());
+    }
+    let mut opts = v5::MqttOptions::new(&config.client_id, broker_address(config), config.port);
+    let mut properties = ConnectProperties::new();
+    properties.max_packet_size = Some(MAX_PACKET_SIZE as u32);
+    // In 3.1.1 a session kept by `clean_session: false` outlives any
+    // disconnect; MQTT 5 ends it on disconnect unless told to keep it.
+    if !config.clean_session {
+        properties.session_expiry_interval = Some(u32::MAX);
+    }
+    opts.set_connect_properties(properties);
+    opts.set_keep_alive(config.keep_alive);
+    opts.set_clean_start(config.clean_session);
+    opts.set_outgoing_inflight_upper_limit(config.max_inflight);
+
+    if let Some(availability) = &config.availability {
+        opts.set_last_will(LastWillV5::new(
+            &availability.topic,
+            availability.offline.as_bytes(),
+            mqtt_client::qos_v5(QoS::AtLeastOnce),
+            true,
+            None,
+        ));
+    }
+
+    if let Some((user, pass)) = credentials_for(config) {
+        opts.set_credentials(user, pass);
+    }
+
+    opts.set_transport(transport(config, warnings)?);
+
+    Ok(opts)
+}
+
+/// Options for the bridge's own client, in `config.protocol`.
+pub fn client_options(
+    config: &MqttConfig,
+    warnings: &mut Vec<String>,
+) -> Result<ClientOptions, String> {
+    match config.protocol {
+        Protocol::V311 => {
+            mqtt_options(config, warnings).map(|opts| ClientOptions::V311(Box::new(opts)))
+        }
+        Protocol::V5 => {
+            mqtt5_options(config, warnings).map(|opts| ClientOptions::V5(Box::new(opts)))
+        }
+    }
+}
+
+/// MQTT QoS level from its wire number; anything unknown means 0.
+pub fn qos_from_level(level: u8) -> QoS {
+    match level {
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+/// Queue `topic` for SubAck tracking and send its SUBSCRIBE. Callers hold
+/// the subscription table lock, see `SubAckTracker`.
+async fn send_subscribe(
+    client: &Client,
+    sub_acks: &SubAcks,
+    topic: &str,
+    qos: QoS,
+) -> Result<(), ClientError> {
+    sub_acks.lock().unwrap().queued(topic);
+    let result = client.subscribe(topic, qos).await;
+    if result.is_err() {
+        sub_acks.lock().unwrap().not_sent();
+    }
+    result
+}
+
+async fn send_outcome(tx: &EventSender, outcome: Option<PublishOutcome>) {
+    if let Some(PublishOutcome { id, error }) = outcome {
+        let _ = tx.send(MqttEvent::PublishResult { id, error }).await;
+    }
+}
+
+/// Turn a failed client call into `MqttEvent::Error`. rumqttc only fails
+/// these when its event loop is gone, which its own message doesn't say.
+async fn report(
+    tx: &EventSender,
+    operation: &'static str,
+    topic: Option<&str>,
+    result: Result<(), ClientError>,
+) {
+    if result.is_ok() {
+        return;
+    }
+    let _ = tx
+        .send(MqttEvent::Error {
+            operation,
+            topic: topic.map(String::from),
+            reason: "the MQTT connection is not running".into(),
+        })
+        .await;
+}
+
+/// One connect/ConnAck round trip, then a clean disconnect.
+async fn broker_reachable(options: ClientOptions) -> bool {
+    let (client, mut event_loop) = Client::new(options, 1);
+    let connected = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match event_loop.poll().await {
+                Ok(Polled::ConnAck { .. }) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    })
+    .await
+    .unwrap_or(false);
+    if connected {
+        let _ = client.try_disconnect();
+        let _ = tokio::time::timeout(Duration::from_secs(1), event_loop.poll()).await;
+    }
+    connected
+}
+
 impl MqttBridge {
-    pub fn new(config: &MqttConfig) -> (Self, mpsc::Receiver<MqttEvent>) {
-        let mut opts = MqttOptions::new(&config.client_id, &config.host, config.port);
-        opts.set_keep_alive(std::time::Duration::from_secs(30));
-        opts.set_clean_session(false);
+    pub fn new(config: &MqttConfig) -> (Self, EventReceiver) {
+        let (outbox, warning) = Outbox::open(config.outbox.clone());
+        let carried = Carried {
+            subscriptions: Subscriptions::default(),
+            routes: Routes::default(),
+            cache: Arc::new(std::sync::Mutex::new(TopicCache::new(config.cache))),
+            outbox: Arc::new(Mutex::new(outbox)),
+        };
+        Self::build(config, carried, warning)
+    }
 
-        if let Some((user, pass)) = credentials_for(config) {
-            opts.set_credentials(user, pass);
+    fn build(
+        config: &MqttConfig,
+        carried: Carried,
+        outbox_warning: Option<String>,
+    ) -> (Self, EventReceiver) {
+        let Carried {
+            subscriptions,
+            routes,
+            cache,
+            outbox,
+        } = carried;
+        let disconnect_sent = Arc::new(Notify::new());
+        let online = Arc::new(AtomicBool::new(false));
+        let sub_acks = SubAcks::default();
+        let stats = Arc::new(std::sync::Mutex::new(BridgeStats::new(Instant::now())));
+        let stop = Arc::new(Notify::new());
+        let acl = Arc::new(AclGuard::new(config.acl.clone()));
+        let inbound = Arc::new(Inbound {
+            acl: acl.clone(),
+            signing: config.signing.clone().map(Verifier::new),
+            freshness: config
+                .freshness
+                .clone()
+                .map(|freshness| std::sync::Mutex::new(FreshnessGuard::new(freshness))),
+            refusals: Default::default(),
+            cache: cache.clone(),
+            routes: routes.clone(),
+        });
+        let (tx, rx) = mqtt_channel::channel(config.node_queue.clone());
+
+        if let Some(warning) = outbox_warning {
+            let _ = tx.try_send(MqttEvent::OutboxError(warning));
         }
 
-        let (client, event_loop) = AsyncClient::new(opts, 256);
+        // The probe checks whether the primary is back without touching this
+        // PC's session there, and without arming (or firing) its Last Will.
+        let probe = MqttConfig {
+            client_id: format!("{}-probe", config.client_id),
+            clean_session: true,
+            availability: None,
+            ..config.clone()
+        };
+        let mut warnings = Vec::new();
+        // The probe shares the primary's TLS setup, and its warnings.
+        let (opts, primary_probe) = match client_options(config, &mut warnings)
+            .and_then(|opts| Ok((opts, client_options(&probe, &mut Vec::new())?)))
+        {
+            Ok(opts) => opts,
+            Err(e) => {
+                // Never fall back to plain TCP when TLS was asked for. Dropping
+                // the event loop makes every client call fail fast instead of
+                // queueing forever, and the error is the only event sent.
+                let opts = MqttOptions::new(&config.client_id, &config.host, config.port);
+                let (client, _event_loop) = Client::new(ClientOptions::V311(Box::new(opts)), 256);
+                stats.lock().unwrap().failed(&e);
+                let _ = tx.try_send(MqttEvent::ConnectFailed(e));
+                return (
+                    Self {
+                        publisher: Arc::new(Publisher::new(client.clone(), stats.clone())),
+                        client,
+                        subscriptions,
+                        availability: None,
+                        disconnect_sent,
+                        outbox,
+                        online,
+                        tx,
+                        sub_acks,
+                        routes,
+                        cache,
+                        stats,
+                        acl,
+                        inbound,
+                        stop,
+                        event_loop_task: std::sync::Mutex::new(None),
+                    },
+                    rx,
+                );
+            }
+        };
+
+        // A fallback that can't even be set up (bad certificate path) is
+        // reported and skipped rather than taking the primary down with it.
+        let mut brokers = vec![BrokerTarget {
+            label: config.broker_label(),
+            options: opts.clone(),
+        }];
+        let label = config.broker_label();
+        for warning in warnings {
+            let _ = tx.try_send(MqttEvent::ConfigWarning(format!("{label}: {warning}")));
+        }
+        for fallback in config.broker_configs().into_iter().skip(1) {
+            let mut warnings = Vec::new();
+            match client_options(&fallback, &mut warnings) {
+                Ok(options) => {
+                    let label = fallback.broker_label();
+                    for warning in warnings {
+                        let _ =
+                            tx.try_send(MqttEvent::ConfigWarning(format!("{label}: {warning}")));
+                    }
+                    brokers.push(BrokerTarget { label, options });
+                }
+                Err(e) => {
+                    let _ = tx.try_send(MqttEvent::ConnectFailed(format!(
+                        "{} (fallback skipped): {e}",
+                        fallback.broker_label()
+                    )));
+                }
+            }
+        }
 
-        let subscriptions: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
-        let (tx, rx) = mpsc::channel(512);
+        let (client, event_loop) = Client::new(opts, 256);
+        let publisher = Arc::new(Publisher::new(client.clone(), stats.clone()));
 
-        let subs_clone = subscriptions.clone();
-        let client_clone = client.clone();
+        let context = EventLoopContext {
+            tx: tx.clone(),
+            subscriptions: subscriptions.clone(),
+            client: client.clone(),
+            brokers,
+            failover: config.failover.clone(),
+            primary_probe,
+            availability: config.availability.clone(),
+            disconnect_sent: disconnect_sent.clone(),
+            outbox: outbox.clone(),
+            online: online.clone(),
+            reconnect: config.reconnect.clone(),
+            sub_acks: sub_acks.clone(),
+            publisher: publisher.clone(),
+            stats: stats.clone(),
+            inbound: inbound.clone(),
+            stop: stop.clone(),
+        };
 
         // MqttBridge::new is called from Tauri's setup() (no ambient tokio
         // runtime there) — tauri::async_runtime provides the runtime handle
-        tauri::async_runtime::spawn(Self::run_event_loop(
-            event_loop,
-            tx,
-            subs_clone,
-            client_clone,
-        ));
+        let task = tauri::async_runtime::spawn(Self::run_event_loop(event_loop, context));
+        tauri::async_runtime::spawn(Self::expire_publish_acks(publisher.clone(), tx.downgrade()));
 
         (
             Self {
                 client,
                 subscriptions,
+                availability: config.availability.clone(),
+                disconnect_sent,
+                outbox,
+                online,
+                tx,
+                sub_acks,
+                publisher,
+                routes,
+                cache,
+                stats,
+                acl,
+                inbound,
+                stop,
+                event_loop_task: std::sync::Mutex::new(Some(task)),
             },
             rx,
         )
     }
 
-    async fn run_event_loop(
-        mut event_loop: rumqttc::EventLoop,
-        tx: mpsc::Sender<MqttEvent>,
-        subscriptions: Arc<Mutex<HashSet<String>>>,
-        client: AsyncClient,
-    ) {
+    async fn run_event_loop(mut event_loop: EventLoop, ctx: EventLoopContext) {
+        let EventLoopContext {
+            tx,
+            subscriptions,
+            client,
+            brokers,
+            failover,
+            primary_probe,
+            availability,
+            disconnect_sent,
+            outbox,
+            online,
+            reconnect,
+            sub_acks,
+            publisher,
+            stats,
+            inbound,
+            stop,
+        } = ctx;
         let mut was_connected = false;
+        let mut takeover = TakeoverDetector::default();
+        let mut last_failure: Option<String> = None;
+        // Filters an MQTT 5 broker refused: not replayed, since each refusal
+        // costs the connection (see `refused_subscription`).
+        let mut refused_filters = HashSet::new();
+        // Failed attempts since the last ConnAck.
+        let mut attempt: u32 = 0;
+        // Index into `brokers`, and failed attempts on it.
+        let mut current = 0;
+        let mut failures_here: u32 = 0;
+        // Bumped whenever a running probe is no longer wanted; a probe only
+        // reports back while the generation it started with is current.
+        let probe_generation = Arc::new(AtomicU64::new(0));
+        let (probe_tx, mut probe_rx) = mpsc::channel::<u64>(1);
 
         loop {
-            match event_loop.poll().await {
-                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+            let polled = tokio::select! {
+                _ = stop.notified() => {
+                    probe_generation.fetch_add(1, Ordering::AcqRel);
+                    Self::abandon_publishes(&publisher, &tx).await;
+                    return;
+                }
+                Some(generation) = probe_rx.recv() => {
+                    if generation != probe_generation.load(Ordering::Acquire) || current == 0 {
+                        continue;
+                    }
+                    // The primary answers again: drop the fallback link, and
+                    // the next poll() dials the primary. Dropping poll()
+                    // mid-read is fine here, the connection is abandoned.
+                    probe_generation.fetch_add(1, Ordering::AcqRel);
+                    let from = brokers[current].label.clone();
+                    current = 0;
+                    failures_here = 0;
+                    event_loop.set_options(brokers[0].options.clone());
+                    event_loop.clean();
+                    online.store(false, Ordering::Release);
+                    let to = brokers[0].label.clone();
+                    let _ = tx.send(MqttEvent::Failover { from, to }).await;
+                    takeover = TakeoverDetector::default();
+                    if was_connected {
+                        was_connected = false;
+                        let reason = "switching back to the primary broker".to_string();
+                        let _ = tx.send(MqttEvent::Disconnected(reason)).await;
+                    }
+                    continue;
+                }
+                polled = event_loop.poll() => polled,
+            };
+            match polled {
+                Ok(Polled::ConnAck { session_present }) => {
                     was_connected = true;
-                    let _ = tx.send(MqttEvent::Connected).await;
+                    takeover.connected(Instant::now());
+                    if let Some(freshness) = &inbound.freshness {
+                        let mut freshness = freshness.lock().unwrap();
+                        freshness.connected(session_present, Instant::now());
+                    }
+                    last_failure = None;
+                    attempt = 0;
+                    failures_here = 0;
+                    sub_acks.lock().unwrap().reconnected();
+                    let broker = brokers[current].label.clone();
+                    stats.lock().unwrap().connected(&broker);
+                    let _ = tx.send(MqttEvent::Connected { broker }).await;
+
+                    if current != 0 && !failover.probe_interval.is_zero() {
+                        let generation = probe_generation.fetch_add(1, Ordering::AcqRel) + 1;
+                        tauri::async_runtime::spawn(Self::probe_primary(
+                            primary_probe.clone(),
+                            failover.probe_interval,
+                            probe_generation.clone(),
+                            generation,
+                            probe_tx.clone(),
+                        ));
+                    }
 
                     // Replay subscriptions on reconnect
                     let subs = subscriptions.lock().await;
-                    for topic in subs.iter() {
-                        let _ = client.subscribe(topic, QoS::AtMostOnce).await;
+                    for (topic, qos) in subs.broker_subscriptions() {
+                        if refused_filters.contains(&topic) {
+                            continue;
+                        }
+                        let result = send_subscribe(&client, &sub_acks, &topic, qos).await;
+                        report(&tx, "subscribe", Some(&topic), result).await;
                     }
+                    drop(subs);
+
+                    // Publishing happens in a task of its own: it feeds the
+                    // request channel this loop empties, so it can't run
+                    // inline.
+                    online.store(true, Ordering::Release);
+                    let publisher = publisher.clone();
+                    let outbox = outbox.clone();
+                    let online = online.clone();
+                    let tx = tx.clone();
+                    let availability = availability.clone();
+                    tauri::async_runtime::spawn(async move {
+                        // Birth message: overwrites the retained Last Will
+                        // left by the previous unclean disconnect.
+                        if let Some(availability) = &availability {
+                            let result = publisher
+                                .publish(
+                                    &availability.topic,
+                                    QoS::AtLeastOnce,
+                                    true,
+                                    availability.online.as_bytes().to_vec(),
+                                    None,
+                                    None,
+                                )
+                                .await;
+                            report(&tx, "publish", Some(&availability.topic), result).await;
+                        }
+                        Self::drain_outbox(publisher, outbox, online, tx).await;
+                    });
                 }
-                Ok(Event::Incoming(Packet::Publish(publish))) => {
-                    let topic = publish.topic.clone();
-                    let payload = String::from_utf8_lossy(&publish.payload).to_string();
-                    let _ = tx
-                        .send(MqttEvent::Message { topic, payload })
-                        .await;
+                Ok(Polled::Publish(Received {
+                    topic,
+                    payload,
+                    retain,
+                    properties,
+                })) => {
+                    stats
+                        .lock()
+                        .unwrap()
+                        .received(&topic, payload.len(), Instant::now());
+                    let now = mqtt_signing::unix_now();
+                    let admission = inbound.receive(&topic, payload, retain, Instant::now(), now);
+                    let payload = match admission {
+                        Admission::Deliver(payload) => payload,
+                        Admission::Refused(refusal) => {
+                            if let Some(event) = refusal {
+                                let _ = tx.send(event).await;
+                            }
+                            continue;
+                        }
+                    };
+                    let message = MqttEvent::Message {
+                        topic,
+                        payload,
+                        properties,
+                    };
+                    let delivery = tx.send(message).await;
+                    if delivery == Ok(Delivery::Overflowed) {
+                        let config = tx.config();
+                        let _ = tx
+                            .send(MqttEvent::NodeBehind {
+                                capacity: config.capacity,
+                                overflow: config.overflow,
+                            })
+                            .await;
+                    }
                 }
-                Ok(_) => {}
+                Ok(Polled::PublishSent(pkid)) => {
+                    let outcome = publisher.tracker.lock().unwrap().sent(pkid, Instant::now());
+                    send_outcome(&tx, outcome).await;
+                }
+                Ok(Polled::PublishAcked(pkid)) => {
+                    let outcome = publisher.tracker.lock().unwrap().acked(pkid);
+                    send_outcome(&tx, outcome).await;
+                }
+                Ok(Polled::SubscribeSent(pkid)) => {
+                    sub_acks.lock().unwrap().sent(pkid);
+                }
+                Ok(Polled::SubAck { pkid, refused }) => {
+                    let Some(topic) = sub_acks.lock().unwrap().acked(pkid) else {
+                        continue;
+                    };
+                    match refused {
+                        Some(refused) => {
+                            let reason =
+                                format!("the broker rejected the subscription ({refused})");
+                            let _ = tx.send(MqttEvent::SubscribeFailed { topic, reason }).await;
+                        }
+                        None => {
+                            refused_filters.remove(&topic);
+                        }
+                    }
+                }
+                Ok(Polled::DisconnectSent) => {
+                    disconnect_sent.notify_waiters();
+                }
+                Ok(Polled::Other) => {}
                 Err(e) => {
+                    online.store(false, Ordering::Release);
+                    if let Some(refused) = e.refused_subscription() {
+                        let topic = sub_acks.lock().unwrap().refused();
+                        if let Some(topic) = topic {
+                            refused_filters.insert(topic.clone());
+                            let reason =
+                                format!("the broker rejected the subscription ({refused})");
+                            let _ = tx.send(MqttEvent::SubscribeFailed { topic, reason }).await;
+                        }
+                    }
+                    stats.lock().unwrap().failed(&format!(
+                        "{}: {}",
+                        brokers[current].label,
+                        e.describe()
+                    ));
                     if was_connected {
                         was_connected = false;
-                        let _ = tx
-                            .send(MqttEvent::Disconnected(e.to_string()))
-                            .await;
+                        let _ = tx.send(MqttEvent::Disconnected(e.to_string())).await;
+                        // Reconnecting would only kick the other client off in
+                        // turn. The loop ends here, dropping rumqttc's so client
+                        // calls fail fast; a rebuilt bridge starts over.
+                        if takeover.dropped(Instant::now()) || e.session_taken_over() {
+                            let client_id = brokers[current].options.client_id();
+                            stats.lock().unwrap().failed(&format!(
+                                "client id '{client_id}' is in use by another client; \
+                                 not reconnecting"
+                            ));
+                            let _ = tx.send(MqttEvent::SessionTakeover { client_id }).await;
+                            probe_generation.fetch_add(1, Ordering::AcqRel);
+                            Self::abandon_publishes(&publisher, &tx).await;
+                            return;
+                        }
+                    } else {
+                        let reason = format!("{}: {}", brokers[current].label, e.describe());
+                        if last_failure.as_ref() != Some(&reason) {
+                            let _ = tx.send(MqttEvent::ConnectFailed(reason.clone())).await;
+                            last_failure = Some(reason);
+                        }
+                    }
+
+                    failures_here += 1;
+                    if brokers.len() > 1 && failures_here >= failover.after_failures {
+                        let from = brokers[current].label.clone();
+                        current = (current + 1) % brokers.len();
+                        failures_here = 0;
+                        event_loop.set_options(brokers[current].options.clone());
+                        let to = brokers[current].label.clone();
+                        let _ = tx.send(MqttEvent::Failover { from, to }).await;
+                    }
+
+                    // The next poll() redials; back off so an outage of hours
+                    // doesn't mean a connection attempt every second. The
+                    // count carries across brokers, so cycling through a list
+                    // of dead ones still slows down.
+                    attempt = attempt.saturating_add(1);
+                    let delay = reconnect.delay(attempt, fastrand::f64());
+                    let _ = tx.send(MqttEvent::Reconnecting { attempt, delay }).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = stop.notified() => {
+                            probe_generation.fetch_add(1, Ordering::AcqRel);
+                            Self::abandon_publishes(&publisher, &tx).await;
+                            return;
+                        }
                     }
-                    // rumqttc will auto-reconnect; brief pause to avoid tight loop
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                 }
             }
         }
     }
 
-    pub async fn subscribe(&self, topics: &[String]) {
+    /// Every `interval`, try a throwaway connection to the primary; report
+    /// `generation` once one gets a ConnAck. Gives up quietly as soon as the
+    /// event loop has moved on (back to the primary, or to another fallback).
+    async fn probe_primary(
+        options: ClientOptions,
+        interval: Duration,
+        current_generation: Arc<AtomicU64>,
+        generation: u64,
+        primary_back: mpsc::Sender<u64>,
+    ) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if current_generation.load(Ordering::Acquire) != generation {
+                return;
+            }
+            if broker_reachable(options.clone()).await {
+                let _ = primary_back.try_send(generation);
+                return;
+            }
+        }
+    }
+
+    /// Send what queued up while offline, oldest first. The outbox stays
+    /// locked per message, so a live publish can't overtake the backlog: it
+    /// sees a non-empty outbox and queues behind it. Stops when the link drops
+    /// again; the rest waits for the next ConnAck.
+    async fn drain_outbox(
+        publisher: Arc<Publisher>,
+        outbox: Arc<Mutex<Outbox>>,
+        online: Arc<AtomicBool>,
+        tx: EventSender,
+    ) {
+        loop {
+            if !online.load(Ordering::Acquire) {
+                break;
+            }
+            let mut queue = outbox.lock().await;
+            let Some(msg) = queue.front() else {
+                break;
+            };
+            // try_publish: a full request channel must not hold the outbox
+            // lock (and with it every live publish) until the link is back.
+            match publisher.try_publish(msg).await {
+                Ok(()) => queue.pop_sent(),
+                Err(_) => {
+                    drop(queue);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        }
+
+        let mut queue = outbox.lock().await;
+        if let Err(e) = queue.flush() {
+            let _ = tx.send(MqttEvent::OutboxError(e)).await;
+        }
+        let stats = queue.take_stats();
+        if !stats.is_empty() {
+            let _ = tx.send(MqttEvent::OutboxDrained(stats)).await;
+        }
+    }
+
+    async fn abandon_publishes(publisher: &Publisher, tx: &EventSender) {
+        let reason = "the MQTT connection was replaced before the broker acknowledged it";
+        let abandoned = publisher.tracker.lock().unwrap().abandon(reason);
+        for outcome in abandoned {
+            send_outcome(tx, Some(outcome)).await;
+        }
+    }
+
+    /// Fail publishes whose ack is overdue. Holds the event sender weakly so
+    /// it ends with the bridge instead of keeping the channel open.
+    async fn expire_publish_acks(publisher: Arc<Publisher>, tx: WeakEventSender) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let Some(tx) = tx.upgrade() else {
+                return;
+            };
+            let expired = publisher.tracker.lock().unwrap().expire(Instant::now());
+            for outcome in expired {
+                send_outcome(&tx, Some(outcome)).await;
+            }
+        }
+    }
+
+    /// Subscribe `owner` to each filter at its QoS. The broker is only
+    /// contacted when the filter is new or its highest QoS went up. When it
+    /// isn't, it won't resend retained values either: an owner taking back
+    /// a filter it already held (a respawned child subscribing again) gets
+    /// the cached ones instead, see `Inbound::replay`. A new owner on a held
+    /// filter gets nothing, since Node already had the broker's copies.
+    pub async fn subscribe(&self, owner: &str, topics: &[(String, QoS)]) {
         let mut subs = self.subscriptions.lock().await;
-        for topic in topics {
-            subs.insert(topic.clone());
-            let _ = self.client.subscribe(topic, QoS::AtMostOnce).await;
+        for (topic, qos) in topics {
+            let held = subs.holds(owner, topic);
+            match subs.add(owner, topic, *qos) {
+                BrokerChange::Subscribe(qos) => {
+                    let result = send_subscribe(&self.client, &self.sub_acks, topic, qos).await;
+                    report(&self.tx, "subscribe", Some(topic), result).await;
+                }
+                _ if owner == NATIVE_OWNER || !held => {}
+                _ => {
+                    for event in self.inbound.replay(topic) {
+                        let _ = self.tx.send(event).await;
+                    }
+                }
+            }
         }
     }
 
-    pub async fn unsubscribe(&self, topics: &[String]) {
+    /// Drop `owner`'s interest in each filter. Other owners keep receiving:
+    /// the broker unsubscribe goes out only when the last one leaves.
+    pub async fn unsubscribe(&self, owner: &str, topics: &[String]) {
         let mut subs = self.subscriptions.lock().await;
         for topic in topics {
-            subs.remove(topic);
-            let _ = self.client.unsubscribe(topic).await;
+            match subs.remove(owner, topic) {
+                BrokerChange::Unsubscribe => {
+                    let result = self.client.unsubscribe(topic).await;
+                    report(&self.tx, "unsubscribe", Some(topic), result).await;
+                }
+                BrokerChange::Subscribe(qos) => {
+                    let result = send_subscribe(&self.client, &self.sub_acks, topic, qos).await;
+                    report(&self.tx, "subscribe", Some(topic), result).await;
+                }
+                BrokerChange::None => {}
+            }
         }
     }
 
-    pub async fn publish(&self, topic: &str, payload: &str, retain: bool, qos: QoS) {
-        let _ = self
-            .client
-            .publish(topic, qos, retain, payload.as_bytes().to_vec())
+    /// Handle messages on `filter` in Rust, whether or not Node is running.
+    /// The handler sees what passed the inbound checks, as Node would (see
+    /// `Inbound`). The broker subscription is held under the `rust` owner;
+    /// Node still receives the messages if it subscribes too.
+    pub async fn route(&self, filter: &str, qos: QoS, handler: Handler) -> Result<(), String> {
+        self.routes.write().unwrap().add(filter, handler)?;
+        self.subscribe(NATIVE_OWNER, &[(filter.to_string(), qos)])
             .await;
+        Ok(())
+    }
+
+    /// Undoes `route(filter, ..)`: its handlers go, and so does the `rust`
+    /// owner's hold on the broker subscription.
+    pub async fn unroute(&self, filter: &str) {
+        self.routes.write().unwrap().remove(filter);
+        self.unsubscribe(NATIVE_OWNER, &[filter.to_string()]).await;
+    }
+
+    pub fn status(&self) -> BridgeStatus {
+        let mut status = self.stats.lock().unwrap().snapshot(Instant::now());
+        status.node_queue = self.tx.stats();
+        status.acl = self.acl.stats();
+        status.refused = self.inbound.refusals.lock().unwrap().stats();
+        status
+    }
+
+    /// Node's side of `mqtt.acl`, checked before its publish or subscribe
+    /// is carried out. A refusal is counted and reported as `AclDenied`.
+    pub async fn node_may(&self, check: Check, topic: &str) -> bool {
+        match self.acl.check(check, topic) {
+            Verdict::Allowed => true,
+            Verdict::Denied { first } => {
+                let topic = topic.to_string();
+                let _ = self
+                    .tx
+                    .send(MqttEvent::AclDenied {
+                        check,
+                        topic,
+                        first,
+                    })
+                    .await;
+                false
+            }
+        }
+    }
+
+    /// `mqtt.signing` is on: some inbound messages only count when signed.
+    pub fn checks_signatures(&self) -> bool {
+        self.inbound.signing.is_some()
+    }
+
+    pub fn topic_value(&self, topic: &str) -> Option<CachedValue> {
+        self.cache.lock().unwrap().get(topic).cloned()
+    }
+
+    pub fn cached_topics(&self, filter: Option<&str>) -> Vec<String> {
+        self.cache.lock().unwrap().topics(filter)
+    }
+
+    pub async fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.subscriptions.lock().await.snapshot()
+    }
+
+    /// Publish now if the broker is connected and nothing is queued ahead;
+    /// otherwise hold the message in the outbox until the next ConnAck.
+    /// With an `id`, a `PublishResult` follows once the broker has acked it,
+    /// the ack timed out, or the outbox had to drop it.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        retain: bool,
+        qos: QoS,
+        properties: Option<MessageProperties>,
+        id: Option<String>,
+    ) {
+        let mut outbox = self.outbox.lock().await;
+        if self.online.load(Ordering::Acquire) && outbox.is_empty() {
+            drop(outbox);
+            let result = self
+                .publisher
+                .publish(topic, qos, retain, payload, properties.as_ref(), id.clone())
+                .await;
+            if let (Err(_), Some(id)) = (&result, id) {
+                let error = Some("the MQTT connection is not running".to_string());
+                send_outcome(&self.tx, Some(PublishOutcome { id, error })).await;
+            }
+            report(&self.tx, "publish", Some(topic), result).await;
+            return;
+        }
+        let queued = QueuedPublish {
+            topic: topic.to_string(),
+            payload,
+            qos: qos as u8,
+            retain,
+            properties,
+            id,
+        };
+        if let Err(e) = outbox.push(queued) {
+            let _ = self.tx.try_send(MqttEvent::OutboxError(e));
+        }
+        for id in outbox.take_dropped_ids() {
+            let error = Some("dropped from the offline queue".to_string());
+            send_outcome(&self.tx, Some(PublishOutcome { id, error })).await;
+        }
+    }
+
+    /// Answer a publish that never got as far as the client.
+    pub async fn reject_publish(&self, id: String, reason: String) {
+        let error = Some(reason);
+        send_outcome(&self.tx, Some(PublishOutcome { id, error })).await;
     }
 
     pub async fn disconnect(&self) {
-        let _ = self.client.disconnect().await;
+        let result = self.client.disconnect().await;
+        report(&self.tx, "disconnect", None, result).await;
+    }
+
+    /// Take this bridge down ahead of `replace`: offline status, DISCONNECT,
+    /// event loop ended. Whatever it was connected to can go away after this.
+    pub async fn retire(&self) {
+        // From here on publishes queue up for the new connection. Ones the
+        // old client already took go out before its DISCONNECT.
+        self.online.store(false, Ordering::Release);
+        self.shutdown().await;
+        self.stop.notify_one();
+        let task = self.event_loop_task.lock().unwrap().take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+
+    /// Start a bridge for `config` in place of this one, once `retire` has
+    /// taken it down. Subscriptions, native routes, cached values and the
+    /// outbox carry over; the new event loop re-subscribes after its ConnAck.
+    pub async fn replace(&self, config: &MqttConfig) -> (Self, EventReceiver) {
+        self.cache.lock().unwrap().set_policy(config.cache);
+        let mut outbox = self.outbox.lock().await;
+        let warning = outbox.reconfigure(config.outbox.clone()).err();
+        let dropped = outbox.take_dropped_ids();
+        drop(outbox);
+        for id in dropped {
+            let error = Some("dropped from the offline queue".to_string());
+            send_outcome(&self.tx, Some(PublishOutcome { id, error })).await;
+        }
+
+        let carried = Carried {
+            subscriptions: self.subscriptions.clone(),
+            routes: self.routes.clone(),
+            cache: self.cache.clone(),
+            outbox: self.outbox.clone(),
+        };
+        Self::build(config, carried, warning)
+    }
+
+    /// Announce `offline` and disconnect cleanly, for app exit.
+    ///
+    /// A clean DISCONNECT tells the broker to discard the Last Will, so the
+    /// offline status has to be published by hand. Waits (bounded) until the
+    /// DISCONNECT is on the wire: the publish queued ahead of it is then
+    /// written too, and the process can exit without losing either.
+    pub async fn shutdown(&self) {
+        let mut sent = std::pin::pin!(self.disconnect_sent.notified());
+        sent.as_mut().enable();
+
+        if let Some(availability) = &self.availability {
+            let _ = self
+                .publisher
+                .publish(
+                    &availability.topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    availability.offline.as_bytes().to_vec(),
+                    None,
+                    None,
+                )
+                .await;
+        }
+        if self.client.disconnect().await.is_ok() {
+            let _ = tokio::time::timeout(Duration::from_secs(2), sent).await;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mqtt_acl::Rules;
 
     fn config(username: Option<&str>, password: Option<&str>) -> MqttConfig {
         MqttConfig {
@@ -153,6 +1514,23 @@ mod tests {
             username: username.map(str::to_string),
             password: password.map(str::to_string),
             client_id: "test".into(),
+            tls: None,
+            ws_path: None,
+            availability: None,
+            outbox: OutboxConfig::default(),
+            keep_alive: Duration::from_secs(30),
+            clean_session: false,
+            max_inflight: 100,
+            reconnect: Backoff::default(),
+            fallbacks: Vec::new(),
+            failover: Failover::default(),
+            cache: CachePolicy::default(),
+            node_queue: ChannelConfig::default(),
+            embedded: None,
+            acl: Acl::default(),
+            signing: None,
+            freshness: None,
+            protocol: Protocol::default(),
         }
     }
 
@@ -186,4 +1564,240 @@ mod tests {
         let cfg = config(None, Some("pass"));
         assert_eq!(credentials_for(&cfg), None);
     }
+
+    #[test]
+    fn websocket_address_is_the_full_url() {
+        let mut cfg = config(None, None);
+        cfg.host = "broker.example.com".into();
+        cfg.port = 443;
+        cfg.ws_path = Some("/mqtt".into());
+        assert_eq!(cfg.broker_label(), "ws://broker.example.com:443/mqtt");
+
+        cfg.tls = Some(TlsConfig::default());
+        assert_eq!(cfg.broker_label(), "wss://broker.example.com:443/mqtt");
+    }
+
+    #[test]
+    fn websocket_url_brackets_ipv6_hosts() {
+        let mut cfg = config(None, None);
+        cfg.host = "::1".into();
+        cfg.port = 8080;
+        cfg.ws_path = Some("/".into());
+        assert_eq!(cfg.broker_label(), "ws://[::1]:8080/");
+    }
+
+    #[test]
+    fn availability_registers_a_retained_last_will() {
+        let mut cfg = config(None, None);
+        cfg.availability = Some(Availability {
+            topic: "home/pc/status".into(),
+            online: "online".into(),
+            offline: "offline".into(),
+        });
+        let will = mqtt_options(&cfg, &mut Vec::new())
+            .unwrap()
+            .last_will()
+            .expect("LWT set");
+        assert_eq!(will.topic, "home/pc/status");
+        assert_eq!(&will.message[..], b"offline");
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
+    }
+
+    #[test]
+    fn no_last_will_without_availability() {
+        assert!(mqtt_options(&config(None, None), &mut Vec::new())
+            .unwrap()
+            .last_will()
+            .is_none());
+    }
+
+    #[test]
+    fn tls_setup_errors_surface_from_mqtt_options() {
+        let mut cfg = config(None, None);
+        cfg.tls = Some(TlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..Default::default()
+        });
+        let err = mqtt_options(&cfg, &mut Vec::new()).expect_err("missing CA must fail");
+        assert!(err.contains("/nonexistent/ca.pem"), "{err}");
+    }
+
+    #[test]
+    fn unknown_qos_levels_fall_back_to_zero() {
+        assert_eq!(qos_from_level(1), QoS::AtLeastOnce);
+        assert_eq!(qos_from_level(2), QoS::ExactlyOnce);
+        assert_eq!(qos_from_level(7), QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let backoff = Backoff {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.0,
+        };
+        let delays: Vec<_> = (1..=8).map(|n| backoff.delay(n, 0.5).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff.delay(u32::MAX, 0.5), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_bounds() {
+        let backoff = Backoff {
+            min_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(300),
+            jitter: 0.2,
+        };
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_secs(8));
+        assert_eq!(backoff.delay(1, 0.5), Duration::from_secs(10));
+        assert!(backoff.delay(1, 0.999) < Duration::from_secs(12));
+        // Jitter never pushes past the cap.
+        assert_eq!(backoff.delay(20, 0.999), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn session_settings_reach_the_options() {
+        let mut cfg = config(None, None);
+        cfg.keep_alive = Duration::from_secs(90);
+        cfg.clean_session = true;
+        cfg.max_inflight = 10;
+        let opts = mqtt_options(&cfg, &mut Vec::new()).unwrap();
+        assert_eq!(opts.keep_alive(), Duration::from_secs(90));
+        assert!(opts.clean_session());
+        assert_eq!(opts.inflight(), 10);
+    }
+
+    #[test]
+    fn mqtt5_keeps_the_session_and_the_will() {
+        let mut cfg = config(None, None);
+        cfg.protocol = Protocol::V5;
+        cfg.availability = Some(Availability {
+            topic: "home/pc/status".into(),
+            online: "online".into(),
+            offline: "offline".into(),
+        });
+        let ClientOptions::V5(opts) = client_options(&cfg, &mut Vec::new()).unwrap() else {
+            panic!("expected MQTT 5 options");
+        };
+        assert!(!opts.clean_start());
+        let properties = opts.connect_properties().unwrap();
+        assert_eq!(properties.session_expiry_interval, Some(u32::MAX));
+        assert_eq!(opts.max_packet_size(), Some(MAX_PACKET_SIZE as u32));
+        assert_eq!(&opts.last_will().expect("LWT set").message[..], b"offline");
+
+        cfg.keep_alive = Duration::ZERO;
+        assert!(client_options(&cfg, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn fallbacks_become_full_broker_configs() {
+        let mut cfg = config(Some("pc"), Some("secret"));
+        cfg.fallbacks = vec![BrokerEndpoint {
+            host: "nas.local".into(),
+            port: 443,
+            username: None,
+            password: None,
+            tls: Some(TlsConfig::default()),
+            ws_path: Some("/mqtt".into()),
+        }];
+        let configs = cfg.broker_configs();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].broker_label(), "localhost:1883");
+        assert_eq!(configs[1].broker_label(), "wss://nas.local:443/mqtt");
+        assert_eq!(configs[1].username, None);
+        assert_eq!(configs[1].client_id, cfg.client_id);
+    }
+
+    #[test]
+    fn native_routes_only_see_verified_payloads() {
+        let topic = "home/room/pc/windows/shutdown";
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let routes = Routes::default();
+        let record = seen.clone();
+        let handler: Handler = Arc::new(move |_, payload| {
+            record.lock().unwrap().push(payload.to_vec());
+        });
+        routes.write().unwrap().add(topic, handler).unwrap();
+        let secret = "correct horse battery staple";
+        let inbound = Inbound {
+            acl: Arc::default(),
+            signing: Some(Verifier::new(SigningConfig {
+                secret: secret.into(),
+                topics: vec![topic.into()],
+                max_age: Duration::from_secs(30),
+            })),
+            freshness: None,
+            refusals: Default::default(),
+            cache: Cache::default(),
+            routes,
+        };
+        let now = 1_760_000_000;
+        let receive =
+            |payload: &str| inbound.receive(topic, payload.into(), false, Instant::now(), now);
+
+        let signed = mqtt_signing::sign(secret, topic, "1", now);
+        assert!(matches!(receive(&signed), Admission::Deliver(payload) if payload == b"1"));
+        assert!(matches!(
+            receive("1"),
+            Admission::Refused(Some(MqttEvent::Unsigned { .. }))
+        ));
+        // A flood is counted, not reported message by message.
+        assert!(matches!(receive("1"), Admission::Refused(None)));
+        assert_eq!(inbound.refusals.lock().unwrap().stats().unsigned, 2);
+        // The route got the unwrapped payload, and only the signed one.
+        assert_eq!(*seen.lock().unwrap(), vec![b"1".to_vec()]);
+    }
+
+    #[test]
+    fn only_checked_retained_values_are_replayed() {
+        let secret = "correct horse battery staple";
+        let inbound = Inbound {
+            acl: Arc::new(AclGuard::new(Acl {
+                inbound: Rules {
+                    allow: None,
+                    deny: vec!["pc/secret/#".into()],
+                },
+                ..Acl::default()
+            })),
+            signing: Some(Verifier::new(SigningConfig {
+                secret: secret.into(),
+                topics: vec!["pc/exec/#".into()],
+                max_age: Duration::from_secs(30),
+            })),
+            freshness: None,
+            refusals: Default::default(),
+            cache: Arc::new(std::sync::Mutex::new(TopicCache::new(CachePolicy::All))),
+            routes: Routes::default(),
+        };
+        let now = 1_760_000_000;
+        let receive = |topic: &str, payload: &str, retain: bool| {
+            inbound.receive(topic, payload.into(), retain, Instant::now(), now)
+        };
+        let signed = mqtt_signing::sign(secret, "pc/exec/run", "notepad", now);
+        receive("pc/exec/run", &signed, true);
+        receive("pc/exec/other", "calc", true);
+        receive("pc/secret/pin", "1234", true);
+        receive("pc/volume", "40", false);
+        receive("pc/status", "online", true);
+
+        // Refused messages never reached the cache; the signed one did,
+        // unwrapped, but isn't replayed.
+        let cache = inbound.cache.lock().unwrap();
+        assert_eq!(
+            cache.topics(None),
+            vec!["pc/exec/run", "pc/status", "pc/volume"]
+        );
+        assert_eq!(cache.get("pc/exec/run").unwrap().payload, b"notepad");
+        drop(cache);
+        let replayed: Vec<_> = inbound
+            .replay("pc/#")
+            .into_iter()
+            .map(|event| match event {
+                MqttEvent::Message { topic, .. } => topic,
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        assert_eq!(replayed, vec!["pc/status"]);
+    }
 }
@@ -0,0 +1,823 @@
+use crate::mqtt_bridge::qos_from_level;
+use crate::mqtt_topics::{filter_matches, valid_filter};
+use bytes::BytesMut;
+use hmac::{Hmac, Mac};
+use rumqttc::mqttbytes::v4::{
+    self, ConnAck, Connect, ConnectReturnCode, LastWill, Packet, PubAck, PubComp, PubRec, Publish,
+    SubAck, SubscribeReasonCode, UnsubAck,
+};
+use rumqttc::mqttbytes::{self, Protocol};
+use rumqttc::{AsyncClient, Event, MqttOptions, QoS};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Same ceiling as the bridge's own client: clipboard screenshots.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+/// A client that opens a socket and never sends CONNECT is dropped.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages a slow client can fall behind by before new ones skip it. Also
+/// how many QoS 2 messages it may leave waiting for their PUBREL.
+const SESSION_QUEUE: usize = 1024;
+/// Requests the uplink can have in flight: the retained catch-up after a
+/// reconnect goes out in one burst.
+const UPLINK_QUEUE: usize = 1024;
+const UPSTREAM_RETRY: Duration = Duration::from_secs(5);
+const UPSTREAM_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// `(message, level)` for the app log.
+pub type Log = Arc<dyn Fn(String, &'static str) + Send + Sync>;
+
+/// `mqtt.embedded`: a broker inside the app, for when the home one is out of
+/// reach. Local clients (the bridge, MIDI pads, scripts) connect to `listen`;
+/// `outgoing` and `incoming` filters are shared with the upstream broker
+/// while it is reachable.
+///
+/// Sessions are always clean: nothing is kept for a client between
+/// connections, so `clean_session = false` is refused rather than quietly
+/// ignored, and nothing is redelivered after a reconnect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddedConfig {
+    pub listen: SocketAddr,
+    /// What every client must log in with. Without it anyone who can reach
+    /// `listen` gets in, so `read_embedded` only allows that on loopback.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Local messages copied to the upstream broker.
+    pub outgoing: Vec<String>,
+    /// Upstream messages copied to local subscribers.
+    pub incoming: Vec<String>,
+}
+
+impl EmbeddedConfig {
+    pub fn bridges(&self) -> bool {
+        !self.outgoing.is_empty() || !self.incoming.is_empty()
+    }
+}
+
+/// QoS is capped at 1 on the way out: on localhost nothing is worth the
+/// exactly-once round trips. A QoS 2 PUBLISH coming in is still held until
+/// its PUBREL, so a resent one isn't passed on twice.
+fn lower(a: QoS, b: QoS) -> QoS {
+    qos_from_level((a as u8).min(b as u8))
+}
+
+/// Where a message came from. What the upstream sent is never sent back up.
+#[derive(Clone, Copy, PartialEq)]
+enum Origin {
+    Local,
+    Upstream,
+}
+
+enum Outgoing {
+    Publish(Publish),
+    /// Another connection took over this client id.
+    Close,
+}
+
+struct Session {
+    client_id: String,
+    tx: mpsc::Sender<Outgoing>,
+    filters: Vec<(String, QoS)>,
+}
+
+struct Uplink {
+    client: AsyncClient,
+    outgoing: Vec<String>,
+    online: AtomicBool,
+}
+
+impl Uplink {
+    /// `topic` goes up: a local message on an `outgoing` filter. What the
+    /// upstream sent is never sent back.
+    fn shares(&self, topic: &str, origin: Origin) -> bool {
+        origin == Origin::Local && self.outgoing.iter().any(|f| filter_matches(f, topic))
+    }
+}
+
+/// The login clients must give, kept as a MAC under a random key: checking
+/// one compares two tags in constant time, so how long a refusal takes says
+/// nothing about how much of the password was right.
+struct Credentials {
+    key: [u8; 32],
+    tag: Vec<u8>,
+}
+
+impl Credentials {
+    fn new(username: &str, password: &str) -> Self {
+        let key = std::array::from_fn(|_| fastrand::u8(..));
+        let tag = Self::mac(&key, username, password).finalize().into_bytes();
+        Self {
+            key,
+            tag: tag.to_vec(),
+        }
+    }
+
+    fn mac(key: &[u8], username: &str, password: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+        // Length first, so "ab" + "c" and "a" + "bc" differ.
+        mac.update(&(username.len() as u64).to_be_bytes());
+        mac.update(username.as_bytes());
+        mac.update(password.as_bytes());
+        mac
+    }
+
+    fn accepts(&self, username: &str, password: &str) -> bool {
+        Self::mac(&self.key, username, password)
+            .verify_slice(&self.tag)
+            .is_ok()
+    }
+}
+
+/// Sessions, retained messages and the uplink: what connections share.
+struct Hub {
+    sessions: Mutex<HashMap<u64, Session>>,
+    retained: Mutex<HashMap<String, Publish>>,
+    next_id: AtomicU64,
+    /// `username` and `password` from `EmbeddedConfig`.
+    login: Option<Credentials>,
+    uplink: Option<Uplink>,
+}
+
+impl Hub {
+    fn new(config: &EmbeddedConfig, uplink: Option<Uplink>) -> Self {
+        let login = config.username.as_deref().map(|username| {
+            Credentials::new(username, config.password.as_deref().unwrap_or_default())
+        });
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            retained: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            login,
+            uplink,
+        }
+    }
+
+    /// The CONNACK code for `connect`, and why when it isn't `Success`.
+    fn admit(&self, connect: &Connect) -> Result<(), (ConnectReturnCode, &'static str)> {
+        if connect.protocol != Protocol::V4 {
+            return Err((ConnectReturnCode::RefusedProtocolVersion, "not MQTT 3.1.1"));
+        }
+        if let Some(credentials) = &self.login {
+            match &connect.login {
+                Some(login) if credentials.accepts(&login.username, &login.password) => {}
+                Some(_) => {
+                    return Err((ConnectReturnCode::BadUserNamePassword, "wrong login"));
+                }
+                None => return Err((ConnectReturnCode::NotAuthorized, "no login")),
+            }
+        }
+        if !connect.clean_session {
+            let why = "clean_session is false, and no sessions are kept here";
+            return Err((ConnectReturnCode::ServiceUnavailable, why));
+        }
+        Ok(())
+    }
+
+    fn publish(&self, publish: &Publish, origin: Origin) {
+        if publish.retain {
+            let mut retained = self.retained.lock().unwrap();
+            if publish.payload.is_empty() {
+                retained.remove(&publish.topic);
+            } else {
+                retained.insert(publish.topic.clone(), publish.clone());
+            }
+        }
+
+        for session in self.sessions.lock().unwrap().values() {
+            let granted = session
+                .filters
+                .iter()
+                .filter(|(filter, _)| filter_matches(filter, &publish.topic))
+                .map(|(_, qos)| *qos)
+                .max_by_key(|qos| *qos as u8);
+            if let Some(granted) = granted {
+                let mut copy = publish.clone();
+                copy.qos = lower(granted, publish.qos);
+                copy.retain = false;
+                copy.dup = false;
+                // A client this far behind loses messages rather than
+                // holding everyone else up.
+                let _ = session.tx.try_send(Outgoing::Publish(copy));
+            }
+        }
+
+        if let Some(uplink) = &self.uplink {
+            let shared = uplink.shares(&publish.topic, origin);
+            if shared && uplink.online.load(Ordering::Acquire) {
+                let _ = uplink.client.try_publish(
+                    &publish.topic,
+                    lower(publish.qos, QoS::AtLeastOnce),
+                    publish.retain,
+                    publish.payload.clone(),
+                );
+            }
+        }
+    }
+
+    fn retained_for(&self, filter: &str, qos: QoS) -> Vec<Publish> {
+        self.retained
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|publish| filter_matches(filter, &publish.topic))
+            .map(|publish| {
+                let mut copy = publish.clone();
+                copy.qos = lower(qos, publish.qos);
+                copy
+            })
+            .collect()
+    }
+}
+
+/// A running embedded broker. Dropping it closes the listener, every client
+/// connection and the uplink.
+pub struct EmbeddedBroker {
+    tasks: Vec<tauri::async_runtime::JoinHandle<()>>,
+    hub: Arc<Hub>,
+}
+
+impl Drop for EmbeddedBroker {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        // Connection tasks notice their queue closing.
+        self.hub.sessions.lock().unwrap().clear();
+    }
+}
+
+impl EmbeddedBroker {
+    /// Binds `config.listen` right away, so a taken port is an error here
+    /// rather than a log line later. `upstream` is where shared topics go;
+    /// only used when the config has filters to share.
+    pub fn start(
+        config: &EmbeddedConfig,
+        upstream: Option<MqttOptions>,
+        log: Log,
+    ) -> Result<Self, String> {
+        let listener = std::net::TcpListener::bind(config.listen)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| format!("embedded broker on {}: {e}", config.listen))?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+        let (uplink, uplink_loop) = match upstream {
+            Some(options) if config.bridges() => {
+                let (client, event_loop) = AsyncClient::new(options, UPLINK_QUEUE);
+                let uplink = Uplink {
+                    client,
+                    outgoing: config.outgoing.clone(),
+                    online: AtomicBool::new(false),
+                };
+                (Some(uplink), Some(event_loop))
+            }
+            _ => (None, None),
+        };
+        let hub = Arc::new(Hub::new(config, uplink));
+
+        let mut tasks = Vec::new();
+        if let Some(event_loop) = uplink_loop {
+            tasks.push(tauri::async_runtime::spawn(run_uplink(
+                hub.clone(),
+                event_loop,
+                config.incoming.clone(),
+                log.clone(),
+            )));
+        }
+        tasks.push(tauri::async_runtime::spawn(accept(
+            hub.clone(),
+            listener,
+            log.clone(),
+        )));
+        log(
+            format!("Embedded MQTT broker listening on {local_addr}"),
+            "info",
+        );
+        Ok(Self { tasks, hub })
+    }
+}
+
+async fn accept(hub: Arc<Hub>, listener: std::net::TcpListener, log: Log) {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log(format!("embedded broker: {e}"), "error");
+            return;
+        }
+    };
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let _ = stream.set_nodelay(true);
+                tauri::async_runtime::spawn(serve(hub.clone(), stream, log.clone()));
+            }
+            Err(e) => {
+                log(format!("embedded broker: accept: {e}"), "warn");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Read one packet, waiting for more bytes as needed. `None` once the
+/// client is gone or sent garbage.
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin), buf: &mut BytesMut) -> Option<Packet> {
+    loop {
+        match v4::read(buf, MAX_PACKET_SIZE) {
+            Ok(packet) => return Some(packet),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(_) => return None,
+        }
+        match stream.read_buf(buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+}
+
+async fn write(stream: &mut (impl AsyncWrite + Unpin), packet: &Packet) -> bool {
+    let mut buf = BytesMut::new();
+    let written = match packet {
+        Packet::ConnAck(p) => p.write(&mut buf),
+        Packet::Publish(p) => p.write(&mut buf),
+        Packet::PubAck(p) => p.write(&mut buf),
+        Packet::PubRec(p) => p.write(&mut buf),
+        Packet::PubComp(p) => p.write(&mut buf),
+        Packet::SubAck(p) => p.write(&mut buf),
+        Packet::UnsubAck(p) => p.write(&mut buf),
+        Packet::PingResp => v4::PingResp.write(&mut buf),
+        _ => return true,
+    };
+    written.is_ok() && stream.write_all(&buf).await.is_ok()
+}
+
+/// One client connection, from CONNECT to its socket closing.
+async fn serve(hub: Arc<Hub>, stream: impl AsyncRead + AsyncWrite + Send + 'static, log: Log) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = BytesMut::with_capacity(4096);
+    let connect =
+        match tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buf)).await {
+            Ok(Some(Packet::Connect(connect))) => connect,
+            _ => return,
+        };
+    if let Err((code, why)) = hub.admit(&connect) {
+        let client_id = &connect.client_id;
+        log(
+            format!("embedded broker: refused client '{client_id}': {why}"),
+            "warn",
+        );
+        write(&mut writer, &Packet::ConnAck(ConnAck::new(code, false))).await;
+        return;
+    }
+
+    let id = hub.next_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel(SESSION_QUEUE);
+    {
+        let mut sessions = hub.sessions.lock().unwrap();
+        // A second connection with the same id takes over, as on any broker.
+        sessions.retain(|_, session| {
+            let same = session.client_id == connect.client_id && !connect.client_id.is_empty();
+            if same {
+                let _ = session.tx.try_send(Outgoing::Close);
+            }
+            !same
+        });
+        sessions.insert(
+            id,
+            Session {
+                client_id: connect.client_id.clone(),
+                tx,
+                filters: Vec::new(),
+            },
+        );
+    }
+
+    let accepted = ConnAck::new(ConnectReturnCode::Success, false);
+    let clean = if write(&mut writer, &Packet::ConnAck(accepted)).await {
+        session_loop(
+            &hub,
+            id,
+            connect.keep_alive,
+            &mut reader,
+            &mut writer,
+            &mut buf,
+            &mut rx,
+        )
+        .await
+    } else {
+        false
+    };
+
+    hub.sessions.lock().unwrap().remove(&id);
+    if !clean {
+        if let Some(will) = connect.last_will {
+            hub.publish(&will_message(will), Origin::Local);
+        }
+    }
+}
+
+fn will_message(will: LastWill) -> Publish {
+    let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+    publish.retain = will.retain;
+    publish
+}
+
+/// Serves a connected client. `true` when it left with DISCONNECT, which
+/// cancels its Last Will.
+async fn session_loop(
+    hub: &Hub,
+    id: u64,
+    keep_alive: u16,
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    buf: &mut BytesMut,
+    rx: &mut mpsc::Receiver<Outgoing>,
+) -> bool {
+    // The spec's grace: one and a half keep-alive periods of silence.
+    let idle = match keep_alive {
+        0 => Duration::MAX,
+        secs => Duration::from_millis(u64::from(secs) * 1500),
+    };
+    let mut next_pkid: u16 = 0;
+    // QoS 2 messages received but not released yet, by packet id.
+    let mut unreleased: HashMap<u16, Publish> = HashMap::new();
+    loop {
+        tokio::select! {
+            packet = tokio::time::timeout(idle, read_packet(reader, buf)) => {
+                let Ok(Some(packet)) = packet else {
+                    return false;
+                };
+                let reply = match packet {
+                    Packet::Publish(publish) => match publish.qos {
+                        QoS::AtMostOnce => {
+                            hub.publish(&publish, Origin::Local);
+                            None
+                        }
+                        QoS::AtLeastOnce => {
+                            hub.publish(&publish, Origin::Local);
+                            Some(Packet::PubAck(PubAck::new(publish.pkid)))
+                        }
+                        // Passed on at PUBREL: until then the client may
+                        // send it again, and it must still go out once.
+                        QoS::ExactlyOnce => {
+                            let pkid = publish.pkid;
+                            let full = unreleased.len() >= SESSION_QUEUE;
+                            if full && !unreleased.contains_key(&pkid) {
+                                return false;
+                            }
+                            unreleased.insert(pkid, publish);
+                            Some(Packet::PubRec(PubRec::new(pkid)))
+                        }
+                    },
+                    Packet::PubRel(rel) => {
+                        if let Some(publish) = unreleased.remove(&rel.pkid) {
+                            hub.publish(&publish, Origin::Local);
+                        }
+                        Some(Packet::PubComp(PubComp::new(rel.pkid)))
+                    }
+                    Packet::Subscribe(subscribe) => {
+                        let mut codes = Vec::new();
+                        let mut retained = Vec::new();
+                        {
+                            let mut sessions = hub.sessions.lock().unwrap();
+                            let Some(session) = sessions.get_mut(&id) else {
+                                return false;
+                            };
+                            for filter in subscribe.filters {
+                                if !valid_filter(&filter.path) {
+                                    codes.push(SubscribeReasonCode::Failure);
+                                    continue;
+                                }
+                                let qos = lower(filter.qos, QoS::AtLeastOnce);
+                                session.filters.retain(|(existing, _)| *existing != filter.path);
+                                session.filters.push((filter.path.clone(), qos));
+                                retained.extend(hub.retained_for(&filter.path, qos));
+                                codes.push(SubscribeReasonCode::Success(qos));
+                            }
+                        }
+                        if !write(writer, &Packet::SubAck(SubAck::new(subscribe.pkid, codes))).await {
+                            return false;
+                        }
+                        for mut publish in retained {
+                            if publish.qos != QoS::AtMostOnce {
+                                next_pkid = next_pkid.wrapping_add(1).max(1);
+                                publish.pkid = next_pkid;
+                            }
+                            if !write(writer, &Packet::Publish(publish)).await {
+                                return false;
+                            }
+                        }
+                        None
+                    }
+                    Packet::Unsubscribe(unsubscribe) => {
+                        if let Some(session) = hub.sessions.lock().unwrap().get_mut(&id) {
+                            session
+                                .filters
+                                .retain(|(filter, _)| !unsubscribe.topics.contains(filter));
+                        }
+                        Some(Packet::UnsubAck(UnsubAck::new(unsubscribe.pkid)))
+                    }
+                    Packet::PingReq => Some(Packet::PingResp),
+                    Packet::Disconnect => return true,
+                    // Acks for what we sent: sessions are clean, so nothing
+                    // is redelivered and there is nothing to settle.
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    if !write(writer, &reply).await {
+                        return false;
+                    }
+                }
+            }
+            outgoing = rx.recv() => {
+                let Some(Outgoing::Publish(mut publish)) = outgoing else {
+                    return false;
+                };
+                if publish.qos != QoS::AtMostOnce {
+                    next_pkid = next_pkid.wrapping_add(1).max(1);
+                    publish.pkid = next_pkid;
+                }
+                if !write(writer, &Packet::Publish(publish)).await {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the connection to the upstream broker, backing off while it is out
+/// of reach, and feeds what it sends on `incoming` to local subscribers.
+async fn run_uplink(
+    hub: Arc<Hub>,
+    mut event_loop: rumqttc::EventLoop,
+    incoming: Vec<String>,
+    log: Log,
+) {
+    let Some(uplink) = &hub.uplink else {
+        return;
+    };
+    let upstream = event_loop.mqtt_options.broker_address();
+    let mut retry = UPSTREAM_RETRY;
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                retry = UPSTREAM_RETRY;
+                uplink.online.store(true, Ordering::Release);
+                for filter in &incoming {
+                    let _ = uplink.client.try_subscribe(filter, QoS::AtLeastOnce);
+                }
+                // Catch the upstream up on retained state that changed while
+                // it was away, the availability topic above all: it still
+                // holds the Last Will from when the link dropped.
+                for filter in &uplink.outgoing {
+                    for publish in hub.retained_for(filter, QoS::AtLeastOnce) {
+                        let _ = uplink.client.try_publish(
+                            &publish.topic,
+                            publish.qos,
+                            true,
+                            publish.payload,
+                        );
+                    }
+                }
+                log(
+                    format!(
+                        "Embedded broker: sharing topics with {}:{}",
+                        upstream.0, upstream.1
+                    ),
+                    "info",
+                );
+            }
+            Ok(Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                hub.publish(&publish, Origin::Upstream);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if uplink.online.swap(false, Ordering::AcqRel) {
+                    log(
+                        format!(
+                            "Embedded broker: upstream {}:{} lost: {e}; running local only",
+                            upstream.0, upstream.1
+                        ),
+                        "warn",
+                    );
+                }
+                tokio::time::sleep(retry).await;
+                retry = (retry * 2).min(UPSTREAM_RETRY_MAX);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::mqttbytes::v4::{PubRel, Subscribe};
+    use tokio::io::DuplexStream;
+
+    fn quiet() -> Log {
+        Arc::new(|_, _| {})
+    }
+
+    fn local() -> EmbeddedConfig {
+        EmbeddedConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            username: None,
+            password: None,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        }
+    }
+
+    /// A client speaking raw packets to `serve` over an in-memory pipe.
+    struct Peer {
+        stream: DuplexStream,
+        buf: BytesMut,
+    }
+
+    impl Peer {
+        async fn connect(hub: &Arc<Hub>, connect: Connect) -> (Self, ConnectReturnCode) {
+            let (stream, server) = tokio::io::duplex(4096);
+            tokio::spawn(serve(hub.clone(), server, quiet()));
+            let mut peer = Self {
+                stream,
+                buf: BytesMut::new(),
+            };
+            peer.send(Packet::Connect(connect)).await;
+            match peer.recv().await {
+                Packet::ConnAck(ack) => (peer, ack.code),
+                other => panic!("expected CONNACK, got {other:?}"),
+            }
+        }
+
+        /// Writes what a client sends; `write` only knows the broker's side.
+        async fn send(&mut self, packet: Packet) {
+            let mut buf = BytesMut::new();
+            match packet {
+                Packet::Connect(p) => p.write(&mut buf),
+                Packet::Publish(p) => p.write(&mut buf),
+                Packet::PubRel(p) => p.write(&mut buf),
+                Packet::Subscribe(p) => p.write(&mut buf),
+                other => panic!("peer can't send {other:?}"),
+            }
+            .unwrap();
+            self.stream.write_all(&buf).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Packet {
+            read_packet(&mut self.stream, &mut self.buf).await.unwrap()
+        }
+    }
+
+    fn session(hub: &Hub, filters: &[(&str, QoS)]) -> mpsc::Receiver<Outgoing> {
+        let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+        let id = hub.next_id.fetch_add(1, Ordering::Relaxed);
+        let filters = filters.iter().map(|(f, qos)| (f.to_string(), *qos));
+        let session = Session {
+            client_id: format!("raw-{id}"),
+            tx,
+            filters: filters.collect(),
+        };
+        hub.sessions.lock().unwrap().insert(id, session);
+        rx
+    }
+
+    fn topics(rx: &mut mpsc::Receiver<Outgoing>) -> Vec<(String, QoS)> {
+        std::iter::from_fn(|| match rx.try_recv().ok()? {
+            Outgoing::Publish(publish) => Some((publish.topic, publish.qos)),
+            Outgoing::Close => None,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn routes_publishes_to_matching_subscribers() {
+        let hub = Hub::new(&local(), None);
+        let mut keys = session(&hub, &[("home/room/pc/keys/+", QoS::AtLeastOnce)]);
+        let mut everything = session(&hub, &[("#", QoS::AtMostOnce)]);
+
+        for (topic, qos) in [
+            ("home/room/pc/keys/press", QoS::ExactlyOnce),
+            ("home/room/pc/audio/volume", QoS::AtLeastOnce),
+            ("$SYS/uptime", QoS::AtMostOnce),
+        ] {
+            hub.publish(&Publish::new(topic, qos, "x"), Origin::Local);
+        }
+
+        let press = ("home/room/pc/keys/press".to_string(), QoS::AtLeastOnce);
+        assert_eq!(topics(&mut keys), [press]);
+        assert_eq!(
+            topics(&mut everything),
+            [
+                ("home/room/pc/keys/press".to_string(), QoS::AtMostOnce),
+                ("home/room/pc/audio/volume".to_string(), QoS::AtMostOnce),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn late_subscribers_get_retained_messages() {
+        let hub = Arc::new(Hub::new(&local(), None));
+        let (mut bridge, _) = Peer::connect(&hub, Connect::new("bridge")).await;
+        let mut status = Publish::new("home/room/pc/status", QoS::AtLeastOnce, "online");
+        status.retain = true;
+        status.pkid = 1;
+        bridge.send(Packet::Publish(status)).await;
+        assert!(matches!(bridge.recv().await, Packet::PubAck(_)));
+
+        let (mut dashboard, _) = Peer::connect(&hub, Connect::new("dashboard")).await;
+        let subscribe = Subscribe::new("home/room/pc/#", QoS::AtMostOnce);
+        dashboard.send(Packet::Subscribe(subscribe)).await;
+        assert!(matches!(dashboard.recv().await, Packet::SubAck(_)));
+        let Packet::Publish(publish) = dashboard.recv().await else {
+            panic!("expected the retained status");
+        };
+        assert_eq!(publish.topic, "home/room/pc/status");
+        assert!(publish.retain);
+        assert_eq!(publish.qos, QoS::AtMostOnce);
+    }
+
+    #[tokio::test]
+    async fn exactly_once_is_passed_on_at_pubrel() {
+        let hub = Arc::new(Hub::new(&local(), None));
+        let mut listener = session(&hub, &[("#", QoS::AtLeastOnce)]);
+        let (mut pad, _) = Peer::connect(&hub, Connect::new("midi-pad")).await;
+        let mut press = Publish::new("home/room/pc/keys/press", QoS::ExactlyOnce, "C4");
+        press.pkid = 7;
+
+        pad.send(Packet::Publish(press.clone())).await;
+        assert_eq!(pad.recv().await, Packet::PubRec(PubRec::new(7)));
+        press.dup = true;
+        pad.send(Packet::Publish(press)).await;
+        assert_eq!(pad.recv().await, Packet::PubRec(PubRec::new(7)));
+        assert!(topics(&mut listener).is_empty());
+
+        pad.send(Packet::PubRel(PubRel::new(7))).await;
+        assert_eq!(pad.recv().await, Packet::PubComp(PubComp::new(7)));
+        let press = ("home/room/pc/keys/press".to_string(), QoS::AtLeastOnce);
+        assert_eq!(topics(&mut listener), [press]);
+    }
+
+    #[tokio::test]
+    async fn refuses_what_it_cannot_honour() {
+        let open = Arc::new(Hub::new(&local(), None));
+        let mut persistent = Connect::new("dashboard");
+        persistent.clean_session = false;
+        let (_, code) = Peer::connect(&open, persistent).await;
+        assert_eq!(code, ConnectReturnCode::ServiceUnavailable);
+
+        let config = EmbeddedConfig {
+            username: Some("pc".into()),
+            password: Some("secret".into()),
+            ..local()
+        };
+        let guarded = Arc::new(Hub::new(&config, None));
+        let (_, code) = Peer::connect(&guarded, Connect::new("anonymous")).await;
+        assert_eq!(code, ConnectReturnCode::NotAuthorized);
+        let mut wrong = Connect::new("guess");
+        wrong.set_login("pc", "hunter2");
+        let (_, code) = Peer::connect(&guarded, wrong).await;
+        assert_eq!(code, ConnectReturnCode::BadUserNamePassword);
+        let mut right = Connect::new("bridge");
+        right.set_login("pc", "secret");
+        let (_, code) = Peer::connect(&guarded, right).await;
+        assert_eq!(code, ConnectReturnCode::Success);
+    }
+
+    #[test]
+    fn credentials_need_both_halves_exactly() {
+        let credentials = Credentials::new("pc", "secret");
+        assert!(credentials.accepts("pc", "secret"));
+        assert!(!credentials.accepts("pcs", "ecret"));
+        assert!(!credentials.accepts("pc", "secret2"));
+        assert!(!credentials.accepts("pc", ""));
+    }
+
+    #[test]
+    fn only_local_outgoing_topics_go_up() {
+        let options = MqttOptions::new("laptop-upstream", "localhost", 1883);
+        let uplink = Uplink {
+            client: AsyncClient::new(options, 1).0,
+            outgoing: vec!["home/room/pc/#".into()],
+            online: AtomicBool::new(true),
+        };
+        assert!(uplink.shares("home/room/pc/keys/press", Origin::Local));
+        assert!(!uplink.shares("home/room/pc/keys/press", Origin::Upstream));
+        assert!(!uplink.shares("laptop/only", Origin::Local));
+    }
+
+    #[test]
+    fn a_taken_port_fails_at_start() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = EmbeddedConfig {
+            listen: taken.local_addr().unwrap(),
+            ..local()
+        };
+        assert!(EmbeddedBroker::start(&config, None, quiet()).is_err());
+    }
+}
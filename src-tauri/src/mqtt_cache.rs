@@ -0,0 +1,214 @@
+use crate::mqtt_topics::filter_matches;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// New topics stop being cached past this many; known ones keep updating.
+const MAX_TOPICS: usize = 10_000;
+
+/// Which inbound messages `mqtt.cache` keeps.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CachePolicy {
+    Off,
+    /// Topics the broker delivered a retained value for. Later live messages
+    /// on those topics keep the entry current.
+    #[default]
+    Retained,
+    /// The last message on every topic.
+    All,
+}
+
+impl CachePolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "off" => Ok(Self::Off),
+            "retained" => Ok(Self::Retained),
+            "all" => Ok(Self::All),
+            other => Err(format!(
+                "unknown cache mode '{other}' (retained, all or off)"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedValue {
+    pub payload: Vec<u8>,
+    /// The broker holds a retained value for the topic.
+    pub retained: bool,
+    /// `payload` is that retained value, the one the broker would send a new
+    /// subscriber; not a live message that came after it.
+    pub broker_copy: bool,
+    pub received: SystemTime,
+}
+
+/// Last value per topic, for the UI and for subscribers that arrive after
+/// the broker has already sent its retained messages (a respawned child).
+#[derive(Debug, Default)]
+pub struct TopicCache {
+    policy: CachePolicy,
+    values: HashMap<String, CachedValue>,
+}
+
+impl TopicCache {
+    pub fn new(policy: CachePolicy) -> Self {
+        Self {
+            policy,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Switch modes in place. Entries the new mode wouldn't have taken in are
+    /// dropped.
+    pub fn set_policy(&mut self, policy: CachePolicy) {
+        self.policy = policy;
+        match policy {
+            CachePolicy::Off => self.values.clear(),
+            CachePolicy::Retained => self.values.retain(|_, value| value.retained),
+            CachePolicy::All => {}
+        }
+    }
+
+    pub fn update(&mut self, topic: &str, payload: &[u8], retain: bool, now: SystemTime) {
+        if self.policy == CachePolicy::Off {
+            return;
+        }
+        // An empty retained message clears the topic on the broker.
+        if retain && payload.is_empty() {
+            self.values.remove(topic);
+            return;
+        }
+        let retained = match self.values.get(topic) {
+            Some(known) => retain || known.retained,
+            None if retain || self.policy == CachePolicy::All => retain,
+            None => return,
+        };
+        if !self.values.contains_key(topic) && self.values.len() >= MAX_TOPICS {
+            return;
+        }
+        self.values.insert(
+            topic.to_string(),
+            CachedValue {
+                payload: payload.to_vec(),
+                retained,
+                broker_copy: retain,
+                received: now,
+            },
+        );
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&CachedValue> {
+        self.values.get(topic)
+    }
+
+    /// Cached topics, sorted, optionally narrowed by a topic filter.
+    pub fn topics(&self, filter: Option<&str>) -> Vec<String> {
+        let mut topics: Vec<_> = self
+            .values
+            .keys()
+            .filter(|topic| filter.is_none_or(|f| filter_matches(f, topic)))
+            .cloned()
+            .collect();
+        topics.sort();
+        topics
+    }
+
+    /// Topic and payload of every broker copy under `filter`, for a replay:
+    /// what the broker itself would resend as retained, nothing more.
+    pub fn retained_matching(&self, filter: &str) -> Vec<(String, Vec<u8>)> {
+        self.topics(Some(filter))
+            .into_iter()
+            .filter(|topic| self.values[topic].broker_copy)
+            .map(|topic| {
+                let payload = self.values[&topic].payload.clone();
+                (topic, payload)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(cache: &TopicCache, topic: &str) -> Option<Vec<u8>> {
+        cache.get(topic).map(|v| v.payload.clone())
+    }
+
+    #[test]
+    fn retained_mode_follows_topics_that_had_a_retained_value() {
+        let now = SystemTime::now();
+        let mut cache = TopicCache::new(CachePolicy::Retained);
+        cache.update("pc/status", b"online", true, now);
+        cache.update("pc/cmd", b"notepad", false, now);
+        assert_eq!(cache.topics(None), vec!["pc/status"]);
+
+        // A live update on a retained topic keeps the entry current, but it
+        // is no longer what the broker would resend.
+        cache.update("pc/status", b"offline", false, now);
+        assert_eq!(payload(&cache, "pc/status"), Some(b"offline".to_vec()));
+        assert!(cache.get("pc/status").unwrap().retained);
+        assert!(!cache.get("pc/status").unwrap().broker_copy);
+    }
+
+    #[test]
+    fn empty_retained_message_clears_the_topic() {
+        let now = SystemTime::now();
+        let mut cache = TopicCache::new(CachePolicy::All);
+        cache.update("pc/volume", b"40", true, now);
+        cache.update("pc/volume", b"", true, now);
+        assert_eq!(cache.get("pc/volume"), None);
+    }
+
+    #[test]
+    fn all_mode_keeps_live_messages_too() {
+        let now = SystemTime::now();
+        let mut cache = TopicCache::new(CachePolicy::All);
+        cache.update("pc/cmd", b"notepad", false, now);
+        let value = cache.get("pc/cmd").unwrap();
+        assert_eq!(value.payload, b"notepad");
+        assert!(!value.retained);
+    }
+
+    #[test]
+    fn off_caches_nothing() {
+        let mut cache = TopicCache::new(CachePolicy::Off);
+        cache.update("pc/status", b"online", true, SystemTime::now());
+        assert!(cache.topics(None).is_empty());
+    }
+
+    #[test]
+    fn replays_narrow_by_filter_and_skip_live_messages() {
+        let now = SystemTime::now();
+        let mut cache = TopicCache::new(CachePolicy::All);
+        cache.update("pc/sysstats/ram", b"50", true, now);
+        cache.update("pc/sysstats/cpu", b"10", true, now);
+        cache.update("pc/sysstats/disk", b"70", false, now);
+        cache.update("pc/status", b"online", true, now);
+        assert_eq!(
+            cache.retained_matching("pc/sysstats/+"),
+            vec![
+                ("pc/sysstats/cpu".to_string(), b"10".to_vec()),
+                ("pc/sysstats/ram".to_string(), b"50".to_vec()),
+            ]
+        );
+        assert_eq!(cache.topics(Some("pc/#")).len(), 4);
+    }
+
+    #[test]
+    fn narrowing_the_mode_drops_what_it_wouldnt_keep() {
+        let now = SystemTime::now();
+        let mut cache = TopicCache::new(CachePolicy::All);
+        cache.update("pc/status", b"online", true, now);
+        cache.update("pc/cmd", b"notepad", false, now);
+        cache.set_policy(CachePolicy::Retained);
+        assert_eq!(cache.topics(None), vec!["pc/status"]);
+        cache.set_policy(CachePolicy::Off);
+        assert!(cache.topics(None).is_empty());
+    }
+
+    #[test]
+    fn policy_names() {
+        assert_eq!(CachePolicy::parse("all"), Ok(CachePolicy::All));
+        assert!(CachePolicy::parse("everything").is_err());
+    }
+}
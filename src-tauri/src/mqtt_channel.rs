@@ -0,0 +1,459 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Events that may be held back or thrown away when the reader falls behind.
+/// Everything else (connection changes, errors, publish results) is rare and
+/// always goes through, past the capacity.
+pub trait Droppable {
+    /// `Some(topic)` for an inbound message.
+    fn topic(&self) -> Option<&str>;
+}
+
+/// What happens to an inbound message when `capacity` of them are already
+/// waiting for Node.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// Replace the waiting message on the same topic, so Node gets the
+    /// latest value of each; with none waiting, drop the oldest.
+    #[default]
+    Coalesce,
+    DropOldest,
+    /// Wait for room, but never longer than `block_limit`: the event loop
+    /// waits with it and stops answering the broker's pings meanwhile. After
+    /// that the oldest is dropped.
+    Block,
+}
+
+impl Overflow {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "coalesce" => Ok(Self::Coalesce),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+            other => Err(format!(
+                "unknown overflow policy '{other}' (coalesce, drop-oldest or block)"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelConfig {
+    /// Inbound messages held for the reader.
+    pub capacity: usize,
+    pub overflow: Overflow,
+    /// How long `Overflow::Block` may hold a sender; `None` waits for good.
+    pub block_limit: Option<Duration>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 512,
+            overflow: Overflow::default(),
+            block_limit: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// How a message went in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Queued,
+    /// Something was coalesced or dropped to make room, and it's the first
+    /// time since the queue was last empty: worth a log line.
+    Overflowed,
+    /// Coalesced or dropped again in the same episode.
+    StillOverflowing,
+}
+
+/// The receiver is gone.
+#[derive(Debug, PartialEq)]
+pub struct Closed;
+
+/// Part of the bridge status: how Node is keeping up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ChannelStats {
+    /// Messages waiting for Node now.
+    pub queued: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// Droppable items in `items`.
+    messages: usize,
+    overflowing: bool,
+    receiver_gone: bool,
+    dropped: u64,
+    coalesced: u64,
+}
+
+struct Shared<T> {
+    config: ChannelConfig,
+    state: Mutex<State<T>>,
+    senders: AtomicUsize,
+    ready: Notify,
+    room: Notify,
+}
+
+/// An mpsc queue between the event loop and the writer to Node that never
+/// makes the sender wait on a slow reader (except, briefly, under
+/// `Overflow::Block`).
+pub fn channel<T: Droppable>(config: ChannelConfig) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            messages: 0,
+            overflowing: false,
+            receiver_gone: false,
+            dropped: 0,
+            coalesced: 0,
+        }),
+        senders: AtomicUsize::new(1),
+        ready: Notify::new(),
+        room: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Droppable> Sender<T> {
+    pub async fn send(&self, mut item: T) -> Result<Delivery, Closed> {
+        let deadline = self
+            .shared
+            .config
+            .block_limit
+            .map(|limit| Instant::now() + limit);
+        loop {
+            let room = self.shared.room.notified();
+            match self.push(item, false) {
+                Ok(delivery) => return delivery,
+                Err(back) => {
+                    let waited = match deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline, room).await.is_ok(),
+                        None => {
+                            room.await;
+                            true
+                        }
+                    };
+                    if !waited {
+                        return self.push(back, true).unwrap_or(Err(Closed));
+                    }
+                    item = back;
+                }
+            }
+        }
+    }
+
+    /// Like `send`, but `Overflow::Block` drops the oldest instead of
+    /// waiting.
+    pub fn try_send(&self, item: T) -> Result<Delivery, Closed> {
+        self.push(item, true).unwrap_or(Err(Closed))
+    }
+
+    /// `Err(item)` when the item has to wait for room.
+    fn push(&self, item: T, no_wait: bool) -> Result<Result<Delivery, Closed>, T> {
+        let config = &self.shared.config;
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receiver_gone {
+            return Ok(Err(Closed));
+        }
+        let mut delivery = Delivery::Queued;
+        if item.topic().is_some() && state.messages >= config.capacity {
+            if config.overflow == Overflow::Block && !no_wait {
+                return Err(item);
+            }
+            delivery = if state.overflowing {
+                Delivery::StillOverflowing
+            } else {
+                Delivery::Overflowed
+            };
+            state.overflowing = true;
+            if config.overflow == Overflow::Coalesce {
+                let topic = item.topic();
+                if let Some(waiting) = state
+                    .items
+                    .iter_mut()
+                    .find(|waiting| waiting.topic() == topic)
+                {
+                    *waiting = item;
+                    state.coalesced += 1;
+                    return Ok(Ok(delivery));
+                }
+            }
+            if let Some(oldest) = state
+                .items
+                .iter()
+                .position(|waiting| waiting.topic().is_some())
+            {
+                state.items.remove(oldest);
+                state.messages -= 1;
+                state.dropped += 1;
+            }
+        }
+        if item.topic().is_some() {
+            state.messages += 1;
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(Ok(delivery))
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        let state = self.shared.state.lock().unwrap();
+        ChannelStats {
+            queued: state.messages,
+            dropped: state.dropped,
+            coalesced: state.coalesced,
+        }
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.shared.config
+    }
+
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+/// Doesn't keep the channel open: `upgrade` fails once every `Sender` is
+/// gone.
+pub struct WeakSender<T> {
+    shared: Weak<Shared<T>>,
+}
+
+impl<T> WeakSender<T> {
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let shared = self.shared.upgrade()?;
+        shared
+            .senders
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n > 0).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Sender { shared })
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Droppable> Receiver<T> {
+    /// The next event, in order; `None` once every sender is gone and the
+    /// queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let ready = self.shared.ready.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    if item.topic().is_some() {
+                        state.messages -= 1;
+                        self.shared.room.notify_one();
+                    }
+                    if state.items.is_empty() {
+                        state.overflowing = false;
+                    }
+                    return Some(item);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            ready.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_gone = true;
+        state.items.clear();
+        drop(state);
+        self.shared.room.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Message(&'static str, u32),
+        Connected,
+    }
+
+    impl Droppable for Event {
+        fn topic(&self) -> Option<&str> {
+            match self {
+                Event::Message(topic, _) => Some(topic),
+                Event::Connected => None,
+            }
+        }
+    }
+
+    fn config(overflow: Overflow) -> ChannelConfig {
+        ChannelConfig {
+            capacity: 2,
+            overflow,
+            block_limit: Some(Duration::from_millis(50)),
+        }
+    }
+
+    async fn drain(rx: &mut Receiver<Event>) -> Vec<Event> {
+        let mut events = Vec::new();
+        while !rx.shared.state.lock().unwrap().items.is_empty() {
+            events.push(rx.recv().await.unwrap());
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn coalesce_keeps_the_latest_per_topic() {
+        let (tx, mut rx) = channel(config(Overflow::Coalesce));
+        tx.send(Event::Message("a", 1)).await.unwrap();
+        tx.send(Event::Message("b", 1)).await.unwrap();
+        assert_eq!(
+            tx.send(Event::Message("a", 2)).await,
+            Ok(Delivery::Overflowed)
+        );
+        assert_eq!(
+            tx.send(Event::Message("c", 1)).await,
+            Ok(Delivery::StillOverflowing)
+        );
+        assert_eq!(
+            drain(&mut rx).await,
+            vec![Event::Message("b", 1), Event::Message("c", 1)]
+        );
+        let stats = tx.stats();
+        assert_eq!((stats.coalesced, stats.dropped), (1, 1));
+
+        // An empty queue ends the episode.
+        tx.send(Event::Message("a", 3)).await.unwrap();
+        tx.send(Event::Message("a", 4)).await.unwrap();
+        assert_eq!(
+            tx.send(Event::Message("a", 5)).await,
+            Ok(Delivery::Overflowed)
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_never_drops_control_events() {
+        let (tx, mut rx) = channel(config(Overflow::DropOldest));
+        tx.send(Event::Connected).await.unwrap();
+        for n in 1..=4 {
+            tx.send(Event::Message("a", n)).await.unwrap();
+        }
+        tx.send(Event::Connected).await.unwrap();
+        assert_eq!(
+            drain(&mut rx).await,
+            vec![
+                Event::Connected,
+                Event::Message("a", 3),
+                Event::Message("a", 4),
+                Event::Connected,
+            ]
+        );
+        assert_eq!(tx.stats().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (tx, mut rx) = channel(ChannelConfig {
+            block_limit: None,
+            ..config(Overflow::Block)
+        });
+        tx.send(Event::Message("a", 1)).await.unwrap();
+        tx.send(Event::Message("a", 2)).await.unwrap();
+
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let first = rx.recv().await;
+            (first, rx)
+        });
+        assert_eq!(tx.send(Event::Message("a", 3)).await, Ok(Delivery::Queued));
+        let (first, mut rx) = reader.await.unwrap();
+        assert_eq!(first, Some(Event::Message("a", 1)));
+        assert_eq!(
+            drain(&mut rx).await,
+            vec![Event::Message("a", 2), Event::Message("a", 3)]
+        );
+        assert_eq!(tx.stats().dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn block_gives_up_after_the_limit() {
+        let (tx, mut rx) = channel(config(Overflow::Block));
+        for n in 1..=3 {
+            tx.send(Event::Message("a", n)).await.unwrap();
+        }
+        assert_eq!(
+            drain(&mut rx).await,
+            vec![Event::Message("a", 2), Event::Message("a", 3)]
+        );
+        assert_eq!(tx.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn closes_with_the_last_sender() {
+        let (tx, mut rx) = channel(ChannelConfig::default());
+        let weak = tx.downgrade();
+        let second = weak.upgrade().unwrap();
+        drop(tx);
+        second.send(Event::Connected).await.unwrap();
+        drop(second);
+        assert_eq!(rx.recv().await, Some(Event::Connected));
+        assert_eq!(rx.recv().await, None);
+        assert!(weak.upgrade().is_none());
+
+        let (tx, rx) = channel::<Event>(ChannelConfig::default());
+        drop(rx);
+        assert_eq!(tx.send(Event::Connected).await, Err(Closed));
+    }
+
+    #[test]
+    fn policy_names() {
+        assert_eq!(Overflow::parse("drop-oldest"), Ok(Overflow::DropOldest));
+        assert!(Overflow::parse("drop-newest").is_err());
+    }
+}
@@ -0,0 +1,518 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::{
+    DisconnectReasonCode, Packet as PacketV5, PublishProperties, SubscribeReasonCode,
+};
+use rumqttc::v5::mqttbytes::QoS as QoSV5;
+use rumqttc::{v5, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// `mqtt.protocol`: 4 (MQTT 3.1.1, the default) or 5. rumqttc has a client,
+/// an event loop and packet types for each; the bridge goes through the
+/// wrappers here and sees the same events either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    V311,
+    V5,
+}
+
+/// The MQTT 5 properties Node can read and set on a message. Always empty
+/// on 3.1.1. Named as in mqtt.js `packet.properties`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageProperties {
+    /// In order, repeats allowed, as on the wire.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    /// Bytes, so base64 over IPC and in the outbox file.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "to_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+fn to_base64<S: Serializer>(data: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match data {
+        Some(data) => serializer.serialize_str(&BASE64.encode(data)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+    let Some(text) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    BASE64
+        .decode(text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl MessageProperties {
+    /// `None` when the message had nothing worth passing on.
+    fn from_v5(properties: PublishProperties) -> Option<Self> {
+        let properties = Self {
+            user_properties: properties.user_properties,
+            content_type: properties.content_type,
+            message_expiry_interval: properties.message_expiry_interval,
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data.map(|data| data.to_vec()),
+        };
+        (properties != Self::default()).then_some(properties)
+    }
+
+    fn to_v5(&self) -> PublishProperties {
+        PublishProperties {
+            user_properties: self.user_properties.clone(),
+            content_type: self.content_type.clone(),
+            message_expiry_interval: self.message_expiry_interval,
+            response_topic: self.response_topic.clone(),
+            correlation_data: self.correlation_data.clone().map(Bytes::from),
+            ..PublishProperties::default()
+        }
+    }
+}
+
+pub fn qos_v5(qos: QoS) -> QoSV5 {
+    match qos {
+        QoS::AtMostOnce => QoSV5::AtMostOnce,
+        QoS::AtLeastOnce => QoSV5::AtLeastOnce,
+        QoS::ExactlyOnce => QoSV5::ExactlyOnce,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ClientOptions {
+    V311(Box<MqttOptions>),
+    V5(Box<v5::MqttOptions>),
+}
+
+impl ClientOptions {
+    pub fn client_id(&self) -> String {
+        match self {
+            ClientOptions::V311(options) => options.client_id(),
+            ClientOptions::V5(options) => options.client_id(),
+        }
+    }
+}
+
+/// A client call that failed: the event loop is gone.
+#[derive(Debug)]
+pub struct ClientError(String);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<rumqttc::ClientError> for ClientError {
+    fn from(error: rumqttc::ClientError) -> Self {
+        Self(error.to_string())
+    }
+}
+
+impl From<v5::ClientError> for ClientError {
+    fn from(error: v5::ClientError) -> Self {
+        Self(error.to_string())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    pub fn new(options: ClientOptions, cap: usize) -> (Self, EventLoop) {
+        match options {
+            ClientOptions::V311(options) => {
+                let (client, event_loop) = rumqttc::AsyncClient::new(*options, cap);
+                (Client::V311(client), EventLoop::V311(Box::new(event_loop)))
+            }
+            ClientOptions::V5(options) => {
+                let (client, event_loop) = v5::AsyncClient::new(*options, cap);
+                (Client::V5(client), EventLoop::V5(Box::new(event_loop)))
+            }
+        }
+    }
+
+    /// `properties` are dropped on 3.1.1, which has nowhere to put them.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: Option<&MessageProperties>,
+    ) -> Result<(), ClientError> {
+        match (self, properties) {
+            (Client::V311(client), _) => Ok(client.publish(topic, qos, retain, payload).await?),
+            (Client::V5(client), Some(properties)) => Ok(client
+                .publish_with_properties(topic, qos_v5(qos), retain, payload, properties.to_v5())
+                .await?),
+            (Client::V5(client), None) => {
+                Ok(client.publish(topic, qos_v5(qos), retain, payload).await?)
+            }
+        }
+    }
+
+    /// Like `publish`, but fails instead of waiting for room in the channel.
+    pub fn try_publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: Option<&MessageProperties>,
+    ) -> Result<(), ClientError> {
+        match (self, properties) {
+            (Client::V311(client), _) => Ok(client.try_publish(topic, qos, retain, payload)?),
+            (Client::V5(client), Some(properties)) => Ok(client.try_publish_with_properties(
+                topic,
+                qos_v5(qos),
+                retain,
+                payload,
+                properties.to_v5(),
+            )?),
+            (Client::V5(client), None) => {
+                Ok(client.try_publish(topic, qos_v5(qos), retain, payload)?)
+            }
+        }
+    }
+
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+        match self {
+            Client::V311(client) => Ok(client.subscribe(topic, qos).await?),
+            Client::V5(client) => Ok(client.subscribe(topic, qos_v5(qos)).await?),
+        }
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), ClientError> {
+        match self {
+            Client::V311(client) => Ok(client.unsubscribe(topic).await?),
+            Client::V5(client) => Ok(client.unsubscribe(topic).await?),
+        }
+    }
+
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            Client::V311(client) => Ok(client.disconnect().await?),
+            Client::V5(client) => Ok(client.disconnect().await?),
+        }
+    }
+
+    pub fn try_disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            Client::V311(client) => Ok(client.try_disconnect()?),
+            Client::V5(client) => Ok(client.try_disconnect()?),
+        }
+    }
+}
+
+/// A PUBLISH from the broker.
+#[derive(Debug)]
+pub struct Received {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+    pub properties: Option<MessageProperties>,
+}
+
+/// What the bridge acts on from either protocol's event loop.
+#[derive(Debug)]
+pub enum Polled {
+    ConnAck {
+        session_present: bool,
+    },
+    Publish(Received),
+    /// Our PUBLISH went out with this packet id.
+    PublishSent(u16),
+    /// PubAck or PubComp: the broker is done with our publish.
+    PublishAcked(u16),
+    SubscribeSent(u16),
+    /// One filter per SUBSCRIBE, so one verdict. MQTT 5 refusals don't get
+    /// here, see `ConnectionError::refused_subscription`.
+    SubAck {
+        pkid: u16,
+        refused: Option<String>,
+    },
+    DisconnectSent,
+    Other,
+}
+
+/// `Debug` of a v5 reason code plus the broker's reason string, if any.
+fn reason(code: impl fmt::Debug, text: Option<&String>) -> String {
+    match text {
+        Some(text) => format!("{code:?}: {text}"),
+        None => format!("{code:?}"),
+    }
+}
+
+impl Polled {
+    fn from_v311(event: Event) -> Self {
+        match event {
+            Event::Incoming(Packet::ConnAck(ack)) => Polled::ConnAck {
+                session_present: ack.session_present,
+            },
+            Event::Incoming(Packet::Publish(publish)) => Polled::Publish(Received {
+                topic: publish.topic,
+                payload: publish.payload.to_vec(),
+                retain: publish.retain,
+                properties: None,
+            }),
+            Event::Outgoing(Outgoing::Publish(pkid)) => Polled::PublishSent(pkid),
+            Event::Incoming(Packet::PubAck(ack)) => Polled::PublishAcked(ack.pkid),
+            Event::Incoming(Packet::PubComp(comp)) => Polled::PublishAcked(comp.pkid),
+            Event::Outgoing(Outgoing::Subscribe(pkid)) => Polled::SubscribeSent(pkid),
+            Event::Incoming(Packet::SubAck(ack)) => Polled::SubAck {
+                pkid: ack.pkid,
+                refused: ack
+                    .return_codes
+                    .contains(&rumqttc::SubscribeReasonCode::Failure)
+                    .then(|| "not authorized, or an invalid filter".to_string()),
+            },
+            Event::Outgoing(Outgoing::Disconnect) => Polled::DisconnectSent,
+            _ => Polled::Other,
+        }
+    }
+
+    fn from_v5(event: v5::Event) -> Self {
+        match event {
+            v5::Event::Incoming(PacketV5::ConnAck(ack)) => Polled::ConnAck {
+                session_present: ack.session_present,
+            },
+            v5::Event::Incoming(PacketV5::Publish(publish)) => Polled::Publish(Received {
+                topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                payload: publish.payload.to_vec(),
+                retain: publish.retain,
+                properties: publish.properties.and_then(MessageProperties::from_v5),
+            }),
+            v5::Event::Outgoing(Outgoing::Publish(pkid)) => Polled::PublishSent(pkid),
+            v5::Event::Incoming(PacketV5::PubAck(ack)) => Polled::PublishAcked(ack.pkid),
+            v5::Event::Incoming(PacketV5::PubComp(comp)) => Polled::PublishAcked(comp.pkid),
+            v5::Event::Outgoing(Outgoing::Subscribe(pkid)) => Polled::SubscribeSent(pkid),
+            v5::Event::Incoming(PacketV5::SubAck(ack)) => Polled::SubAck {
+                pkid: ack.pkid,
+                refused: ack
+                    .return_codes
+                    .iter()
+                    .find(|code| !matches!(code, SubscribeReasonCode::Success(_)))
+                    .map(|code| {
+                        let text = ack
+                            .properties
+                            .as_ref()
+                            .and_then(|p| p.reason_string.as_ref());
+                        reason(code, text)
+                    }),
+            },
+            v5::Event::Outgoing(Outgoing::Disconnect) => Polled::DisconnectSent,
+            _ => Polled::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    V311(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl ConnectionError {
+    /// rumqttc ends an MQTT 5 connection when a SubAck carries a failure
+    /// code, instead of handing the SubAck over; this is its reason code.
+    /// The packet id is lost with it.
+    pub fn refused_subscription(&self) -> Option<String> {
+        match self {
+            ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::SubFail {
+                reason,
+            })) => Some(format!("{reason:?}")),
+            _ => None,
+        }
+    }
+
+    /// An MQTT 5 broker closed the connection because another client
+    /// connected with the same client id.
+    pub fn session_taken_over(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::V5(v5::ConnectionError::MqttState(
+                v5::StateError::ServerDisconnect {
+                    reason_code: DisconnectReasonCode::SessionTakenOver,
+                    ..
+                }
+            ))
+        )
+    }
+
+    /// rumqttc's own messages for these are terse ("TLS: I/O: ..."); say
+    /// which stage failed so a bad certificate doesn't read like a network
+    /// outage.
+    pub fn describe(&self) -> String {
+        match self {
+            ConnectionError::V311(rumqttc::ConnectionError::Tls(e)) => {
+                format!("TLS handshake failed: {e}")
+            }
+            ConnectionError::V311(rumqttc::ConnectionError::ConnectionRefused(code)) => {
+                format!("broker refused the connection ({code:?})")
+            }
+            ConnectionError::V5(v5::ConnectionError::Tls(e)) => {
+                format!("TLS handshake failed: {e}")
+            }
+            ConnectionError::V5(v5::ConnectionError::ConnectionRefused(code)) => {
+                format!("broker refused the connection ({code:?})")
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V311(e) => e.fmt(f),
+            // MQTT 5 brokers say why they hang up.
+            ConnectionError::V5(v5::ConnectionError::MqttState(
+                v5::StateError::ServerDisconnect {
+                    reason_code,
+                    reason_string,
+                },
+            )) => {
+                let text = reason(reason_code, reason_string.as_ref());
+                write!(f, "broker closed the connection ({text})")
+            }
+            ConnectionError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+pub enum EventLoop {
+    V311(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> Result<Polled, ConnectionError> {
+        match self {
+            EventLoop::V311(event_loop) => event_loop
+                .poll()
+                .await
+                .map(Polled::from_v311)
+                .map_err(ConnectionError::V311),
+            EventLoop::V5(event_loop) => event_loop
+                .poll()
+                .await
+                .map(Polled::from_v5)
+                .map_err(ConnectionError::V5),
+        }
+    }
+
+    /// Dial somewhere else on the next poll. Every broker of a bridge speaks
+    /// the same protocol, so the variants always match.
+    pub fn set_options(&mut self, options: ClientOptions) {
+        match (self, options) {
+            (EventLoop::V311(event_loop), ClientOptions::V311(options)) => {
+                event_loop.mqtt_options = *options;
+            }
+            (EventLoop::V5(event_loop), ClientOptions::V5(options)) => {
+                event_loop.options = *options;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clean(&mut self) {
+        match self {
+            EventLoop::V311(event_loop) => event_loop.clean(),
+            EventLoop::V5(event_loop) => event_loop.clean(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_cross_ipc_with_mqtt_js_names() {
+        let properties = MessageProperties {
+            user_properties: vec![("source".into(), "ha".into())],
+            content_type: Some("application/json".into()),
+            message_expiry_interval: Some(60),
+            response_topic: Some("ha/reply".into()),
+            correlation_data: Some(vec![0, 1, 0xff]),
+        };
+        let json = serde_json::to_value(&properties).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "userProperties": [["source", "ha"]],
+                "contentType": "application/json",
+                "messageExpiryInterval": 60,
+                "responseTopic": "ha/reply",
+                "correlationData": "AAH/",
+            })
+        );
+        let back: MessageProperties = serde_json::from_value(json).unwrap();
+        assert_eq!(back, properties);
+        assert_eq!(
+            serde_json::to_string(&MessageProperties::default()).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn v5_properties_round_trip() {
+        let properties = MessageProperties {
+            correlation_data: Some(b"req-7".to_vec()),
+            response_topic: Some("tools/reply".into()),
+            ..MessageProperties::default()
+        };
+        let wire = properties.to_v5();
+        assert_eq!(wire.correlation_data.as_deref(), Some(&b"req-7"[..]));
+        assert_eq!(MessageProperties::from_v5(wire), Some(properties));
+        assert_eq!(
+            MessageProperties::from_v5(PublishProperties::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn refusals_carry_the_reason_code() {
+        let refused =
+            ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::SubFail {
+                reason: SubscribeReasonCode::NotAuthorized,
+            }));
+        assert_eq!(
+            refused.refused_subscription().as_deref(),
+            Some("NotAuthorized")
+        );
+
+        let kicked = ConnectionError::V5(v5::ConnectionError::MqttState(
+            v5::StateError::ServerDisconnect {
+                reason_code: DisconnectReasonCode::SessionTakenOver,
+                reason_string: Some("another client connected".into()),
+            },
+        ));
+        assert_eq!(
+            kicked.to_string(),
+            "broker closed the connection (SessionTakenOver: another client connected)"
+        );
+        assert_eq!(kicked.refused_subscription(), None);
+        assert!(kicked.session_taken_over());
+        assert!(!refused.session_taken_over());
+    }
+}
@@ -0,0 +1,335 @@
+use crate::mqtt_bridge::Availability;
+use serde_json::{json, Map, Value};
+
+/// Every entity this app can announce, as (component, object id). Entities
+/// missing from a run get an empty retained config, which is how Home
+/// Assistant is told to remove them: a disabled module's entities go away
+/// even when the app restarts in between.
+const CATALOG: &[(&str, &str)] = &[
+    ("binary_sensor", "connected"),
+    ("button", "place_windows"),
+    ("button", "store_windows"),
+    ("button", "restore_windows"),
+    ("button", "clear_windows"),
+    ("button", "reload_windows"),
+    ("button", "sleep"),
+    ("button", "restart"),
+    ("button", "shutdown"),
+    ("button", "restart_restore"),
+    ("number", "volume"),
+    ("switch", "mute"),
+    ("sensor", "playback_device"),
+    ("sensor", "recording_device"),
+];
+
+/// The device the entities belong to.
+pub struct DeviceInfo {
+    /// `mqtt.base` made id-safe: unique per PC, stable across restarts.
+    pub node_id: String,
+    pub name: String,
+    pub version: String,
+}
+
+/// Topics of the audio module, its base already applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioTopics {
+    pub volume_set: String,
+    pub volume_stat: String,
+    pub mute_set: String,
+    pub mute_stat: String,
+    /// Recording and playback device topics; `None` with `device: false`.
+    pub devices: Option<(String, String)>,
+}
+
+/// What is there to announce, from config.yml.
+pub struct Exposed {
+    /// `<mqtt.base>/windows`, where the tray's window actions are relayed.
+    pub windows_base: String,
+    /// The power module's base, when it is enabled.
+    pub power_base: Option<String>,
+    pub audio: Option<AudioTopics>,
+}
+
+/// `home/room/pc` -> `home_room_pc`.
+pub fn node_id(base: &str) -> String {
+    base.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+struct Entity {
+    component: &'static str,
+    object_id: &'static str,
+    config: Value,
+}
+
+fn button(object_id: &'static str, name: &str, topic: String) -> Entity {
+    Entity {
+        component: "button",
+        object_id,
+        config: json!({ "name": name, "command_topic": topic, "payload_press": "1" }),
+    }
+}
+
+fn entities(exposed: &Exposed, availability: Option<&Availability>) -> Vec<Entity> {
+    let mut entities = Vec::new();
+    if let Some(availability) = availability {
+        entities.push(Entity {
+            component: "binary_sensor",
+            object_id: "connected",
+            config: json!({
+                "name": "Connected",
+                "device_class": "connectivity",
+                "entity_category": "diagnostic",
+                "state_topic": availability.topic,
+                "payload_on": availability.online,
+                "payload_off": availability.offline,
+            }),
+        });
+    }
+
+    let windows = &exposed.windows_base;
+    entities.extend([
+        button(
+            "place_windows",
+            "Place windows",
+            format!("{windows}/autoplace"),
+        ),
+        button("store_windows", "Store windows", format!("{windows}/store")),
+        button(
+            "restore_windows",
+            "Restore windows",
+            format!("{windows}/restore"),
+        ),
+        button(
+            "clear_windows",
+            "Clear stored windows",
+            format!("{windows}/clear"),
+        ),
+        button(
+            "reload_windows",
+            "Reload configs",
+            format!("{windows}/reload"),
+        ),
+    ]);
+
+    if let Some(power) = &exposed.power_base {
+        let mut restart = button("restart", "Restart", format!("{power}/restart"));
+        restart.config["device_class"] = json!("restart");
+        entities.extend([
+            button("sleep", "Sleep", format!("{power}/sleep")),
+            restart,
+            button("shutdown", "Shutdown", format!("{power}/shutdown")),
+            button(
+                "restart_restore",
+                "Restart with restore",
+                format!("{power}/restart_restore"),
+            ),
+        ]);
+    }
+
+    if let Some(audio) = &exposed.audio {
+        entities.push(Entity {
+            component: "number",
+            object_id: "volume",
+            config: json!({
+                "name": "Volume",
+                "command_topic": audio.volume_set,
+                "state_topic": audio.volume_stat,
+                "min": 0,
+                "max": 100,
+                "step": 1,
+                "unit_of_measurement": "%",
+                "icon": "mdi:volume-high",
+            }),
+        });
+        entities.push(Entity {
+            component: "switch",
+            object_id: "mute",
+            config: json!({
+                "name": "Mute",
+                "command_topic": audio.mute_set,
+                "state_topic": audio.mute_stat,
+                "payload_on": "1",
+                "payload_off": "0",
+                "icon": "mdi:volume-off",
+            }),
+        });
+        if let Some((recording, playback)) = &audio.devices {
+            entities.push(Entity {
+                component: "sensor",
+                object_id: "playback_device",
+                config: json!({
+                    "name": "Playback device",
+                    "state_topic": playback,
+                    "icon": "mdi:speaker",
+                }),
+            });
+            entities.push(Entity {
+                component: "sensor",
+                object_id: "recording_device",
+                config: json!({
+                    "name": "Recording device",
+                    "state_topic": recording,
+                    "icon": "mdi:microphone",
+                }),
+            });
+        }
+    }
+    entities
+}
+
+/// Retained messages to publish, in order: a config document for every
+/// entity there is, an empty payload for every one there isn't.
+pub fn messages(
+    prefix: &str,
+    device: &DeviceInfo,
+    availability: Option<&Availability>,
+    exposed: &Exposed,
+) -> Vec<(String, Vec<u8>)> {
+    let node = &device.node_id;
+    let topic = |component: &str, object_id: &str| {
+        format!("{prefix}/{component}/{node}/{object_id}/config")
+    };
+    let present = entities(exposed, availability);
+    let mut messages = Vec::new();
+    for entity in &present {
+        let mut config: Map<String, Value> = match &entity.config {
+            Value::Object(config) => config.clone(),
+            _ => Map::new(),
+        };
+        config.insert(
+            "unique_id".into(),
+            json!(format!("{node}_{}", entity.object_id)),
+        );
+        config.insert(
+            "device".into(),
+            json!({
+                "identifiers": [node],
+                "name": device.name,
+                "model": "windows-mqtt",
+                "sw_version": device.version,
+            }),
+        );
+        config.insert(
+            "origin".into(),
+            json!({ "name": "windows-mqtt", "sw_version": device.version }),
+        );
+        if let Some(availability) = availability {
+            config.insert("availability_topic".into(), json!(availability.topic));
+            config.insert("payload_available".into(), json!(availability.online));
+            config.insert("payload_not_available".into(), json!(availability.offline));
+        }
+        let payload = serde_json::to_vec(&config).unwrap_or_default();
+        messages.push((topic(entity.component, entity.object_id), payload));
+    }
+    for (component, object_id) in CATALOG {
+        let shown = present
+            .iter()
+            .any(|e| e.component == *component && e.object_id == *object_id);
+        if !shown {
+            messages.push((topic(component, object_id), Vec::new()));
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            node_id: node_id("home/room/pc"),
+            name: "DESKTOP-1".into(),
+            version: "1.2.0".into(),
+        }
+    }
+
+    fn availability() -> Availability {
+        Availability {
+            topic: "home/room/pc/status".into(),
+            online: "online".into(),
+            offline: "offline".into(),
+        }
+    }
+
+    fn audio() -> AudioTopics {
+        AudioTopics {
+            volume_set: "home/room/pc/audio/volume/set".into(),
+            volume_stat: "home/room/pc/audio/volume".into(),
+            mute_set: "home/room/pc/audio/mute/set".into(),
+            mute_stat: "home/room/pc/audio/mute".into(),
+            devices: Some((
+                "home/room/pc/audio/device".into(),
+                "home/room/pc/audio/device/playback".into(),
+            )),
+        }
+    }
+
+    fn config_of(messages: &[(String, Vec<u8>)], topic: &str) -> Option<Value> {
+        let (_, payload) = messages.iter().find(|(t, _)| t == topic)?;
+        if payload.is_empty() {
+            return None;
+        }
+        Some(serde_json::from_slice(payload).unwrap())
+    }
+
+    #[test]
+    fn everything_enabled_announces_the_whole_catalog() {
+        let exposed = Exposed {
+            windows_base: "home/room/pc/windows".into(),
+            power_base: Some("home/room/pc/windows".into()),
+            audio: Some(audio()),
+        };
+        let avail = availability();
+        let messages = messages("homeassistant", &device(), Some(&avail), &exposed);
+        assert_eq!(messages.len(), CATALOG.len());
+        assert!(messages.iter().all(|(_, payload)| !payload.is_empty()));
+
+        let volume =
+            config_of(&messages, "homeassistant/number/home_room_pc/volume/config").unwrap();
+        assert_eq!(volume["command_topic"], "home/room/pc/audio/volume/set");
+        assert_eq!(volume["unique_id"], "home_room_pc_volume");
+        assert_eq!(volume["device"]["sw_version"], "1.2.0");
+        assert_eq!(volume["availability_topic"], "home/room/pc/status");
+
+        let restart = config_of(
+            &messages,
+            "homeassistant/button/home_room_pc/restart/config",
+        )
+        .unwrap();
+        assert_eq!(restart["command_topic"], "home/room/pc/windows/restart");
+        assert_eq!(restart["device_class"], "restart");
+    }
+
+    #[test]
+    fn disabled_modules_are_removed() {
+        let exposed = Exposed {
+            windows_base: "home/room/pc/windows".into(),
+            power_base: None,
+            audio: None,
+        };
+        let messages = messages("ha", &device(), None, &exposed);
+        assert_eq!(messages.len(), CATALOG.len());
+        let (_, payload) = messages
+            .iter()
+            .find(|(t, _)| t == "ha/switch/home_room_pc/mute/config")
+            .unwrap();
+        assert!(payload.is_empty());
+        assert!(config_of(&messages, "ha/button/home_room_pc/sleep/config").is_none());
+        assert!(config_of(&messages, "ha/binary_sensor/home_room_pc/connected/config").is_none());
+        let place = config_of(&messages, "ha/button/home_room_pc/place_windows/config").unwrap();
+        assert_eq!(place["command_topic"], "home/room/pc/windows/autoplace");
+        assert!(place.get("availability_topic").is_none());
+    }
+
+    #[test]
+    fn node_ids_are_topic_safe() {
+        assert_eq!(node_id("home/room/pc"), "home_room_pc");
+        assert_eq!(node_id("дом/pc 1"), "____pc_1");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::mqtt_topics::filter_matches;
+use std::time::{Duration, Instant};
+
+/// Past this, a `ts` is taken as milliseconds (what `Date.now()` gives):
+/// in seconds it would be the year 5138.
+const MILLIS_FROM: i64 = 100_000_000_000;
+
+/// `mqtt.stale_commands`: command topics whose messages are dropped when
+/// they have waited too long to be useful.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FreshnessConfig {
+    pub topics: Vec<String>,
+    /// Oldest accepted payload `ts`; payloads without one are not judged by
+    /// age.
+    pub max_age: Option<Duration>,
+    /// After a ConnAck with `session_present`, what arrives this soon is
+    /// taken as queued while we were away and dropped. The embedded broker
+    /// keeps no sessions (it refuses `clean_session = false`), so against it
+    /// only `max_age` applies.
+    pub session_grace: Duration,
+}
+
+/// Tells commands that were queued in the broker session, or carry an old
+/// timestamp, from ones sent just now. Only live messages are judged: the
+/// bridge never replays cached values on these topics.
+#[derive(Debug)]
+pub struct FreshnessGuard {
+    config: FreshnessConfig,
+    /// When the current connection resumed a stored session.
+    resumed_at: Option<Instant>,
+}
+
+impl FreshnessGuard {
+    pub fn new(config: FreshnessConfig) -> Self {
+        Self {
+            config,
+            resumed_at: None,
+        }
+    }
+
+    pub fn connected(&mut self, session_present: bool, now: Instant) {
+        self.resumed_at = session_present.then_some(now);
+    }
+
+    pub fn covers(&self, topic: &str) -> bool {
+        self.config
+            .topics
+            .iter()
+            .any(|filter| filter_matches(filter, topic))
+    }
+
+    /// Why a message on `topic` is too old to pass on, if it is. `unix_now`
+    /// is in seconds.
+    pub fn stale(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        now: Instant,
+        unix_now: i64,
+    ) -> Option<String> {
+        if !self.covers(topic) {
+            return None;
+        }
+        if let Some(resumed_at) = self.resumed_at {
+            let since = now.saturating_duration_since(resumed_at);
+            if since < self.config.session_grace {
+                return Some(format!(
+                    "delivered {:.1}s after resuming the session, likely queued while offline",
+                    since.as_secs_f64()
+                ));
+            }
+        }
+        let max_age = i64::try_from(self.config.max_age?.as_secs()).unwrap_or(i64::MAX);
+        let age = unix_now.saturating_sub(payload_ts(payload)?);
+        (age > max_age).then(|| format!("sent {age}s ago, more than {max_age}s"))
+    }
+}
+
+/// `ts` of a JSON object payload, in seconds. Out-of-range values saturate
+/// at the ends of `i64`, which reads as very old or from the far future.
+fn payload_ts(payload: &[u8]) -> Option<i64> {
+    let value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    let ts = value.get("ts")?.as_f64()? as i64;
+    Some(if ts >= MILLIS_FROM { ts / 1000 } else { ts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_760_000_000;
+
+    fn guard() -> FreshnessGuard {
+        FreshnessGuard::new(FreshnessConfig {
+            topics: vec!["pc/windows/shutdown".into(), "pc/exec/#".into()],
+            max_age: Some(Duration::from_secs(60)),
+            session_grace: Duration::from_secs(5),
+        })
+    }
+
+    #[test]
+    fn the_session_backlog_is_dropped() {
+        let mut guard = guard();
+        let shutdown = "pc/windows/shutdown";
+        let connected = Instant::now();
+        guard.connected(true, connected);
+        let early = connected + Duration::from_secs(1);
+        assert!(guard.stale(shutdown, b"1", early, NOW).is_some());
+        // Other topics are left alone.
+        assert!(guard.stale("pc/audio/volume", b"50", early, NOW).is_none());
+        let later = connected + Duration::from_secs(5);
+        assert!(guard.stale(shutdown, b"1", later, NOW).is_none());
+
+        // A fresh session has no backlog.
+        guard.connected(false, connected);
+        assert!(guard.stale(shutdown, b"1", early, NOW).is_none());
+    }
+
+    #[test]
+    fn old_timestamps_are_dropped() {
+        let guard = guard();
+        let stale =
+            |payload: String| guard.stale("pc/exec/cmd", payload.as_bytes(), Instant::now(), NOW);
+        assert!(stale(format!(r#"{{"payload":"1","ts":{}}}"#, NOW - 61)).is_some());
+        assert!(stale(format!(r#"{{"cmd":"notepad","ts":{}}}"#, (NOW - 10) * 1000)).is_none());
+        assert!(stale(format!(r#"{{"ts":{}}}"#, (NOW - 3600) * 1000)).is_some());
+        // No timestamp: nothing to go by.
+        assert!(stale("notepad".into()).is_none());
+        // Extreme values don't overflow: far past is stale, far future isn't.
+        assert!(stale(r#"{"ts":-1e300}"#.into()).is_some());
+        assert!(stale(format!(r#"{{"ts":{}}}"#, i64::MIN)).is_some());
+        assert!(stale(r#"{"ts":1e300}"#.into()).is_none());
+    }
+}
@@ -0,0 +1,457 @@
+use crate::mqtt_client::MessageProperties;
+use crate::mqtt_topics::filter_matches;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+/// What happens to a publish on a topic while the broker is unreachable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// Queue every message, in order (events: file changes, notifications).
+    KeepAll,
+    /// Only the newest message per topic survives (state: sysstats, volume).
+    KeepLatest,
+    /// Don't queue at all (mouse moves, anything stale by the time it'd land).
+    Drop,
+}
+
+impl QueuePolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "keep-all" => Ok(Self::KeepAll),
+            "keep-latest" => Ok(Self::KeepLatest),
+            "drop" => Ok(Self::Drop),
+            other => Err(format!(
+                "unknown queue policy '{other}' (keep-all, keep-latest or drop)"
+            )),
+        }
+    }
+}
+
+/// `mqtt.queue` from config.yml.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxConfig {
+    /// Oldest messages are dropped beyond this many.
+    pub max_messages: usize,
+    pub default_policy: QueuePolicy,
+    /// Topic filter -> policy; the first matching filter wins.
+    pub policies: Vec<(String, QueuePolicy)>,
+    /// JSONL file the queue survives restarts in. `None` keeps it in memory.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            default_policy: QueuePolicy::KeepAll,
+            policies: Vec::new(),
+            path: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    /// MQTT 5 only.
+    pub properties: Option<MessageProperties>,
+    /// IPC correlation id. Not persisted: whoever asked is gone after a
+    /// restart.
+    pub id: Option<String>,
+}
+
+/// One line of the queue file. Payloads are base64 so binary survives JSON.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    properties: Option<MessageProperties>,
+}
+
+/// Counters since the last report; reset by `take_stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutboxStats {
+    pub queued: u64,
+    pub sent: u64,
+    pub dropped_by_policy: u64,
+    pub dropped_overflow: u64,
+    /// Messages still waiting when the report was taken.
+    pub pending: usize,
+    /// `max_messages`, for the log line.
+    pub limit: usize,
+}
+
+impl OutboxStats {
+    pub fn is_empty(&self) -> bool {
+        self.queued == 0
+            && self.sent == 0
+            && self.dropped_by_policy == 0
+            && self.dropped_overflow == 0
+    }
+}
+
+/// Publishes held back while the broker is down, drained in order after
+/// ConnAck. Every change is written through to `config.path`, so a crash or
+/// a Quit during an outage doesn't lose what was queued.
+pub struct Outbox {
+    config: OutboxConfig,
+    entries: VecDeque<QueuedPublish>,
+    stats: OutboxStats,
+    /// Set after a failed write so a broken disk is reported once, not on
+    /// every publish.
+    persist_failed: bool,
+    /// Ids of messages dropped since `take_dropped_ids`, so their callers
+    /// hear about it.
+    dropped_ids: Vec<String>,
+}
+
+impl Outbox {
+    /// Open the queue, picking up whatever a previous run left behind.
+    /// Unreadable lines are skipped; the error names how many.
+    pub fn open(config: OutboxConfig) -> (Self, Option<String>) {
+        let mut warning = None;
+        let mut entries = VecDeque::new();
+        if let Some(path) = &config.path {
+            match std::fs::read_to_string(path) {
+                Ok(text) => {
+                    let mut bad = 0;
+                    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                        match serde_json::from_str::<Record>(line)
+                            .ok()
+                            .and_then(|r| Some((BASE64.decode(&r.payload).ok()?, r)))
+                        {
+                            Some((payload, r)) => entries.push_back(QueuedPublish {
+                                topic: r.topic,
+                                payload,
+                                qos: r.qos,
+                                retain: r.retain,
+                                properties: r.properties,
+                                id: None,
+                            }),
+                            None => bad += 1,
+                        }
+                    }
+                    if bad > 0 {
+                        warning = Some(format!(
+                            "MQTT outbox {}: skipped {bad} unreadable entries",
+                            path.display()
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warning = Some(format!("MQTT outbox {}: {e}", path.display()));
+                }
+            }
+        }
+        while entries.len() > config.max_messages {
+            entries.pop_front();
+        }
+        (
+            Self {
+                config,
+                entries,
+                stats: OutboxStats::default(),
+                persist_failed: false,
+                dropped_ids: Vec::new(),
+            },
+            warning,
+        )
+    }
+
+    pub fn policy_for(&self, topic: &str) -> QueuePolicy {
+        self.config
+            .policies
+            .iter()
+            .find(|(filter, _)| filter_matches(filter, topic))
+            .map_or(self.config.default_policy, |(_, policy)| *policy)
+    }
+
+    /// Queue `msg` under its topic's policy. `Err` only reports a failed
+    /// write to disk; the message is queued in memory either way.
+    pub fn push(&mut self, msg: QueuedPublish) -> Result<(), String> {
+        match self.policy_for(&msg.topic) {
+            QueuePolicy::Drop => {
+                self.stats.dropped_by_policy += 1;
+                self.dropped_ids.extend(msg.id);
+                return Ok(());
+            }
+            QueuePolicy::KeepLatest => {
+                let (replaced, kept) = std::mem::take(&mut self.entries)
+                    .into_iter()
+                    .partition::<VecDeque<_>, _>(|e| e.topic == msg.topic);
+                self.entries = kept;
+                self.stats.dropped_by_policy += replaced.len() as u64;
+                self.dropped_ids
+                    .extend(replaced.into_iter().filter_map(|e| e.id));
+            }
+            QueuePolicy::KeepAll => {}
+        }
+        self.entries.push_back(msg);
+        self.stats.queued += 1;
+        while self.entries.len() > self.config.max_messages {
+            let dropped = self.entries.pop_front();
+            self.dropped_ids.extend(dropped.and_then(|e| e.id));
+            self.stats.dropped_overflow += 1;
+        }
+        self.flush()
+    }
+
+    /// Apply a changed `mqtt.queue` to what is already queued. Policies only
+    /// affect messages pushed from now on; the size limit applies at once.
+    pub fn reconfigure(&mut self, config: OutboxConfig) -> Result<(), String> {
+        self.config = config;
+        self.persist_failed = false;
+        while self.entries.len() > self.config.max_messages {
+            let dropped = self.entries.pop_front();
+            self.dropped_ids.extend(dropped.and_then(|e| e.id));
+            self.stats.dropped_overflow += 1;
+        }
+        self.flush()
+    }
+
+    pub fn take_dropped_ids(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.dropped_ids)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn front(&self) -> Option<&QueuedPublish> {
+        self.entries.front()
+    }
+
+    /// Forget the front message once the client has taken it. Not written
+    /// through: the drain calls `flush` when it stops, and at worst a crash
+    /// mid-drain resends a few messages next time.
+    pub fn pop_sent(&mut self) {
+        if self.entries.pop_front().is_some() {
+            self.stats.sent += 1;
+        }
+    }
+
+    pub fn take_stats(&mut self) -> OutboxStats {
+        let mut stats = std::mem::take(&mut self.stats);
+        stats.pending = self.entries.len();
+        stats.limit = self.config.max_messages;
+        stats
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        let Some(path) = self.config.path.clone() else {
+            return Ok(());
+        };
+        match self.write_file(&path) {
+            Ok(()) => {
+                self.persist_failed = false;
+                Ok(())
+            }
+            Err(_) if self.persist_failed => Ok(()),
+            Err(e) => {
+                self.persist_failed = true;
+                Err(format!("MQTT outbox {}: {e}", path.display()))
+            }
+        }
+    }
+
+    fn write_file(&self, path: &Path) -> std::io::Result<()> {
+        if self.entries.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut text = String::new();
+        for e in &self.entries {
+            let record = Record {
+                topic: e.topic.clone(),
+                payload: BASE64.encode(&e.payload),
+                qos: e.qos,
+                retain: e.retain,
+                properties: e.properties.clone(),
+            };
+            text += &serde_json::to_string(&record).map_err(std::io::Error::other)?;
+            text.push('\n');
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write-then-rename: a crash mid-write leaves the previous queue.
+        let tmp = path.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(topic: &str, payload: &[u8]) -> QueuedPublish {
+        QueuedPublish {
+            topic: topic.into(),
+            payload: payload.to_vec(),
+            qos: 0,
+            retain: false,
+            properties: None,
+            id: None,
+        }
+    }
+
+    fn outbox(config: OutboxConfig) -> Outbox {
+        Outbox::open(config).0
+    }
+
+    fn drain(outbox: &mut Outbox) -> Vec<QueuedPublish> {
+        let mut sent = Vec::new();
+        while let Some(m) = outbox.front().cloned() {
+            outbox.pop_sent();
+            sent.push(m);
+        }
+        sent
+    }
+
+    #[test]
+    fn keep_all_drains_in_order() {
+        let mut ob = outbox(OutboxConfig::default());
+        ob.push(msg("pc/dirwatch", b"a")).unwrap();
+        ob.push(msg("pc/filewatch", b"b")).unwrap();
+        ob.push(msg("pc/dirwatch", b"c")).unwrap();
+        let payloads: Vec<_> = drain(&mut ob).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(ob.take_stats().sent, 3);
+    }
+
+    #[test]
+    fn policies_match_topic_filters() {
+        let mut ob = outbox(OutboxConfig {
+            policies: vec![
+                ("pc/sysstats/#".into(), QueuePolicy::KeepLatest),
+                ("pc/+/move".into(), QueuePolicy::Drop),
+            ],
+            ..Default::default()
+        });
+        ob.push(msg("pc/sysstats/cpu", b"10")).unwrap();
+        ob.push(msg("pc/sysstats/ram", b"50")).unwrap();
+        ob.push(msg("pc/mouse/move", b"1,1")).unwrap();
+        ob.push(msg("pc/sysstats/cpu", b"20")).unwrap();
+
+        let sent = drain(&mut ob);
+        assert_eq!(
+            sent,
+            vec![msg("pc/sysstats/ram", b"50"), msg("pc/sysstats/cpu", b"20")]
+        );
+        let stats = ob.take_stats();
+        assert_eq!(stats.dropped_by_policy, 2);
+        assert_eq!(stats.queued, 3);
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest() {
+        let mut ob = outbox(OutboxConfig {
+            max_messages: 2,
+            ..Default::default()
+        });
+        for p in [b"1", b"2", b"3"] {
+            ob.push(msg("t", p)).unwrap();
+        }
+        assert_eq!(ob.take_stats().dropped_overflow, 1);
+        assert_eq!(drain(&mut ob), vec![msg("t", b"2"), msg("t", b"3")]);
+    }
+
+    #[test]
+    fn dropped_messages_give_back_their_ids() {
+        let mut ob = outbox(OutboxConfig {
+            max_messages: 2,
+            policies: vec![
+                ("pc/state".into(), QueuePolicy::KeepLatest),
+                ("pc/move".into(), QueuePolicy::Drop),
+            ],
+            ..Default::default()
+        });
+        let with_id = |topic: &str, id: &str| QueuedPublish {
+            id: Some(id.into()),
+            ..msg(topic, b"")
+        };
+        ob.push(with_id("pc/move", "m")).unwrap();
+        ob.push(with_id("pc/state", "s1")).unwrap();
+        ob.push(with_id("pc/state", "s2")).unwrap();
+        ob.push(with_id("pc/event", "e1")).unwrap();
+        ob.push(with_id("pc/event", "e2")).unwrap();
+        assert_eq!(ob.take_dropped_ids(), vec!["m", "s1", "s2"]);
+        assert!(ob.take_dropped_ids().is_empty());
+    }
+
+    #[test]
+    fn reconfigure_keeps_the_queue_within_the_new_limit() {
+        let mut ob = outbox(OutboxConfig::default());
+        for p in [b"1", b"2", b"3"] {
+            ob.push(msg("t", p)).unwrap();
+        }
+        ob.reconfigure(OutboxConfig {
+            max_messages: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(ob.take_stats().dropped_overflow, 1);
+        assert_eq!(drain(&mut ob), vec![msg("t", b"2"), msg("t", b"3")]);
+    }
+
+    #[test]
+    fn queue_survives_a_restart() {
+        let path = std::env::temp_dir().join("wmqtt-outbox-restart.jsonl");
+        std::fs::remove_file(&path).ok();
+        let config = OutboxConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let mut ob = outbox(config.clone());
+        ob.push(msg("pc/shot", &[0x89, 0x50, 0x00, 0xff])).unwrap();
+        let event = QueuedPublish {
+            properties: Some(MessageProperties {
+                content_type: Some("text/plain".into()),
+                correlation_data: Some(vec![7]),
+                ..Default::default()
+            }),
+            ..msg("pc/event", b"x")
+        };
+        ob.push(event.clone()).unwrap();
+        drop(ob);
+
+        let (mut ob, warning) = Outbox::open(config.clone());
+        assert_eq!(warning, None);
+        assert_eq!(ob.take_stats().pending, 2);
+        assert_eq!(ob.front(), Some(&msg("pc/shot", &[0x89, 0x50, 0x00, 0xff])));
+
+        assert_eq!(drain(&mut ob)[1], event);
+        ob.flush().unwrap();
+        assert!(!path.exists(), "an empty queue leaves no file behind");
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped_and_reported() {
+        let path = std::env::temp_dir().join("wmqtt-outbox-corrupt.jsonl");
+        std::fs::write(
+            &path,
+            "{\"topic\":\"t\",\"payload\":\"b2s=\",\"qos\":1,\"retain\":false}\nnot json\n",
+        )
+        .unwrap();
+        let (ob, warning) = Outbox::open(OutboxConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        });
+        assert_eq!(ob.front().map(|m| &m.payload[..]), Some(&b"ok"[..]));
+        assert!(warning.unwrap().contains("skipped 1"));
+        std::fs::remove_file(path).ok();
+    }
+}
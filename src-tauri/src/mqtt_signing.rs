@@ -0,0 +1,205 @@
+use crate::mqtt_topics::filter_matches;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `mqtt.signing`: topics whose messages only reach Node with a valid
+/// signature from someone holding `secret`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningConfig {
+    pub secret: String,
+    pub topics: Vec<String>,
+    /// How far `ts` may be from our clock, either way.
+    pub max_age: Duration,
+}
+
+/// What travels on a signed topic instead of the bare payload. `sig` is the
+/// hex HMAC-SHA256 of `<topic>\n<ts>\n<payload>`, `ts` in Unix seconds.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Envelope {
+    payload: String,
+    ts: i64,
+    sig: String,
+}
+
+fn mac(secret: &str, topic: &str, ts: i64, payload: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{topic}\n{ts}\n{payload}").as_bytes());
+    mac
+}
+
+/// The message to publish on `topic` to have `payload` accepted: what
+/// `--sign` prints.
+pub fn sign(secret: &str, topic: &str, payload: &str, ts: i64) -> String {
+    let sig = mac(secret, topic, ts, payload)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let envelope = Envelope {
+        payload: payload.to_string(),
+        ts,
+        sig,
+    };
+    serde_json::to_string(&envelope).unwrap_or_default()
+}
+
+/// Seconds since the Unix epoch, the clock `ts` is read against.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Checks messages on the signed topics. A signature is accepted once:
+/// the same message sent again inside the window is a replay.
+#[derive(Debug)]
+pub struct Verifier {
+    config: SigningConfig,
+    /// Signatures accepted within the window, with their `ts`.
+    seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl Verifier {
+    pub fn new(config: SigningConfig) -> Self {
+        Self {
+            config,
+            seen: Mutex::default(),
+        }
+    }
+
+    pub fn covers(&self, topic: &str) -> bool {
+        self.config
+            .topics
+            .iter()
+            .any(|filter| filter_matches(filter, topic))
+    }
+
+    /// The payload to hand to Node, unwrapped from its envelope, or why the
+    /// message was refused. `now` is in Unix seconds.
+    pub fn verify(&self, topic: &str, payload: &[u8], now: i64) -> Result<Vec<u8>, String> {
+        let envelope: Envelope = serde_json::from_slice(payload)
+            .map_err(|_| "not signed (expected {payload, ts, sig})".to_string())?;
+        let max_age = self.config.max_age.as_secs();
+        let age = now.abs_diff(envelope.ts);
+        if age > max_age {
+            return Err(format!(
+                "signed {age}s from now, outside the {max_age}s window"
+            ));
+        }
+        let sig = from_hex(&envelope.sig).ok_or("sig is not hex")?;
+        mac(&self.config.secret, topic, envelope.ts, &envelope.payload)
+            .verify_slice(&sig)
+            .map_err(|_| "bad signature".to_string())?;
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, ts| now.abs_diff(*ts) <= max_age);
+        if seen.insert(sig, envelope.ts).is_some() {
+            return Err("signature already used".into());
+        }
+        Ok(envelope.payload.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_760_000_000;
+
+    fn verifier() -> Verifier {
+        Verifier::new(SigningConfig {
+            secret: "correct horse battery staple".into(),
+            topics: vec![
+                "home/room/pc/windows/shutdown".into(),
+                "home/room/pc/exec/#".into(),
+            ],
+            max_age: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn signed_messages_are_unwrapped() {
+        let verifier = verifier();
+        let topic = "home/room/pc/exec/cmd";
+        assert!(verifier.covers(topic));
+        assert!(!verifier.covers("home/room/pc/audio/volume"));
+        let message = sign("correct horse battery staple", topic, "notepad", NOW - 5);
+        assert_eq!(
+            verifier.verify(topic, message.as_bytes(), NOW),
+            Ok(b"notepad".to_vec())
+        );
+    }
+
+    #[test]
+    fn forgeries_are_refused() {
+        let verifier = verifier();
+        let topic = "home/room/pc/windows/shutdown";
+        assert!(verifier.verify(topic, b"1", NOW).is_err());
+
+        let wrong_key = sign("guess", topic, "1", NOW);
+        assert_eq!(
+            verifier.verify(topic, wrong_key.as_bytes(), NOW),
+            Err("bad signature".into())
+        );
+
+        // Signed for another topic.
+        let elsewhere = sign(
+            "correct horse battery staple",
+            "home/room/pc/exec/cmd",
+            "1",
+            NOW,
+        );
+        assert!(verifier.verify(topic, elsewhere.as_bytes(), NOW).is_err());
+
+        // Payload swapped under a valid signature.
+        let mut envelope: serde_json::Value =
+            serde_json::from_str(&sign("correct horse battery staple", topic, "0", NOW)).unwrap();
+        envelope["payload"] = "1".into();
+        let swapped = envelope.to_string();
+        assert!(verifier.verify(topic, swapped.as_bytes(), NOW).is_err());
+    }
+
+    #[test]
+    fn stale_and_replayed_messages_are_refused() {
+        let verifier = verifier();
+        let topic = "home/room/pc/windows/shutdown";
+        let stale = sign("correct horse battery staple", topic, "1", NOW - 31);
+        assert!(verifier.verify(topic, stale.as_bytes(), NOW).is_err());
+        let ahead = sign("correct horse battery staple", topic, "1", NOW + 31);
+        assert!(verifier.verify(topic, ahead.as_bytes(), NOW).is_err());
+        // Timestamps at the edges of i64 are refused, not overflowed.
+        for ts in [i64::MIN, i64::MAX] {
+            let extreme = sign("correct horse battery staple", topic, "1", ts);
+            assert!(verifier.verify(topic, extreme.as_bytes(), NOW).is_err());
+        }
+
+        let fresh = sign("correct horse battery staple", topic, "1", NOW);
+        assert!(verifier.verify(topic, fresh.as_bytes(), NOW).is_ok());
+        assert_eq!(
+            verifier.verify(topic, fresh.as_bytes(), NOW + 1),
+            Err("signature already used".into())
+        );
+    }
+}
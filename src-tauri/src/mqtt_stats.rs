@@ -0,0 +1,307 @@
+use crate::mqtt_acl::{AclStats, REPORTED_LIMIT};
+use crate::mqtt_channel::ChannelStats;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Topic rates are counted per window and reported for the last full one.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// How many topics `top_topics` lists.
+const TOP_TOPICS: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// No ConnAck yet since the bridge started.
+    #[default]
+    Connecting,
+    Connected,
+    /// Lost or never established; reconnecting.
+    Disconnected,
+}
+
+/// What `get_mqtt_status` returns and `<base>/app/bridge` carries.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct BridgeStatus {
+    pub state: ConnectionState,
+    pub broker: Option<String>,
+    /// RFC 3339, local time.
+    pub connected_since: Option<String>,
+    /// ConnAcks after the first one.
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    pub top_topics: Vec<TopicRate>,
+    /// Inbound messages on their way to Node, and how many were dropped or
+    /// coalesced because it fell behind.
+    pub node_queue: ChannelStats,
+    /// Topics `mqtt.acl` refused, per direction.
+    pub acl: AclStats,
+    /// Inbound messages `mqtt.signing` and `mqtt.stale_commands` refused.
+    pub refused: RefusedStats,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RefusedStats {
+    pub unsigned: u64,
+    pub stale: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Refusal {
+    /// `mqtt.signing`: no valid, fresh signature.
+    Unsigned,
+    /// `mqtt.stale_commands`: arrived too late.
+    Stale,
+}
+
+/// Counts inbound refusals the way `AclGuard` does: every one is counted,
+/// only the first per topic is reported, so a flood of bad messages costs a
+/// counter bump each rather than a log line.
+#[derive(Debug, Default)]
+pub struct RefusalCounter {
+    stats: RefusedStats,
+    reported: HashSet<(Refusal, String)>,
+}
+
+impl RefusalCounter {
+    /// `true` for the first refusal of this kind on `topic`.
+    pub fn count(&mut self, refusal: Refusal, topic: &str) -> bool {
+        *match refusal {
+            Refusal::Unsigned => &mut self.stats.unsigned,
+            Refusal::Stale => &mut self.stats.stale,
+        } += 1;
+        self.reported.len() < REPORTED_LIMIT && self.reported.insert((refusal, topic.to_string()))
+    }
+
+    pub fn stats(&self) -> RefusedStats {
+        self.stats.clone()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct TopicRate {
+    pub topic: String,
+    /// Messages in both directions over the last full minute (or the
+    /// minute so far, before one has passed).
+    pub per_minute: f64,
+}
+
+/// Counters behind `BridgeStatus`, fed by the event loop and the publisher.
+#[derive(Debug)]
+pub struct BridgeStats {
+    status: BridgeStatus,
+    connects: u64,
+    window_start: Instant,
+    current: HashMap<String, u64>,
+    /// Counts of the last full window, and how long it really lasted.
+    previous: Option<(HashMap<String, u64>, Duration)>,
+}
+
+fn now_rfc3339() -> String {
+    chrono::Local::now().to_rfc3339()
+}
+
+impl BridgeStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            status: BridgeStatus {
+                state: ConnectionState::Connecting,
+                broker: None,
+                connected_since: None,
+                reconnects: 0,
+                last_error: None,
+                last_error_at: None,
+                messages_in: 0,
+                bytes_in: 0,
+                messages_out: 0,
+                bytes_out: 0,
+                top_topics: Vec::new(),
+                node_queue: ChannelStats::default(),
+                acl: AclStats::default(),
+                refused: RefusedStats::default(),
+            },
+            connects: 0,
+            window_start: now,
+            current: HashMap::new(),
+            previous: None,
+        }
+    }
+
+    pub fn connected(&mut self, broker: &str) {
+        if self.connects > 0 {
+            self.status.reconnects += 1;
+        }
+        self.connects += 1;
+        self.status.state = ConnectionState::Connected;
+        self.status.broker = Some(broker.to_string());
+        self.status.connected_since = Some(now_rfc3339());
+    }
+
+    /// A lost connection or a failed attempt.
+    pub fn failed(&mut self, error: &str) {
+        if self.connects > 0 {
+            self.status.state = ConnectionState::Disconnected;
+        }
+        self.status.connected_since = None;
+        self.status.last_error = Some(error.to_string());
+        self.status.last_error_at = Some(now_rfc3339());
+    }
+
+    pub fn received(&mut self, topic: &str, bytes: usize, now: Instant) {
+        self.status.messages_in += 1;
+        self.status.bytes_in += bytes as u64;
+        self.count(topic, now);
+    }
+
+    pub fn sent(&mut self, topic: &str, bytes: usize, now: Instant) {
+        self.status.messages_out += 1;
+        self.status.bytes_out += bytes as u64;
+        self.count(topic, now);
+    }
+
+    fn count(&mut self, topic: &str, now: Instant) {
+        self.roll(now);
+        *self.current.entry(topic.to_string()).or_default() += 1;
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        // A window with no traffic at all leaves nothing worth reporting.
+        self.previous = if elapsed < RATE_WINDOW * 2 {
+            Some((std::mem::take(&mut self.current), elapsed))
+        } else {
+            self.current.clear();
+            None
+        };
+        self.window_start = now;
+    }
+
+    pub fn snapshot(&mut self, now: Instant) -> BridgeStatus {
+        self.roll(now);
+        let (counts, span) = match &self.previous {
+            Some((counts, span)) => (counts, *span),
+            None => (
+                &self.current,
+                now.saturating_duration_since(self.window_start),
+            ),
+        };
+        let minutes = span.as_secs_f64().max(1.0) / 60.0;
+        let mut top: Vec<_> = counts
+            .iter()
+            .map(|(topic, count)| TopicRate {
+                topic: topic.clone(),
+                per_minute: (*count as f64 / minutes * 10.0).round() / 10.0,
+            })
+            .collect();
+        top.sort_by(|a, b| {
+            b.per_minute
+                .total_cmp(&a.per_minute)
+                .then_with(|| a.topic.cmp(&b.topic))
+        });
+        top.truncate(TOP_TOPICS);
+        BridgeStatus {
+            top_topics: top,
+            ..self.status.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refusals_are_reported_once_per_topic_and_kind() {
+        let mut refusals = RefusalCounter::default();
+        assert!(refusals.count(Refusal::Unsigned, "pc/exec/run"));
+        assert!(!refusals.count(Refusal::Unsigned, "pc/exec/run"));
+        assert!(refusals.count(Refusal::Stale, "pc/exec/run"));
+        assert!(refusals.count(Refusal::Unsigned, "pc/power/off"));
+        assert_eq!(
+            refusals.stats(),
+            RefusedStats {
+                unsigned: 3,
+                stale: 1
+            }
+        );
+    }
+
+    #[test]
+    fn connection_history() {
+        let mut stats = BridgeStats::new(Instant::now());
+        assert_eq!(
+            stats.snapshot(Instant::now()).state,
+            ConnectionState::Connecting
+        );
+        stats.failed("connection refused");
+        assert_eq!(
+            stats.snapshot(Instant::now()).state,
+            ConnectionState::Connecting
+        );
+
+        stats.connected("mqtt://nas:1883");
+        stats.failed("keep-alive timeout");
+        stats.connected("mqtt://nas:1883");
+        let status = stats.snapshot(Instant::now());
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.reconnects, 1);
+        assert!(status.connected_since.is_some());
+        assert_eq!(status.last_error.as_deref(), Some("keep-alive timeout"));
+
+        stats.failed("reset by peer");
+        let status = stats.snapshot(Instant::now());
+        assert_eq!(status.state, ConnectionState::Disconnected);
+        assert_eq!(status.connected_since, None);
+    }
+
+    #[test]
+    fn traffic_counts_both_directions() {
+        let now = Instant::now();
+        let mut stats = BridgeStats::new(now);
+        stats.received("pc/cmd", 7, now);
+        stats.sent("pc/status", 6, now);
+        stats.sent("pc/status", 7, now);
+        let status = stats.snapshot(now);
+        assert_eq!((status.messages_in, status.bytes_in), (1, 7));
+        assert_eq!((status.messages_out, status.bytes_out), (2, 13));
+    }
+
+    #[test]
+    fn top_topics_come_from_the_last_full_minute() {
+        let start = Instant::now();
+        let mut stats = BridgeStats::new(start);
+        for _ in 0..30 {
+            stats.sent("pc/sysstats", 10, start);
+        }
+        stats.received("pc/cmd", 1, start);
+
+        let next = start + RATE_WINDOW;
+        stats.received("pc/cmd", 1, next);
+        let top = stats.snapshot(next + Duration::from_secs(5)).top_topics;
+        assert_eq!(
+            top,
+            vec![
+                TopicRate {
+                    topic: "pc/sysstats".into(),
+                    per_minute: 30.0
+                },
+                TopicRate {
+                    topic: "pc/cmd".into(),
+                    per_minute: 1.0
+                },
+            ]
+        );
+
+        // Quiet for a long while: nothing is busy any more.
+        let later = next + RATE_WINDOW * 3;
+        assert!(stats.snapshot(later).top_topics.is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+use rumqttc::QoS;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Who asked for which topic filter. The broker only sees one subscription
+/// per filter, at the highest QoS any owner wants; it is removed once the
+/// last owner lets go.
+///
+/// An owner is the Node module name (`power`, `exec`, ...) or `node` for
+/// callers that don't say. Subscribing twice from the same owner is not
+/// counted twice: a respawned Node child re-subscribes everything and must
+/// not leave stale references behind.
+#[derive(Debug, Default)]
+pub struct SubscriptionTable {
+    filters: HashMap<String, BTreeMap<String, QoS>>,
+}
+
+/// What the broker has to be told after a table change.
+#[derive(Debug, PartialEq)]
+pub enum BrokerChange {
+    Subscribe(QoS),
+    Unsubscribe,
+    None,
+}
+
+/// One row of `get_mqtt_subscriptions`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SubscriptionInfo {
+    pub topic: String,
+    pub qos: u8,
+    pub owners: Vec<OwnerInfo>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OwnerInfo {
+    pub owner: String,
+    pub qos: u8,
+}
+
+fn max_qos(owners: &BTreeMap<String, QoS>) -> Option<QoS> {
+    owners.values().copied().max_by_key(|qos| *qos as u8)
+}
+
+impl SubscriptionTable {
+    pub fn add(&mut self, owner: &str, filter: &str, qos: QoS) -> BrokerChange {
+        let owners = self.filters.entry(filter.to_string()).or_default();
+        let before = max_qos(owners);
+        owners.insert(owner.to_string(), qos);
+        let after = max_qos(owners);
+        if before == after {
+            BrokerChange::None
+        } else {
+            BrokerChange::Subscribe(after.unwrap_or(qos))
+        }
+    }
+
+    /// `owner` already has `filter`: subscribing again is a respawned child
+    /// taking its subscriptions back.
+    pub fn holds(&self, owner: &str, filter: &str) -> bool {
+        self.filters
+            .get(filter)
+            .is_some_and(|owners| owners.contains_key(owner))
+    }
+
+    pub fn remove(&mut self, owner: &str, filter: &str) -> BrokerChange {
+        let Some(owners) = self.filters.get_mut(filter) else {
+            return BrokerChange::None;
+        };
+        let before = max_qos(owners);
+        if owners.remove(owner).is_none() {
+            return BrokerChange::None;
+        }
+        match max_qos(owners) {
+            None => {
+                self.filters.remove(filter);
+                BrokerChange::Unsubscribe
+            }
+            Some(after) if Some(after) != before => BrokerChange::Subscribe(after),
+            Some(_) => BrokerChange::None,
+        }
+    }
+
+    /// Every filter at its broker-side QoS, for the replay after ConnAck.
+    pub fn broker_subscriptions(&self) -> Vec<(String, QoS)> {
+        self.filters
+            .iter()
+            .filter_map(|(filter, owners)| Some((filter.clone(), max_qos(owners)?)))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> Vec<SubscriptionInfo> {
+        let mut rows: Vec<_> = self
+            .filters
+            .iter()
+            .map(|(filter, owners)| SubscriptionInfo {
+                topic: filter.clone(),
+                qos: max_qos(owners).map_or(0, |qos| qos as u8),
+                owners: owners
+                    .iter()
+                    .map(|(owner, qos)| OwnerInfo {
+                        owner: owner.clone(),
+                        qos: *qos as u8,
+                    })
+                    .collect(),
+            })
+            .collect();
+        rows.sort_by(|a, b| a.topic.cmp(&b.topic));
+        rows
+    }
+}
+
+/// Ties SubAcks back to the filter they answer. A SUBSCRIBE only gets its
+/// packet id inside the event loop, so filters are queued in the order they
+/// enter the request channel and take the next `Outgoing::Subscribe` id.
+/// That holds as long as every subscribe is queued and sent under the
+/// subscription table lock, which keeps the two orders the same.
+#[derive(Debug, Default)]
+pub struct SubAckTracker {
+    queued: VecDeque<String>,
+    /// In the order they went out.
+    sent: VecDeque<(u16, String)>,
+}
+
+impl SubAckTracker {
+    pub fn queued(&mut self, filter: &str) {
+        self.queued.push_back(filter.to_string());
+    }
+
+    /// The request never reached the channel.
+    pub fn not_sent(&mut self) {
+        self.queued.pop_back();
+    }
+
+    pub fn sent(&mut self, pkid: u16) {
+        if let Some(filter) = self.queued.pop_front() {
+            self.sent.push_back((pkid, filter));
+        }
+    }
+
+    pub fn acked(&mut self, pkid: u16) -> Option<String> {
+        let at = self.sent.iter().position(|(sent, _)| *sent == pkid)?;
+        self.sent.remove(at).map(|(_, filter)| filter)
+    }
+
+    /// A refusal that came without its packet id (MQTT 5, see
+    /// `ConnectionError::refused_subscription`). Brokers answer SUBSCRIBEs
+    /// in order, so it is the oldest one still waiting.
+    pub fn refused(&mut self) -> Option<String> {
+        self.sent.pop_front().map(|(_, filter)| filter)
+    }
+
+    /// A new connection: SubAcks for packets sent on the old one never come.
+    pub fn reconnected(&mut self) {
+        self.sent.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broker_unsubscribe_waits_for_the_last_owner() {
+        let mut table = SubscriptionTable::default();
+        assert_eq!(
+            table.add("power", "pc/status", QoS::AtMostOnce),
+            BrokerChange::Subscribe(QoS::AtMostOnce)
+        );
+        assert_eq!(
+            table.add("exec", "pc/status", QoS::AtMostOnce),
+            BrokerChange::None
+        );
+
+        assert!(table.holds("power", "pc/status"));
+        assert!(!table.holds("power", "pc/sleep"));
+        assert_eq!(table.remove("power", "pc/status"), BrokerChange::None);
+        assert!(!table.holds("power", "pc/status"));
+        assert_eq!(table.broker_subscriptions().len(), 1);
+        assert_eq!(table.remove("exec", "pc/status"), BrokerChange::Unsubscribe);
+        assert!(table.broker_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn same_owner_subscribing_twice_is_one_reference() {
+        let mut table = SubscriptionTable::default();
+        table.add("power", "pc/sleep", QoS::AtLeastOnce);
+        assert_eq!(
+            table.add("power", "pc/sleep", QoS::AtLeastOnce),
+            BrokerChange::None
+        );
+        assert_eq!(table.remove("power", "pc/sleep"), BrokerChange::Unsubscribe);
+    }
+
+    #[test]
+    fn broker_qos_is_the_highest_any_owner_asked_for() {
+        let mut table = SubscriptionTable::default();
+        table.add("notify", "pc/cmd", QoS::AtMostOnce);
+        assert_eq!(
+            table.add("exec", "pc/cmd", QoS::AtLeastOnce),
+            BrokerChange::Subscribe(QoS::AtLeastOnce)
+        );
+        // A lower request from someone else doesn't downgrade delivery.
+        assert_eq!(
+            table.add("tts", "pc/cmd", QoS::AtMostOnce),
+            BrokerChange::None
+        );
+        assert_eq!(
+            table.broker_subscriptions(),
+            vec![("pc/cmd".to_string(), QoS::AtLeastOnce)]
+        );
+
+        // The owner that needed QoS 1 leaves: drop back to what's left.
+        assert_eq!(
+            table.remove("exec", "pc/cmd"),
+            BrokerChange::Subscribe(QoS::AtMostOnce)
+        );
+    }
+
+    #[test]
+    fn removing_an_unknown_owner_changes_nothing() {
+        let mut table = SubscriptionTable::default();
+        table.add("power", "pc/sleep", QoS::AtMostOnce);
+        assert_eq!(table.remove("exec", "pc/sleep"), BrokerChange::None);
+        assert_eq!(table.remove("exec", "pc/other"), BrokerChange::None);
+        assert_eq!(table.broker_subscriptions().len(), 1);
+    }
+
+    #[test]
+    fn snapshot_lists_owners_per_topic() {
+        let mut table = SubscriptionTable::default();
+        table.add("power", "pc/b", QoS::AtLeastOnce);
+        table.add("exec", "pc/b", QoS::AtMostOnce);
+        table.add("node", "pc/a", QoS::AtMostOnce);
+        let rows = table.snapshot();
+        assert_eq!(rows[0].topic, "pc/a");
+        assert_eq!(rows[1].qos, 1);
+        assert_eq!(
+            rows[1].owners,
+            vec![
+                OwnerInfo {
+                    owner: "exec".into(),
+                    qos: 0
+                },
+                OwnerInfo {
+                    owner: "power".into(),
+                    qos: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn subacks_map_back_to_their_filters() {
+        let mut acks = SubAckTracker::default();
+        acks.queued("pc/a");
+        acks.queued("pc/b");
+        acks.queued("pc/c");
+        acks.not_sent();
+        acks.sent(7);
+        acks.sent(8);
+        assert_eq!(acks.acked(8).as_deref(), Some("pc/b"));
+        assert_eq!(acks.acked(8), None);
+
+        acks.queued("pc/d");
+        acks.sent(9);
+        assert_eq!(acks.refused().as_deref(), Some("pc/a"));
+        assert_eq!(acks.acked(9).as_deref(), Some("pc/d"));
+
+        acks.queued("pc/e");
+        acks.sent(10);
+        acks.reconnected();
+        assert_eq!(acks.acked(10), None);
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+/// A connection closed sooner than this after its ConnAck counts as cut
+/// short.
+const SHORT_LIVED: Duration = Duration::from_secs(30);
+/// Cut short this many times in a row: someone else is using our client id.
+const IN_A_ROW: u32 = 3;
+
+/// Spots the "session taken over" loop: a broker drops the older of two
+/// connections with the same client id, right after the newer one's ConnAck.
+/// Two clients sharing an id then take turns kicking each other off, and
+/// from here it looks like connections that keep dying within seconds.
+#[derive(Debug, Default)]
+pub struct TakeoverDetector {
+    connected_at: Option<Instant>,
+    short_lived: u32,
+}
+
+impl TakeoverDetector {
+    pub fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// The connection dropped. `true` when this completes a loop: the caller
+    /// stops reconnecting then, or the two clients would go on forever.
+    pub fn dropped(&mut self, now: Instant) -> bool {
+        let Some(since) = self.connected_at.take() else {
+            return false;
+        };
+        if now.saturating_duration_since(since) >= SHORT_LIVED {
+            self.short_lived = 0;
+            return false;
+        }
+        self.short_lived += 1;
+        self.short_lived >= IN_A_ROW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cut_short(detector: &mut TakeoverDetector, now: &mut Instant) -> bool {
+        detector.connected(*now);
+        *now += Duration::from_secs(2);
+        detector.dropped(*now)
+    }
+
+    #[test]
+    fn three_short_connections_in_a_row_are_a_takeover() {
+        let mut now = Instant::now();
+        let mut detector = TakeoverDetector::default();
+        assert!(!cut_short(&mut detector, &mut now));
+        assert!(!cut_short(&mut detector, &mut now));
+        assert!(cut_short(&mut detector, &mut now));
+
+        // A connection that holds ends it.
+        detector.connected(now);
+        now += SHORT_LIVED;
+        assert!(!detector.dropped(now));
+        assert!(!cut_short(&mut detector, &mut now));
+    }
+
+    #[test]
+    fn failed_attempts_dont_count() {
+        let mut now = Instant::now();
+        let mut detector = TakeoverDetector::default();
+        cut_short(&mut detector, &mut now);
+        cut_short(&mut detector, &mut now);
+        // No ConnAck in between: refused or unreachable, not a takeover.
+        assert!(!detector.dropped(now));
+        assert!(!detector.dropped(now));
+    }
+
+    #[test]
+    fn a_long_connection_resets_the_count() {
+        let mut now = Instant::now();
+        let mut detector = TakeoverDetector::default();
+        cut_short(&mut detector, &mut now);
+        cut_short(&mut detector, &mut now);
+        detector.connected(now);
+        now += Duration::from_secs(600);
+        detector.dropped(now);
+        assert!(!cut_short(&mut detector, &mut now));
+        assert!(!cut_short(&mut detector, &mut now));
+        assert!(cut_short(&mut detector, &mut now));
+    }
+}
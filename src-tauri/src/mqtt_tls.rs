@@ -0,0 +1,292 @@
+use rumqttc::tokio_rustls::rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// `mqtt.tls` from config.yml. Paths are already resolved against the config
+/// file's directory by `read_mqtt_config`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    /// PEM bundle to trust instead of the system store.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key for mutual TLS; both or neither.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Name the broker certificate is checked against when it differs from
+    /// `mqtt.host` (connecting by IP, or through an alias). Only the check
+    /// uses it: the SNI sent in the handshake is always `mqtt.host`.
+    pub cert_name: Option<String>,
+    /// `cert_name` was given under its old key, `server_name`.
+    pub legacy_server_name: bool,
+    /// Skip certificate verification entirely. For self-signed lab brokers only.
+    pub insecure: bool,
+}
+
+/// Build the rustls client config for `mqtt.tls`, with what the user should
+/// hear about it that doesn't stop it from working.
+///
+/// Every failure names the offending file or setting: this runs once at
+/// startup and its message is the only hint the user gets in `server-log`.
+pub fn client_config(tls: &TlsConfig) -> Result<(ClientConfig, Vec<String>), String> {
+    let mut warnings = Vec::new();
+    if tls.legacy_server_name {
+        warnings.push(
+            "mqtt.tls.server_name is deprecated, use cert_name: it only sets the name \
+             the certificate is checked against, the SNI is always mqtt.host"
+                .to_string(),
+        );
+    }
+    let builder = ClientConfig::builder();
+
+    let builder = if tls.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(Arc::new(
+                ring::default_provider(),
+            ))))
+    } else {
+        let (roots, ignored) = root_store(tls.ca_file.as_deref())?;
+        if ignored > 0 {
+            warnings.push(format!(
+                "mqtt.tls: skipped {ignored} unparsable CA certificate(s)"
+            ));
+        }
+        match &tls.cert_name {
+            Some(name) => {
+                let name = ServerName::try_from(name.clone())
+                    .map_err(|_| format!("mqtt.tls.cert_name '{name}' is not a valid name"))?;
+                let inner = WebPkiServerVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| format!("mqtt.tls: {e}"))?;
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(FixedServerName { inner, name }))
+            }
+            None => builder.with_root_certificates(roots),
+        }
+    };
+
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = read_certs(cert)?;
+            let key = read_key(key)?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("mqtt.tls: client certificate rejected: {e}"))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("mqtt.tls: cert and key must be set together".into()),
+    };
+    Ok((config, warnings))
+}
+
+/// The trusted roots, and how many certificates were skipped as unparsable.
+fn root_store(ca_file: Option<&Path>) -> Result<(RootCertStore, usize), String> {
+    let mut roots = RootCertStore::empty();
+    let certs = match ca_file {
+        Some(path) => read_certs(path)?,
+        None => rustls_native_certs::load_native_certs()
+            .map_err(|e| format!("mqtt.tls: cannot load system certificates: {e}"))?,
+    };
+    let (_, ignored) = roots.add_parsable_certificates(certs);
+    if roots.is_empty() {
+        return Err(match ca_file {
+            Some(path) => format!("mqtt.tls: no usable CA certificate in {}", path.display()),
+            None => "mqtt.tls: the system certificate store is empty".into(),
+        });
+    }
+    Ok((roots, ignored))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("mqtt.tls: cannot read {}: {e}", path.display()))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = read_pem(path)?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("mqtt.tls: cannot parse {}: {e}", path.display()))?;
+    if certs.is_empty() {
+        return Err(format!(
+            "mqtt.tls: no certificates found in {}",
+            path.display()
+        ));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = read_pem(path)?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| format!("mqtt.tls: cannot parse {}: {e}", path.display()))?
+        .ok_or_else(|| format!("mqtt.tls: no private key found in {}", path.display()))
+}
+
+/// Verifies the chain as usual but against `name` rather than the host we
+/// dialled. rumqttc takes the SNI from the host it dials, so that stays
+/// `mqtt.host`; only the check moves.
+#[derive(Debug)]
+struct FixedServerName {
+    inner: Arc<WebPkiServerVerifier>,
+    name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for FixedServerName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &self.name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// `insecure: true`: any certificate is accepted, but handshake signatures are
+/// still checked so the session keys at least belong to that certificate.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wmqtt-tls-{name}"));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn insecure_needs_no_ca() {
+        let tls = TlsConfig {
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(client_config(&tls).is_ok());
+    }
+
+    #[test]
+    fn missing_ca_file_names_the_path() {
+        let tls = TlsConfig {
+            ca_file: Some(PathBuf::from("/nonexistent/wmqtt-ca.pem")),
+            ..Default::default()
+        };
+        let err = client_config(&tls).unwrap_err();
+        assert!(err.contains("wmqtt-ca.pem"), "{err}");
+    }
+
+    #[test]
+    fn ca_file_without_certificates_is_rejected() {
+        let path = temp_file("empty-ca.pem", "not a certificate\n");
+        let tls = TlsConfig {
+            ca_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let err = client_config(&tls).unwrap_err();
+        assert!(err.contains("no certificates found"), "{err}");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn client_cert_without_key_is_rejected() {
+        let tls = TlsConfig {
+            insecure: true,
+            client_cert: Some(PathBuf::from("client.crt")),
+            ..Default::default()
+        };
+        let err = client_config(&tls).unwrap_err();
+        assert!(err.contains("cert and key must be set together"), "{err}");
+    }
+
+    #[test]
+    fn the_old_server_name_key_still_works_with_a_warning() {
+        let tls = TlsConfig {
+            insecure: true,
+            cert_name: Some("mqtt.home".into()),
+            legacy_server_name: true,
+            ..Default::default()
+        };
+        let (_, warnings) = client_config(&tls).unwrap();
+        assert!(
+            warnings[0].contains("server_name is deprecated"),
+            "{warnings:?}"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+/// Is `filter` something a broker would accept in a SUBSCRIBE: non-empty,
+/// `#` only as the whole last level, `+` only as a whole level.
+pub fn valid_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+    let levels: Vec<_> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains(['#', '+']),
+    })
+}
+
+/// MQTT filter match (MQTT 3.1.1, 4.7):
+/// - `+` matches exactly one level, an empty one included;
+/// - `#` matches the rest, and the parent level itself (`a/#` matches `a`);
+/// - a wildcard in the first level never matches a `$` topic (`$SYS/...`),
+///   those need the `$` level spelled out.
+///
+/// Invalid filters match nothing.
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    if !valid_filter(filter) || topic.is_empty() || topic.contains(['#', '+']) {
+        return false;
+    }
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (l, Some(t)) if l == t => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Called with the topic and payload of every matching inbound message. Runs
+/// on the event loop: anything slower than a lookup should spawn a task.
+pub type Handler = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+/// Native handlers by topic filter, next to the forwarding to Node. A
+/// message goes to every route that matches, in registration order.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(String, Handler)>,
+}
+
+impl Router {
+    pub fn add(&mut self, filter: &str, handler: Handler) -> Result<(), String> {
+        if !valid_filter(filter) {
+            return Err(format!("invalid MQTT topic filter '{filter}'"));
+        }
+        self.routes.push((filter.to_string(), handler));
+        Ok(())
+    }
+
+    /// Drops every handler added for exactly `filter`.
+    pub fn remove(&mut self, filter: &str) {
+        self.routes.retain(|(existing, _)| existing != filter);
+    }
+
+    /// The handlers for `topic`, cloned out so they run without the lock.
+    pub fn handlers_for(&self, topic: &str) -> Vec<Handler> {
+        self.routes
+            .iter()
+            .filter(|(filter, _)| filter_matches(filter, topic))
+            .map(|(_, handler)| handler.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn exact_filters_match_only_themselves() {
+        assert!(filter_matches("pc/cmd", "pc/cmd"));
+        assert!(!filter_matches("pc/cmd", "pc/cmd/silent"));
+        assert!(!filter_matches("pc/cmd/silent", "pc/cmd"));
+        assert!(!filter_matches("pc/cmd", "PC/cmd"));
+        // Empty levels are levels: these are three different topics.
+        assert!(!filter_matches("pc/cmd", "pc/cmd/"));
+        assert!(!filter_matches("pc/cmd", "/pc/cmd"));
+        assert!(filter_matches("/pc/cmd", "/pc/cmd"));
+    }
+
+    #[test]
+    fn plus_matches_exactly_one_level() {
+        assert!(filter_matches("a/+/c", "a/b/c"));
+        assert!(filter_matches("a/+", "a/b"));
+        assert!(!filter_matches("a/+", "a/b/c"));
+        assert!(!filter_matches("a/+", "a"));
+        assert!(filter_matches("+", "a"));
+        assert!(!filter_matches("+", "a/b"));
+        assert!(filter_matches("+/+", "a/b"));
+        // An empty level still counts as one.
+        assert!(filter_matches("a/+", "a/"));
+        assert!(filter_matches("+/b", "/b"));
+        assert!(filter_matches("a/+/c", "a//c"));
+    }
+
+    #[test]
+    fn hash_matches_the_rest_and_the_parent() {
+        assert!(filter_matches("a/#", "a/b/c"));
+        assert!(filter_matches("a/#", "a/b"));
+        assert!(filter_matches("a/#", "a"));
+        assert!(filter_matches("a/#", "a/"));
+        assert!(!filter_matches("a/#", "ab"));
+        assert!(!filter_matches("a/b/#", "a"));
+        assert!(filter_matches("#", "a/b/c"));
+        assert!(filter_matches("#", "/"));
+        assert!(filter_matches("a/+/#", "a/b"));
+        assert!(filter_matches("a/+/#", "a/b/c/d"));
+        assert!(!filter_matches("a/+/#", "a"));
+    }
+
+    #[test]
+    fn dollar_topics_need_the_dollar_level_spelled_out() {
+        assert!(!filter_matches("#", "$SYS/broker/uptime"));
+        assert!(!filter_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(filter_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(filter_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+        // Only the first level is special.
+        assert!(filter_matches("a/+", "a/$b"));
+        assert!(filter_matches("a/#", "a/$b/c"));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected_and_match_nothing() {
+        for filter in ["", "a/#/b", "a#", "a/b#", "a+/b", "a/+b", "##", "a/\0"] {
+            assert!(!valid_filter(filter), "{filter:?}");
+            assert!(!filter_matches(filter, "a/b"), "{filter:?}");
+        }
+        for filter in ["a", "/", "a//b", "+", "#", "+/#", "$SYS/#"] {
+            assert!(valid_filter(filter), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn wildcards_in_a_topic_never_match() {
+        assert!(!filter_matches("#", "a/+"));
+        assert!(!filter_matches("a/+", "a/+"));
+        assert!(!filter_matches("#", ""));
+    }
+
+    #[test]
+    fn router_calls_every_matching_handler() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let handler = |name: &'static str| -> Handler {
+            let calls = calls.clone();
+            Arc::new(move |topic: &str, payload: &[u8]| {
+                let payload = String::from_utf8_lossy(payload).into_owned();
+                calls
+                    .lock()
+                    .unwrap()
+                    .push((name, topic.to_string(), payload));
+            })
+        };
+        let mut router = Router::default();
+        router.add("pc/app/#", handler("app")).unwrap();
+        router.add("pc/+/node", handler("node")).unwrap();
+        assert!(router.add("pc/#/x", handler("bad")).is_err());
+
+        for h in router.handlers_for("pc/app/node") {
+            h("pc/app/node", b"restart");
+        }
+        assert!(router.handlers_for("pc/cmd").is_empty());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                ("app", "pc/app/node".to_string(), "restart".to_string()),
+                ("node", "pc/app/node".to_string(), "restart".to_string()),
+            ]
+        );
+
+        router.remove("pc/app/#");
+        assert_eq!(router.handlers_for("pc/app/node").len(), 1);
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Which way an IPC line went.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    /// Written to the child: MQTT messages and tray/hotkey actions. These
+    /// are what a replay feeds back.
+    ToNode,
+    /// Read from the child: its publishes. Kept to compare against what a
+    /// replay makes it publish.
+    FromNode,
+}
+
+/// One line of a capture file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// Milliseconds since the capture started; replays keep these gaps.
+    pub at_ms: u64,
+    /// Wall clock, for reading the file next to the app log.
+    pub time: String,
+    pub dir: Direction,
+    /// The IPC line itself, as the child reads or wrote it.
+    pub ipc: serde_json::Value,
+}
+
+/// A capture being written. Lines go out one at a time, so a crash loses
+/// at most the line in flight.
+pub struct Capture {
+    path: PathBuf,
+    file: Mutex<LineWriter<File>>,
+    started: Instant,
+    written: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Capture {
+    /// Starts `capture-<date>-<time>.jsonl` in `dir`.
+    pub fn create(dir: &Path) -> Result<Self, String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+        let name = chrono::Local::now().format("capture-%Y%m%d-%H%M%S.jsonl");
+        let path = dir.join(name.to_string());
+        let file = File::create(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(LineWriter::new(file)),
+            started: Instant::now(),
+            written: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lines written and lines lost to write errors so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.written.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Appends `ipc`. A failed write is counted rather than reported: the
+    /// capture must never get in the way of the traffic it records.
+    pub fn record(&self, dir: Direction, ipc: &impl Serialize) {
+        let record = serde_json::to_value(ipc).map(|ipc| Record {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            time: chrono::Local::now().to_rfc3339(),
+            dir,
+            ipc,
+        });
+        let line = record.and_then(|record| serde_json::to_string(&record));
+        let written = match line {
+            Ok(line) => writeln!(self.file.lock().unwrap(), "{line}").is_ok(),
+            Err(_) => false,
+        };
+        let counter = if written { &self.written } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The newest capture in `dir`: the names sort by the time they started.
+pub fn latest(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("capture-") && name.ends_with(".jsonl"))
+        })
+        .max()
+}
+
+pub fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| format!("{}:{}: {e}", path.display(), i + 1))
+        })
+        .collect()
+}
+
+/// Slowest `--replay-speed` other than 0: a hundred times slower than
+/// recorded is already more than anyone waits for.
+pub const MIN_SPEED: f64 = 0.01;
+
+/// What a replay writes to the child and when, relative to its start.
+/// `speed` 1 keeps the original timing, 10 runs ten times faster, and 0
+/// sends everything back to back. Speeds below `MIN_SPEED` count as it.
+pub fn schedule(records: Vec<Record>, speed: f64) -> Vec<(Duration, String)> {
+    records
+        .into_iter()
+        .filter(|record| record.dir == Direction::ToNode)
+        .map(|record| {
+            let at = if speed > 0.0 {
+                Duration::from_millis(record.at_ms).div_f64(speed.max(MIN_SPEED))
+            } else {
+                Duration::ZERO
+            };
+            (at, record.ipc.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "windows-mqtt-capture-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn a_capture_loads_back() {
+        let dir = scratch("roundtrip");
+        let capture = Capture::create(&dir).unwrap();
+        let message = json!({
+            "type": "message",
+            "topic": "home/room/pc/keys/press",
+            "payload": "C4",
+            "encoding": "utf8",
+        });
+        let publish =
+            json!({"type": "publish", "topic": "home/room/pc/keys/state", "payload": "1"});
+        capture.record(Direction::ToNode, &message);
+        capture.record(Direction::FromNode, &publish);
+        assert_eq!(capture.counts(), (2, 0));
+
+        assert_eq!(latest(&dir).as_deref(), Some(capture.path()));
+        let records = load(capture.path()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].dir, Direction::ToNode);
+        assert_eq!(records[0].ipc, message);
+        assert_eq!(records[1].ipc, publish);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replays_feed_only_what_went_to_node() {
+        let record = |at_ms, dir, topic: &str| Record {
+            at_ms,
+            time: String::new(),
+            dir,
+            ipc: json!({ "topic": topic }),
+        };
+        let records = vec![
+            record(0, Direction::ToNode, "a"),
+            record(500, Direction::FromNode, "b"),
+            record(2000, Direction::ToNode, "c"),
+        ];
+        let plan = schedule(records, 4.0);
+        assert_eq!(
+            plan,
+            vec![
+                (Duration::ZERO, r#"{"topic":"a"}"#.to_string()),
+                (Duration::from_millis(500), r#"{"topic":"c"}"#.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn speed_zero_sends_back_to_back() {
+        let records = vec![Record {
+            at_ms: 60_000,
+            time: String::new(),
+            dir: Direction::ToNode,
+            ipc: json!({}),
+        }];
+        assert_eq!(schedule(records, 0.0)[0].0, Duration::ZERO);
+    }
+
+    #[test]
+    fn tiny_speeds_are_clamped() {
+        let records = vec![Record {
+            at_ms: 60_000,
+            time: String::new(),
+            dir: Direction::ToNode,
+            ipc: json!({}),
+        }];
+        assert_eq!(schedule(records, 1e-300)[0].0, Duration::from_secs(6_000));
+    }
+
+    #[test]
+    fn a_broken_line_names_its_number() {
+        let dir = scratch("broken");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture-1.jsonl");
+        std::fs::write(
+            &path,
+            "{\"atMs\":0,\"time\":\"\",\"dir\":\"toNode\",\"ipc\":{}}\n\nnot json\n",
+        )
+        .unwrap();
+        let error = load(&path).unwrap_err();
+        assert!(error.contains("capture-1.jsonl:3:"), "{error}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -45,7 +45,9 @@ export default async (mqtt, config, log) => {
 
       if (ttsTopic && data.success_tts) {
         if (data.success_tts === 'stdout') data.success_tts = stdout;
-        mqtt.publish(ttsTopic, data.success_tts);
+        mqtt.publish(ttsTopic, data.success_tts, { qos: 1 }, (err) => {
+          if (err) console.error(`cmd: ${data.cmd}, result not delivered: ${err.message}`);
+        });
       }
 
       if (stdout && !data.silent) console.log(`stdout: ${stdout}`);
@@ -72,12 +74,14 @@ export default async (mqtt, config, log) => {
           config.base + '/cmd',
           config.base + '/cmd/silent',
         ],
+        qos: 1,
         handler: cmd
       },
       {
         topics: [
           config.base + '/ssh',
         ],
+        qos: 1,
         handler: ssh
       },
     ]
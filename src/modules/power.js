@@ -119,9 +119,10 @@ export default async (mqtt, config, log) => {
   return {
     subscriptions: [
       {topics: [`${config.base}/store/done`], handler: () => ackQueue.resolveNext()},
-      {topics: [`${config.base}/sleep`], handler: () => sleep()},
+      {topics: [`${config.base}/sleep`], qos: 1, handler: () => sleep()},
       {
         topics: [`${config.base}/restart`],
+        qos: 1,
         handler: (topic, message) => {
           log(`< ${topic}: ${message}`);
           if (`${message}` === 'nostore') restart();
@@ -130,13 +131,14 @@ export default async (mqtt, config, log) => {
       },
       {
         topics: [`${config.base}/shutdown`],
+        qos: 1,
         handler: (topic, message) => {
           log(`< ${topic}: ${message}`);
           if (`${message}` === 'store') storeAndThen(shutdown);
           else shutdown();
         },
       },
-      {topics: [`${config.base}/restart_restore`], handler: () => storeAndThen(restart)},
+      {topics: [`${config.base}/restart_restore`], qos: 1, handler: () => storeAndThen(restart)},
     ],
     // Пункты трея приходят не по MQTT, а по stdin от Tauri, и это намеренно:
     // выключение машины должно работать и при лежащем брокере. Раздавал их
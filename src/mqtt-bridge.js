@@ -1,6 +1,96 @@
 import { EventEmitter } from 'events';
 import readline from 'readline';
 
+// Payloads cross the stdin/stdout pipe inside JSON lines. Text goes as-is;
+// bytes that are not valid UTF-8 (screenshots, CBOR) go as base64 so they
+// arrive untouched. Rust picks the encoding for inbound messages.
+
+/**
+ * @param {{payload?: string, encoding?: string}} msg
+ * @returns {Buffer}
+ */
+function decodePayload(msg) {
+  return Buffer.from(msg.payload || '', msg.encoding === 'base64' ? 'base64' : 'utf8');
+}
+
+/**
+ * @param {Buffer | string | number | boolean} payload
+ * @returns {{payload: string, encoding: 'utf8' | 'base64'}}
+ */
+function encodePayload(payload) {
+  if (payload instanceof Buffer) {
+    return { payload: payload.toString('base64'), encoding: 'base64' };
+  }
+  return { payload: String(payload), encoding: 'utf8' };
+}
+
+// MQTT 5 properties (mqtt.protocol: 5) in the shape mqtt.js uses for
+// `packet.properties`: userProperties as an object, a repeated key holding an
+// array, and correlationData as a Buffer. Over the pipe they travel as
+// [key, value] pairs and base64.
+const PLAIN_PROPERTIES = ['contentType', 'messageExpiryInterval', 'responseTopic'];
+
+/**
+ * @param {object} [props] properties as Rust sends them
+ * @returns {object | undefined}
+ */
+function propertiesFromRust(props) {
+  if (!props) return undefined;
+  const out = {};
+  for (const key of PLAIN_PROPERTIES) {
+    if (props[key] !== undefined) out[key] = props[key];
+  }
+  if (props.userProperties) {
+    out.userProperties = {};
+    for (const [key, value] of props.userProperties) {
+      const prev = out.userProperties[key];
+      out.userProperties[key] = prev === undefined ? value : [].concat(prev, value);
+    }
+  }
+  if (props.correlationData !== undefined) {
+    out.correlationData = Buffer.from(props.correlationData, 'base64');
+  }
+  return out;
+}
+
+/**
+ * @param {object} props mqtt.js publish `options.properties`
+ * @returns {object}
+ */
+function propertiesToRust(props) {
+  const out = {};
+  for (const key of PLAIN_PROPERTIES) {
+    if (props[key] !== undefined) out[key] = props[key];
+  }
+  if (props.userProperties) {
+    out.userProperties = Object.entries(props.userProperties).flatMap(([key, values]) =>
+      [].concat(values).map(value => [key, String(value)]));
+  }
+  if (props.correlationData !== undefined) {
+    out.correlationData = Buffer.from(props.correlationData).toString('base64');
+  }
+  return out;
+}
+
+/**
+ * Normalizes the mqtt.js subscribe forms into what Rust expects. QoS 0 goes
+ * as a bare filter, anything else as `{topic, qos}`.
+ * @param {string | string[] | Record<string, {qos?: number}>} topics
+ * @param {{qos?: number}} [options]
+ * @returns {Array<string | {topic: string, qos: number}>}
+ */
+function subscribeTopics(topics, options) {
+  const defaultQos = options?.qos || 0;
+  const entries = typeof topics === 'string' || Array.isArray(topics)
+    ? [].concat(topics).map(topic => [topic, defaultQos])
+    : Object.entries(topics).map(([topic, opts]) => [topic, opts?.qos ?? defaultQos]);
+  return entries.map(([topic, qos]) => (qos ? { topic, qos } : topic));
+}
+
+function owner(options) {
+  return options?.owner ? { owner: options.owner } : {};
+}
+
 class MqttBridge extends EventEmitter {
   constructor() {
     super();
@@ -19,9 +109,14 @@ class MqttBridge extends EventEmitter {
       }
 
       switch (msg.type) {
-        case 'message':
-          this.emit('message', msg.topic, Buffer.from(msg.payload || ''));
+        case 'message': {
+          // Third argument as in mqtt.js: the packet, carrying the MQTT 5
+          // properties when the sender set any.
+          const payload = decodePayload(msg);
+          const properties = propertiesFromRust(msg.properties);
+          this.emit('message', msg.topic, payload, { topic: msg.topic, payload, properties });
           break;
+        }
         case 'connected':
           this.emit('connect');
           break;
@@ -31,6 +126,23 @@ class MqttBridge extends EventEmitter {
         case 'action':
           this.emit('action', msg.action, msg.payload);
           break;
+        case 'error':
+          this._emitError(msg);
+          break;
+        case 'publishResult':
+          this._publishResult(msg);
+          break;
+        case 'topicValue':
+          this._answer(msg.id, msg.value && {
+            topic: msg.value.topic,
+            payload: decodePayload(msg.value),
+            retained: msg.value.retained,
+            receivedAt: new Date(msg.value.receivedAt),
+          });
+          break;
+        case 'topicList':
+          this._answer(msg.id, msg.topics);
+          break;
       }
     });
 
@@ -39,31 +151,95 @@ class MqttBridge extends EventEmitter {
     });
   }
 
-  subscribe(topics) {
-    const arr = Array.isArray(topics) ? topics : [topics];
-    this._send({ type: 'subscribe', topics: arr });
+  // Same 'error' event as mqtt.js, with operation/topic attached. Rust has
+  // already logged it; without a listener EventEmitter would throw, which
+  // would take the whole child down over a denied subscription.
+  _emitError(msg) {
+    if (!this.listenerCount('error')) return;
+    const target = msg.topic ? ` ${msg.topic}` : '';
+    const err = new Error(`MQTT ${msg.operation}${target} failed: ${msg.reason}`);
+    err.operation = msg.operation;
+    err.topic = msg.topic;
+    this.emit('error', err);
   }
 
-  unsubscribe(topics) {
+  // options.owner names the module holding the subscription: Rust only
+  // unsubscribes at the broker once no owner is left on a topic.
+  subscribe(topics, options) {
+    this._send({ type: 'subscribe', topics: subscribeTopics(topics, options), ...owner(options) });
+  }
+
+  unsubscribe(topics, options) {
     const arr = Array.isArray(topics) ? topics : [topics];
-    this._send({ type: 'unsubscribe', topics: arr });
+    this._send({ type: 'unsubscribe', topics: arr, ...owner(options) });
   }
 
-  publish(topic, payload, options) {
+  // mqtt.js signature: publish(topic, payload, [options], [callback]).
+  // With a callback Rust answers once the broker has acked the message (QoS
+  // 1/2), it has been written (QoS 0), or it failed or timed out.
+  publish(topic, payload, options, callback) {
+    if (typeof options === 'function') {
+      callback = options;
+      options = undefined;
+    }
     const msg = {
       type: 'publish',
       topic,
-      payload: payload instanceof Buffer ? payload.toString() : String(payload),
+      ...encodePayload(payload),
     };
     if (options) {
       msg.options = {
         retain: !!options.retain,
         qos: options.qos || 0,
       };
+      // Dropped by Rust unless mqtt.protocol is 5.
+      if (options.properties) {
+        msg.options.properties = propertiesToRust(options.properties);
+      }
+    }
+    if (callback) {
+      msg.id = this._track(callback);
     }
     this._send(msg);
   }
 
+  // Last value Rust has cached for a topic (retained ones by default, see
+  // mqtt.cache), or null: { topic, payload: Buffer, retained, receivedAt }.
+  getTopicValue(topic) {
+    return new Promise((resolve) => {
+      this._send({ type: 'getTopicValue', id: this._track(resolve), topic });
+    });
+  }
+
+  // Cached topic names, optionally under an MQTT filter.
+  listTopics(filter) {
+    return new Promise((resolve) => {
+      const msg = { type: 'listTopics', id: this._track(resolve) };
+      if (filter) msg.filter = filter;
+      this._send(msg);
+    });
+  }
+
+  // Request ids for answers Rust sends back (publishResult, topicValue, ...).
+  _track(callback) {
+    this._pending ??= new Map();
+    this._nextId = (this._nextId || 0) + 1;
+    const id = String(this._nextId);
+    this._pending.set(id, callback);
+    return id;
+  }
+
+  _answer(id, value) {
+    const callback = this._pending?.get(id);
+    if (!callback) return;
+    this._pending.delete(id);
+    callback(value);
+  }
+
+  _publishResult(msg) {
+    this._answer(msg.id, msg.ok ? null : new Error(msg.error || 'publish failed'));
+  }
+
   end() {
     // No-op: Rust owns the MQTT connection
   }
@@ -81,4 +257,12 @@ function mqttInit() {
   return new MqttBridge();
 }
 
-export { mqttInit };
+export {
+  mqttInit,
+  MqttBridge,
+  decodePayload,
+  encodePayload,
+  propertiesFromRust,
+  propertiesToRust,
+  subscribeTopics,
+};
@@ -174,15 +174,25 @@ function listenModulesMQTT(modules) {
 }
 
 function subscribeToModuleTopics(modules) {
-  let topics = [];
+  // Each module subscribes under its own name, so the bridge can tell owners
+  // apart when two modules listen on one topic. A subscription may ask for
+  // `qos: 1` so commands survive a flaky link.
+  let total = 0;
   for (let mod of modules) {
-    const modTopics = mod.subscriptions?.map(sub => Array.isArray(sub.topics) ? sub.topics : [sub.topics]).flat() || [];
-    topics = [...topics, ...modTopics];
+    const topics = {};
+    for (const sub of mod.subscriptions || []) {
+      const qos = sub.qos || 0;
+      for (const topic of [sub.topics].flat()) {
+        topics[topic] = { qos: Math.max(qos, topics[topic]?.qos || 0) };
+      }
+    }
+    const modTopics = Object.keys(topics);
+    if (!modTopics.length) continue;
+    total += modTopics.length;
+    log(`${modTopics.map(t => `- ${t}${topics[t].qos ? ` (qos ${topics[t].qos})` : ''} [${mod.name}]`).join('\n')}`, 'debug');
+    mqtt.subscribe(topics, { owner: mod.name });
   }
-  const allTopics = topics.flat();
-  log(`Subscribe to ${allTopics.length} topics`)
-  log(`${allTopics.map(t => `- ${t}`).join('\n')}`, 'debug');
-  mqtt.subscribe(topics.flat());
+  log(`Subscribe to ${total} topics`)
 }
 
 function getHandler(topic, modules) {
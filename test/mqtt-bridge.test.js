@@ -0,0 +1,150 @@
+import { test } from 'node:test';
+import assert from 'node:assert';
+
+import {
+  MqttBridge,
+  decodePayload,
+  encodePayload,
+  propertiesFromRust,
+  subscribeTopics,
+} from '../src/mqtt-bridge.js';
+
+test('текст идёт по трубе как есть', () => {
+  assert.deepStrictEqual(encodePayload('22.5'), { payload: '22.5', encoding: 'utf8' });
+  assert.deepStrictEqual(encodePayload(1), { payload: '1', encoding: 'utf8' });
+  assert.strictEqual(decodePayload({ payload: 'on' }).toString(), 'on');
+});
+
+test('Buffer доезжает до брокера байт в байт', () => {
+  // Раньше Buffer превращался в строку через toString(), и всё, что не было
+  // UTF-8 (скриншот, CBOR), приходило заменёнными на U+FFFD байтами.
+  const bytes = Buffer.from([0x89, 0x50, 0x4e, 0x47, 0x00, 0xff, 0xfe]);
+  const encoded = encodePayload(bytes);
+  assert.strictEqual(encoded.encoding, 'base64');
+  assert.deepStrictEqual(decodePayload(encoded), bytes);
+});
+
+test('входящее сообщение без encoding читается как UTF-8', () => {
+  // Так пишет Rust-сторона для текстовых payload'ов и так писала всегда.
+  assert.deepStrictEqual(decodePayload({ payload: 'привет' }), Buffer.from('привет'));
+  assert.deepStrictEqual(decodePayload({}), Buffer.alloc(0));
+});
+
+test('subscribe передаёт QoS каждого топика', () => {
+  // QoS 0 уходит голой строкой — так Rust понимал подписки и раньше.
+  assert.deepStrictEqual(subscribeTopics('a/b'), ['a/b']);
+  assert.deepStrictEqual(subscribeTopics(['a', 'b'], { qos: 1 }), [
+    { topic: 'a', qos: 1 },
+    { topic: 'b', qos: 1 },
+  ]);
+  assert.deepStrictEqual(subscribeTopics({ 'pc/cmd': { qos: 1 }, 'pc/status': {} }), [
+    { topic: 'pc/cmd', qos: 1 },
+    'pc/status',
+  ]);
+});
+
+test('subscribe и unsubscribe называют модуль-владельца', () => {
+  // Без owner Rust запишет подписку на «node» — как для старых клиентов.
+  // Экземпляр без конструктора: настоящий повесил бы readline на stdin теста.
+  const sent = [];
+  const bridge = Object.create(MqttBridge.prototype);
+  bridge._send = (msg) => sent.push(msg);
+  bridge.subscribe('pc/cmd', { qos: 1, owner: 'exec' });
+  bridge.unsubscribe('pc/cmd', { owner: 'exec' });
+  bridge.unsubscribe('pc/other');
+  assert.deepStrictEqual(sent, [
+    { type: 'subscribe', topics: [{ topic: 'pc/cmd', qos: 1 }], owner: 'exec' },
+    { type: 'unsubscribe', topics: ['pc/cmd'], owner: 'exec' },
+    { type: 'unsubscribe', topics: ['pc/other'] },
+  ]);
+});
+
+test('ошибка моста приходит событием error с топиком', () => {
+  const bridge = Object.create(MqttBridge.prototype);
+  // Без подписчика событие молча пропускается: Rust уже записал его в лог.
+  bridge._emitError({ operation: 'subscribe', topic: 'pc/cmd', reason: 'denied' });
+
+  let received;
+  bridge.on('error', (err) => { received = err; });
+  bridge._emitError({ operation: 'subscribe', topic: 'pc/cmd', reason: 'denied' });
+  assert.strictEqual(received.operation, 'subscribe');
+  assert.strictEqual(received.topic, 'pc/cmd');
+  assert.match(received.message, /pc\/cmd failed: denied/);
+});
+
+test('publish с callback получает ответ по своему id', () => {
+  const sent = [];
+  const bridge = Object.create(MqttBridge.prototype);
+  bridge._send = (msg) => sent.push(msg);
+  const results = [];
+  bridge.publish('pc/a', 'x', { qos: 1 }, (err) => results.push(['a', err]));
+  bridge.publish('pc/b', 'y', (err) => results.push(['b', err]));
+  bridge.publish('pc/c', 'z');
+
+  assert.strictEqual(sent[0].id, '1');
+  assert.strictEqual(sent[1].id, '2');
+  assert.strictEqual(sent[1].options, undefined);
+  assert.strictEqual(sent[2].id, undefined);
+
+  bridge._publishResult({ id: '2', ok: false, error: 'timeout' });
+  bridge._publishResult({ id: '1', ok: true });
+  bridge._publishResult({ id: '1', ok: true });
+  assert.strictEqual(results.length, 2);
+  assert.strictEqual(results[0][0], 'b');
+  assert.match(results[0][1].message, /timeout/);
+  assert.deepStrictEqual(results[1], ['a', null]);
+});
+
+test('getTopicValue и listTopics отвечают из кэша Rust', async () => {
+  const sent = [];
+  const bridge = Object.create(MqttBridge.prototype);
+  bridge._send = (msg) => sent.push(msg);
+
+  const value = bridge.getTopicValue('pc/status');
+  const missing = bridge.getTopicValue('pc/none');
+  const topics = bridge.listTopics('pc/#');
+  assert.deepStrictEqual(sent, [
+    { type: 'getTopicValue', id: '1', topic: 'pc/status' },
+    { type: 'getTopicValue', id: '2', topic: 'pc/none' },
+    { type: 'listTopics', id: '3', filter: 'pc/#' },
+  ]);
+
+  bridge._answer('3', ['pc/status']);
+  bridge._answer('2', null);
+  bridge._answer('1', { topic: 'pc/status', payload: Buffer.from('online'), retained: true });
+  assert.deepStrictEqual(await topics, ['pc/status']);
+  assert.strictEqual(await missing, null);
+  assert.strictEqual((await value).payload.toString(), 'online');
+});
+
+test('свойства MQTT 5 ходят в форме mqtt.js', () => {
+  const sent = [];
+  const bridge = Object.create(MqttBridge.prototype);
+  bridge._send = (msg) => sent.push(msg);
+  bridge.publish('pc/reply', 'ok', {
+    qos: 1,
+    properties: {
+      responseTopic: 'ha/reply',
+      correlationData: Buffer.from([0, 1]),
+      userProperties: { source: 'pc', tag: ['a', 'b'] },
+    },
+  });
+  assert.deepStrictEqual(sent[0].options.properties, {
+    responseTopic: 'ha/reply',
+    correlationData: 'AAE=',
+    userProperties: [['source', 'pc'], ['tag', 'a'], ['tag', 'b']],
+  });
+
+  // Обратно повторённый ключ собирается в массив, как у mqtt.js.
+  assert.deepStrictEqual(propertiesFromRust({
+    contentType: 'application/json',
+    correlationData: 'AAE=',
+    userProperties: [['source', 'ha'], ['tag', 'a'], ['tag', 'b']],
+  }), {
+    contentType: 'application/json',
+    correlationData: Buffer.from([0, 1]),
+    userProperties: { source: 'ha', tag: ['a', 'b'] },
+  });
+  // MQTT 3.1.1 и сообщения без свойств: как раньше, ничего.
+  assert.strictEqual(propertiesFromRust(undefined), undefined);
+});